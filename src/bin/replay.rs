@@ -0,0 +1,38 @@
+extern crate elevator;
+
+use std::env;
+use std::process;
+
+use elevator::event_log::event_log::read_log;
+use elevator::event_log::replay::replay;
+
+// Feeds a recorded event log back through the FSM and request handler, and
+// prints every event that is decided differently today.
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            println!("Usage: replay <event log>");
+            process::exit(2);
+        }
+    };
+
+    let records = match read_log(&path) {
+        Ok(records) => records,
+        Err(err) => {
+            println!("Could not read {}. Error: {}", path, err);
+            process::exit(2);
+        }
+    };
+
+    let inputs = records.iter().filter(|record| record.is_input()).count();
+    let divergences = replay(&records);
+    for divergence in &divergences {
+        println!("{}", divergence);
+    }
+    println!("Replayed {} events from {}, {} diverged", inputs, path, divergences.len());
+
+    if !divergences.is_empty() {
+        process::exit(1);
+    }
+}
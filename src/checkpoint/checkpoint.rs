@@ -0,0 +1,195 @@
+use std::io;
+use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use request_handler::request::{Request, IP, now_ms};
+use elevator_fsm::elevator_fsm::State;
+
+// The state of a node, enough for a new process to carry on mid-trip.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub saved_at: u64,
+    pub requests: Vec<Vec<Request>>,
+    pub peer_positions: HashMap<IP, usize>,
+    pub state: State,
+    pub floor: usize,
+    pub direction_up: bool,
+    pub door_remaining_ms: Option<u64>,
+}
+
+impl Checkpoint {
+    pub fn age(&self) -> Duration {
+        Duration::from_millis(now_ms().saturating_sub(self.saved_at))
+    }
+
+    // Equal apart from the clocks, which change on every call.
+    fn same_state(&self, other: &Checkpoint) -> bool {
+        self.requests == other.requests
+            && self.peer_positions == other.peer_positions
+            && self.state == other.state
+            && self.floor == other.floor
+            && self.direction_up == other.direction_up
+            && self.door_remaining_ms.is_some() == other.door_remaining_ms.is_some()
+    }
+}
+
+// 64 bit FNV-1a, enough to tell a torn or garbled file from a whole one.
+fn checksum(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// The file is the checksum in hex on the first line and the checkpoint as JSON
+// on the second. It is written to a temporary file first and renamed into
+// place, so a crash leaves either the old or the new checkpoint.
+pub fn save<P: AsRef<Path>>(path: P, checkpoint: &Checkpoint) -> io::Result<()> {
+    let path = path.as_ref();
+    let json = serde_json::to_string(checkpoint).unwrap();
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        write!(file, "{:016x}\n{}\n", checksum(json.as_bytes()), json)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+
+    // The rename is only durable once the directory is.
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+// Returns None when there is no checkpoint, and an error when it is damaged.
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Option<Checkpoint>> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut file) => { file.read_to_string(&mut text)?; },
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let mut lines = text.lines();
+    let (sum, json) = match (lines.next(), lines.next()) {
+        (Some(sum), Some(json)) => (sum, json),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "checkpoint is truncated")),
+    };
+    if u64::from_str_radix(sum, 16).ok() != Some(checksum(json.as_bytes())) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "checkpoint checksum does not match"));
+    }
+    let checkpoint = serde_json::from_str(json).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(Some(checkpoint))
+}
+
+// Saves a checkpoint whenever the state changes, and every interval besides so
+// the remaining door time stays fresh.
+pub struct Checkpointer {
+    path: PathBuf,
+    interval: Duration,
+    last: Option<Checkpoint>,
+    last_save: Instant,
+}
+
+impl Checkpointer {
+    pub fn new(path: PathBuf, interval: Duration) -> Self {
+        Checkpointer {
+            path: path,
+            interval: interval,
+            last: None,
+            last_save: Instant::now(),
+        }
+    }
+
+    // Returns whether the checkpoint was written.
+    pub fn update(&mut self, checkpoint: Checkpoint) -> io::Result<bool> {
+        let changed = match self.last {
+            Some(ref last) => !last.same_state(&checkpoint),
+            None => true,
+        };
+        if !changed && self.last_save.elapsed() < self.interval {
+            return Ok(false);
+        }
+
+        save(&self.path, &checkpoint)?;
+        self.last = Some(checkpoint);
+        self.last_save = Instant::now();
+        Ok(true)
+    }
+
+    // Saves whatever changed since the last write, on the way out, so the next
+    // start does not pick up an older checkpoint.
+    pub fn flush(&mut self, checkpoint: Checkpoint) -> io::Result<()> {
+        save(&self.path, &checkpoint)?;
+        self.last = Some(checkpoint);
+        self.last_save = Instant::now();
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn checkpoint() -> Checkpoint {
+        let mut peer_positions = HashMap::new();
+        peer_positions.insert("10.0.0.2".to_string(), 3);
+        Checkpoint {
+            saved_at: 1000,
+            requests: vec![vec![]; 3],
+            peer_positions: peer_positions,
+            state: State::DoorOpen,
+            floor: 1,
+            direction_up: false,
+            door_remaining_ms: Some(800),
+        }
+    }
+
+    #[test]
+    fn saves_loads_and_rejects_damaged_files() {
+        let path = env::temp_dir().join("elevator_checkpoint_test.json");
+        let _ = fs::remove_file(&path);
+        assert_eq!(load(&path).unwrap(), None);
+
+        save(&path, &checkpoint()).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(checkpoint()));
+
+        let mut checkpointer = Checkpointer::new(path.clone(), Duration::from_secs(60));
+        assert!(checkpointer.update(checkpoint()).unwrap());
+        let mut later = checkpoint();
+        later.saved_at = 2000;
+        later.door_remaining_ms = Some(100);
+        assert!(!checkpointer.update(later.clone()).unwrap());
+        later.floor = 2;
+        assert!(checkpointer.update(later).unwrap());
+        // A flush writes even when nothing changed.
+        let mut last = load(&path).unwrap().unwrap();
+        last.saved_at = 3000;
+        checkpointer.flush(last.clone()).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(last));
+
+        // A write that was cut short.
+        let mut text = String::new();
+        File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+        File::create(&path).unwrap().write_all(&text.as_bytes()[..text.len() - 10]).unwrap();
+        assert!(load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
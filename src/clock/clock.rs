@@ -0,0 +1,87 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use request_handler::request;
+
+// Where a node reads the time. Tests use a ManualClock, so timeouts are stepped
+// through instead of waited for.
+pub trait Clock: Send + Sync {
+    // For measuring durations and setting deadlines.
+    fn now(&self) -> Instant;
+    // Milliseconds since the Unix epoch, for the timestamps of requests.
+    fn now_ms(&self) -> u64;
+}
+
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_ms(&self) -> u64 {
+        request::now_ms()
+    }
+}
+
+pub fn real() -> Arc<dyn Clock> {
+    Arc::new(RealClock)
+}
+
+// Starts at the time it was made and only moves when advanced. Clones share the
+// time, so a test keeps one to advance the clock it has handed out.
+#[derive(Clone)]
+pub struct ManualClock {
+    start: Instant,
+    start_ms: u64,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            start: Instant::now(),
+            start_ms: request::now_ms(),
+            elapsed: Arc::new(Mutex::new(Duration::new(0, 0))),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn now_ms(&self) -> u64 {
+        let elapsed = self.elapsed();
+        self.start_ms + elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn manual_clock_moves_only_when_advanced() {
+        let manual = ManualClock::new();
+        let clock: Arc<dyn Clock> = Arc::new(manual.clone());
+        let (before, before_ms) = (clock.now(), clock.now_ms());
+
+        assert_eq!(clock.now(), before);
+        manual.advance(Duration::from_millis(1500));
+        assert_eq!(clock.now().duration_since(before), Duration::from_millis(1500));
+        assert_eq!(clock.now_ms() - before_ms, 1500);
+    }
+}
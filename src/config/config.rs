@@ -0,0 +1,620 @@
+use std::io;
+use std::io::Read;
+use std::fmt;
+use std::error::Error;
+use std::fs::File;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use serde_json;
+
+use elevator_driver::elev_io::N_FLOORS;
+use network::socket::{NetMode, MulticastConfig, Interface};
+use network::peer::PeerConfig;
+use network::transport::TransportKind;
+use network::fault::{FaultPlan, FaultInjector};
+use request_handler::request_transmitter::{TransmitterConfig, SharedSettings};
+
+pub const USAGE: &str = "\
+Usage: elevator [--config <path>] [--backend hw|sim] [--node-id <id>] [--process-pair]
+
+    --config <path>   read the settings from a JSON file
+    --backend <name>  hw drives the elevator hardware, sim a simulated car
+    --node-id <id>    identifies this node to its peers, instead of a random id
+    --process-pair    run with a backup process that takes over on a crash";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Hardware,
+    Sim,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub backend: Backend,
+    pub node_id: Option<String>,
+    pub process_pair: bool,
+    pub floors: usize,
+    pub peer_port: u16,
+    pub bcast_port: u16,
+    pub supervisor_port: u16,
+    pub supervisor_timeout: Duration,
+    pub multicast_group: Option<IpAddr>,
+    pub multicast_ttl: u32,
+    pub multicast_interface: Interface,
+    pub peer: PeerConfig,
+    pub door_open: Duration,
+    pub stuck_timeout: Duration,
+    pub broadcast_tick: Duration,
+    pub poll_period: Duration,
+    pub sim_floor_travel: Duration,
+    pub metrics_addr: SocketAddr,
+    pub metrics_file: String,
+    pub event_log: String,
+    pub checkpoint_file: String,
+    pub checkpoint_interval: Duration,
+    pub checkpoint_max_age: Duration,
+    // The faults are read from fault_file when the config is loaded, and the
+    // admin port can change them at runtime.
+    pub fault_file: Option<String>,
+    pub faults: Option<FaultPlan>,
+    pub fault_admin_port: Option<u16>,
+    pub reliable_delivery: bool,
+    // Digests of the request table every tick, instead of the whole table.
+    pub anti_entropy: bool,
+    pub sidechannel_port: Option<u16>,
+    // The node the event log is shipped to over the sidechannel, and the file
+    // the collector appends the shipped logs to.
+    pub log_collector: Option<IpAddr>,
+    pub collected_log: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let transmitter = TransmitterConfig::default();
+        Config {
+            backend: Backend::Hardware,
+            node_id: None,
+            process_pair: false,
+            floors: N_FLOORS,
+            peer_port: transmitter.peer_port,
+            bcast_port: transmitter.bcast_port,
+            supervisor_port: 9878,
+            supervisor_timeout: Duration::from_millis(500),
+            multicast_group: None,
+            multicast_ttl: 1,
+            multicast_interface: Interface::Default,
+            peer: transmitter.peer,
+            door_open: Duration::from_secs(2),
+            stuck_timeout: Duration::from_secs(5),
+            broadcast_tick: Duration::from_millis(150),
+            poll_period: Duration::from_millis(20),
+            sim_floor_travel: Duration::from_millis(2000),
+            metrics_addr: "127.0.0.1:9105".parse().unwrap(),
+            metrics_file: "elevator_metrics.prom".to_string(),
+            event_log: "elevator_events.jsonl".to_string(),
+            checkpoint_file: "elevator_checkpoint.json".to_string(),
+            checkpoint_interval: Duration::from_secs(1),
+            checkpoint_max_age: Duration::from_secs(30),
+            fault_file: None,
+            faults: None,
+            fault_admin_port: None,
+            reliable_delivery: false,
+            anti_entropy: false,
+            sidechannel_port: None,
+            log_collector: None,
+            collected_log: "elevator_events_collected.jsonl".to_string(),
+        }
+    }
+}
+
+// The settings as written in the config file. Everything is optional, and the
+// values are checked when they are applied to a Config.
+#[derive(Deserialize, Debug, Default)]
+struct ConfigFile {
+    #[serde(default)]
+    backend: Option<String>,
+    #[serde(default)]
+    node_id: Option<String>,
+    #[serde(default)]
+    process_pair: Option<bool>,
+    #[serde(default)]
+    floors: Option<usize>,
+    #[serde(default)]
+    peer_port: Option<u16>,
+    #[serde(default)]
+    bcast_port: Option<u16>,
+    #[serde(default)]
+    supervisor_port: Option<u16>,
+    #[serde(default)]
+    supervisor_timeout_ms: Option<u64>,
+    #[serde(default)]
+    multicast_group: Option<String>,
+    #[serde(default)]
+    multicast_ttl: Option<u32>,
+    // An IPv4 address for IPv4 groups, an interface index for IPv6 groups.
+    #[serde(default)]
+    multicast_interface: Option<String>,
+    #[serde(default)]
+    heartbeat_interval_ms: Option<u64>,
+    #[serde(default)]
+    heartbeat_timeout_ms: Option<u64>,
+    #[serde(default)]
+    suspect_after: Option<u32>,
+    #[serde(default)]
+    door_open_ms: Option<u64>,
+    #[serde(default)]
+    stuck_timeout_ms: Option<u64>,
+    #[serde(default)]
+    broadcast_tick_ms: Option<u64>,
+    #[serde(default)]
+    poll_period_ms: Option<u64>,
+    #[serde(default)]
+    sim_floor_travel_ms: Option<u64>,
+    #[serde(default)]
+    metrics_addr: Option<String>,
+    #[serde(default)]
+    metrics_file: Option<String>,
+    #[serde(default)]
+    event_log: Option<String>,
+    #[serde(default)]
+    checkpoint_file: Option<String>,
+    #[serde(default)]
+    checkpoint_interval_ms: Option<u64>,
+    #[serde(default)]
+    checkpoint_max_age_ms: Option<u64>,
+    #[serde(default)]
+    fault_file: Option<String>,
+    #[serde(default)]
+    fault_admin_port: Option<u16>,
+    #[serde(default)]
+    reliable_delivery: Option<bool>,
+    #[serde(default)]
+    anti_entropy: Option<bool>,
+    #[serde(default)]
+    sidechannel_port: Option<u16>,
+    #[serde(default)]
+    log_collector: Option<String>,
+    #[serde(default)]
+    collected_log: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Usage(String),
+    Io(String, io::Error),
+    Parse(String, String),
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Usage(ref msg) => write!(f, "{}\n\n{}", msg, USAGE),
+            ConfigError::Io(ref path, ref err) => write!(f, "Could not read config file {}: {}", path, err),
+            ConfigError::Parse(ref path, ref err) => write!(f, "Config file {} is not valid: {}", path, err),
+            ConfigError::Invalid(setting, ref reason) => write!(f, "Invalid setting {}: {}", setting, reason),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Usage(_) => "bad command line",
+            ConfigError::Io(..) => "config file could not be read",
+            ConfigError::Parse(..) => "config file could not be parsed",
+            ConfigError::Invalid(..) => "invalid setting",
+        }
+    }
+}
+
+fn parse_backend(value: &str) -> Result<Backend, ConfigError> {
+    match value {
+        "hw" | "hardware" => Ok(Backend::Hardware),
+        "sim" => Ok(Backend::Sim),
+        _ => Err(ConfigError::Invalid("backend", format!("{:?} is neither hw nor sim", value))),
+    }
+}
+
+fn parse_interface(value: &str) -> Result<Interface, ConfigError> {
+    if let Ok(addr) = value.parse::<Ipv4Addr>() {
+        return Ok(Interface::Addr(addr));
+    }
+    if let Ok(index) = value.parse::<u32>() {
+        return Ok(Interface::Index(index));
+    }
+    Err(ConfigError::Invalid("multicast_interface", format!("{:?} is neither an IPv4 address nor an interface index", value)))
+}
+
+fn millis(setting: &'static str, value: u64) -> Result<Duration, ConfigError> {
+    if value == 0 {
+        return Err(ConfigError::Invalid(setting, "must be more than 0 ms".to_string()));
+    }
+    Ok(Duration::from_millis(value))
+}
+
+impl Config {
+    // Builds the config from the command line, without the program name. The
+    // config file is read first, and the other flags override it.
+    pub fn from_args(args: &[String]) -> Result<Config, ConfigError> {
+        let mut config_path = None;
+        let mut backend = None;
+        let mut node_id = None;
+        let mut process_pair = false;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| match args.next() {
+                Some(value) => Ok(value.clone()),
+                None => Err(ConfigError::Usage(format!("{} needs a value", flag))),
+            };
+            match arg.as_str() {
+                "--config" => config_path = Some(value("--config")?),
+                "--backend" => backend = Some(value("--backend")?),
+                "--node-id" => node_id = Some(value("--node-id")?),
+                "--process-pair" => process_pair = true,
+                "--help" | "-h" => return Err(ConfigError::Usage(String::new())),
+                other => return Err(ConfigError::Usage(format!("Unknown argument {}", other))),
+            }
+        }
+
+        let mut config = Config::default();
+        if let Some(path) = config_path {
+            config.load(&path)?;
+        }
+        if let Some(backend) = backend {
+            config.backend = parse_backend(&backend)?;
+        }
+        if node_id.is_some() {
+            config.node_id = node_id;
+        }
+        if process_pair {
+            config.process_pair = true;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(&mut self, path: &str) -> Result<(), ConfigError> {
+        let mut text = String::new();
+        File::open(path)
+             .and_then(|mut file| file.read_to_string(&mut text))
+             .map_err(|err| ConfigError::Io(path.to_string(), err))?;
+        let file: ConfigFile = serde_json::from_str(&text)
+             .map_err(|err| ConfigError::Parse(path.to_string(), err.to_string()))?;
+        self.apply(file)
+    }
+
+    fn apply(&mut self, file: ConfigFile) -> Result<(), ConfigError> {
+        if let Some(backend) = file.backend {
+            self.backend = parse_backend(&backend)?;
+        }
+        if file.node_id.is_some() {
+            self.node_id = file.node_id;
+        }
+        if let Some(process_pair) = file.process_pair {
+            self.process_pair = process_pair;
+        }
+        if let Some(floors) = file.floors {
+            self.floors = floors;
+        }
+        if let Some(port) = file.peer_port {
+            self.peer_port = port;
+        }
+        if let Some(port) = file.bcast_port {
+            self.bcast_port = port;
+        }
+        if let Some(port) = file.supervisor_port {
+            self.supervisor_port = port;
+        }
+        if let Some(ms) = file.supervisor_timeout_ms {
+            self.supervisor_timeout = millis("supervisor_timeout_ms", ms)?;
+        }
+        if let Some(group) = file.multicast_group {
+            let group = group.parse::<IpAddr>().map_err(|err| {
+                ConfigError::Invalid("multicast_group", format!("{:?} is not an address: {}", group, err))
+            })?;
+            self.multicast_group = Some(group);
+        }
+        if let Some(ttl) = file.multicast_ttl {
+            self.multicast_ttl = ttl;
+        }
+        if let Some(interface) = file.multicast_interface {
+            self.multicast_interface = parse_interface(&interface)?;
+        }
+        if let Some(ms) = file.heartbeat_interval_ms {
+            self.peer.interval = millis("heartbeat_interval_ms", ms)?;
+        }
+        if let Some(ms) = file.heartbeat_timeout_ms {
+            self.peer.timeout = millis("heartbeat_timeout_ms", ms)?;
+        }
+        if let Some(count) = file.suspect_after {
+            self.peer.suspect_after = count;
+        }
+        if let Some(ms) = file.door_open_ms {
+            self.door_open = millis("door_open_ms", ms)?;
+        }
+        if let Some(ms) = file.stuck_timeout_ms {
+            self.stuck_timeout = millis("stuck_timeout_ms", ms)?;
+        }
+        if let Some(ms) = file.broadcast_tick_ms {
+            self.broadcast_tick = millis("broadcast_tick_ms", ms)?;
+        }
+        if let Some(ms) = file.poll_period_ms {
+            self.poll_period = millis("poll_period_ms", ms)?;
+        }
+        if let Some(ms) = file.sim_floor_travel_ms {
+            self.sim_floor_travel = millis("sim_floor_travel_ms", ms)?;
+        }
+        if let Some(addr) = file.metrics_addr {
+            self.metrics_addr = addr.parse::<SocketAddr>().map_err(|err| {
+                ConfigError::Invalid("metrics_addr", format!("{:?} is not an address and port: {}", addr, err))
+            })?;
+        }
+        if let Some(path) = file.metrics_file {
+            self.metrics_file = path;
+        }
+        if let Some(path) = file.event_log {
+            self.event_log = path;
+        }
+        if let Some(path) = file.checkpoint_file {
+            self.checkpoint_file = path;
+        }
+        if let Some(ms) = file.checkpoint_interval_ms {
+            self.checkpoint_interval = millis("checkpoint_interval_ms", ms)?;
+        }
+        if let Some(ms) = file.checkpoint_max_age_ms {
+            self.checkpoint_max_age = millis("checkpoint_max_age_ms", ms)?;
+        }
+        if let Some(path) = file.fault_file {
+            let faults = FaultPlan::load(&path).map_err(|err| match err.kind() {
+                io::ErrorKind::InvalidData => ConfigError::Parse(path.clone(), err.to_string()),
+                _ => ConfigError::Io(path.clone(), err),
+            })?;
+            self.faults = Some(faults);
+            self.fault_file = Some(path);
+        }
+        if file.fault_admin_port.is_some() {
+            self.fault_admin_port = file.fault_admin_port;
+        }
+        if let Some(reliable_delivery) = file.reliable_delivery {
+            self.reliable_delivery = reliable_delivery;
+        }
+        if let Some(anti_entropy) = file.anti_entropy {
+            self.anti_entropy = anti_entropy;
+        }
+        if file.sidechannel_port.is_some() {
+            self.sidechannel_port = file.sidechannel_port;
+        }
+        if let Some(collector) = file.log_collector {
+            self.log_collector = Some(collector.parse::<IpAddr>().map_err(|err| {
+                ConfigError::Invalid("log_collector", format!("{:?} is not an address: {}", collector, err))
+            })?);
+        }
+        if let Some(path) = file.collected_log {
+            self.collected_log = path;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.floors < 2 || self.floors > N_FLOORS {
+            return Err(ConfigError::Invalid("floors", format!("{} is outside 2 to {}", self.floors, N_FLOORS)));
+        }
+        if self.peer_port == 0 || self.bcast_port == 0 || self.supervisor_port == 0 {
+            return Err(ConfigError::Invalid("ports", "port 0 is not allowed".to_string()));
+        }
+        if self.peer_port == self.bcast_port
+            || self.supervisor_port == self.peer_port
+            || self.supervisor_port == self.bcast_port {
+            return Err(ConfigError::Invalid("ports", "peer, broadcast and supervisor ports must differ".to_string()));
+        }
+        if let Some(port) = self.fault_admin_port {
+            if port == 0 || port == self.peer_port || port == self.bcast_port || port == self.supervisor_port {
+                return Err(ConfigError::Invalid("fault_admin_port", format!("{} is 0 or taken by another port", port)));
+            }
+        }
+        if let Some(port) = self.sidechannel_port {
+            // TCP, so it may share a number with the UDP ports, but not with the metrics endpoint.
+            if port == 0 || port == self.metrics_addr.port() {
+                return Err(ConfigError::Invalid("sidechannel_port", format!("{} is 0 or taken by the metrics endpoint", port)));
+            }
+        }
+        if self.log_collector.is_some() && self.sidechannel_port.is_none() {
+            return Err(ConfigError::Invalid("log_collector", "logs are shipped over the sidechannel, set sidechannel_port".to_string()));
+        }
+        if let Some(group) = self.multicast_group {
+            if !group.is_multicast() {
+                return Err(ConfigError::Invalid("multicast_group", format!("{} is not a multicast address", group)));
+            }
+            match (group, self.multicast_interface) {
+                (IpAddr::V4(_), Interface::Index(_)) | (IpAddr::V6(_), Interface::Addr(_)) => {
+                    return Err(ConfigError::Invalid("multicast_interface", format!("does not suit the group {}", group)));
+                },
+                _ => {},
+            }
+        }
+        if self.multicast_ttl == 0 || self.multicast_ttl > 255 {
+            return Err(ConfigError::Invalid("multicast_ttl", format!("{} is outside 1 to 255", self.multicast_ttl)));
+        }
+        // A zero interval is refused as a read timeout, and a peer must be
+        // suspected before it is given up on.
+        if self.peer.interval == Duration::from_millis(0) {
+            return Err(ConfigError::Invalid("heartbeat_interval_ms", "must be more than 0 ms".to_string()));
+        }
+        if self.peer.suspect_after == 0 || self.peer.interval * self.peer.suspect_after >= self.peer.timeout {
+            return Err(ConfigError::Invalid("suspect_after", "the suspect window must be shorter than heartbeat_timeout_ms".to_string()));
+        }
+        if let Some(ref node_id) = self.node_id {
+            // Peer ids are "ip:node_id", and the address is found by the last ':'.
+            if node_id.is_empty() || node_id.contains(':') || node_id.contains(char::is_whitespace) {
+                return Err(ConfigError::Invalid("node_id", format!("{:?} must be a non-empty word without ':'", node_id)));
+            }
+        }
+        if self.stuck_timeout <= self.door_open {
+            return Err(ConfigError::Invalid("stuck_timeout_ms", "must be longer than door_open_ms".to_string()));
+        }
+        // The main loop hands the heartbeats a checkpoint every broadcast tick,
+        // and they stop when it has not for the supervisor timeout.
+        if self.process_pair && self.broadcast_tick >= self.supervisor_timeout {
+            return Err(ConfigError::Invalid("broadcast_tick_ms", "must be shorter than supervisor_timeout_ms".to_string()));
+        }
+        if self.poll_period >= self.broadcast_tick {
+            return Err(ConfigError::Invalid("poll_period_ms", "must be shorter than broadcast_tick_ms".to_string()));
+        }
+        if self.metrics_file.is_empty() || self.event_log.is_empty() || self.checkpoint_file.is_empty() {
+            return Err(ConfigError::Invalid("paths", "metrics_file, event_log and checkpoint_file must be set".to_string()));
+        }
+        if self.collected_log.is_empty() || self.collected_log == self.event_log {
+            return Err(ConfigError::Invalid("collected_log", "must be set and differ from event_log".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn shared_settings(&self) -> SharedSettings {
+        SharedSettings {
+            anti_entropy: self.anti_entropy,
+            reliable_delivery: self.reliable_delivery,
+            broadcast_tick_ms: self.broadcast_tick.as_secs() * 1000 + self.broadcast_tick.subsec_millis() as u64,
+        }
+    }
+
+    pub fn transmitter(&self) -> TransmitterConfig {
+        let mode = match self.multicast_group {
+            Some(group) => NetMode::Multicast(MulticastConfig {
+                group: group,
+                ttl: self.multicast_ttl,
+                interface: self.multicast_interface,
+            }),
+            None => NetMode::Broadcast,
+        };
+        TransmitterConfig {
+            mode: mode,
+            peer_port: self.peer_port,
+            bcast_port: self.bcast_port,
+            peer: self.peer,
+            node_id: self.node_id.clone(),
+            transport: TransportKind::Udp,
+            faults: self.fault_injector(),
+            sidechannel_port: self.sidechannel_port,
+        }
+    }
+
+    // Without a fault file, an admin port starts from a plan without faults.
+    fn fault_injector(&self) -> Option<FaultInjector> {
+        if self.faults.is_none() && self.fault_admin_port.is_none() {
+            return None;
+        }
+        Some(FaultInjector::new(self.faults.clone().unwrap_or_default()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn command_line_overrides_and_validation() {
+        let config = Config::from_args(&args(&["--backend", "sim", "--node-id", "car2"])).unwrap();
+        assert_eq!(config.backend, Backend::Sim);
+        assert_eq!(config.node_id, Some("car2".to_string()));
+        assert_eq!(config.door_open, Duration::from_secs(2));
+
+        assert!(Config::from_args(&args(&["--backend", "diesel"])).is_err());
+        assert!(Config::from_args(&args(&["--node-id"])).is_err());
+        assert!(Config::from_args(&args(&["--node-id", "10.0.0.1:4"])).is_err());
+
+        let mut config = Config::default();
+        config.apply(ConfigFile { floors: Some(7), ..ConfigFile::default() }).unwrap();
+        match config.validate() {
+            Err(ConfigError::Invalid("floors", _)) => {},
+            other => panic!("Expected an invalid floor count, got {:?}", other),
+        }
+
+        let mut config = Config::default();
+        let file = ConfigFile { door_open_ms: Some(3000), stuck_timeout_ms: Some(6000), ..ConfigFile::default() };
+        config.apply(file).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.stuck_timeout, Duration::from_secs(6));
+
+        let mut config = Config::default();
+        assert!(config.transmitter().faults.is_none());
+        config.apply(ConfigFile { fault_admin_port: Some(config.peer_port), ..ConfigFile::default() }).unwrap();
+        assert!(config.validate().is_err());
+        config.apply(ConfigFile { fault_admin_port: Some(9879), ..ConfigFile::default() }).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.transmitter().faults.unwrap().plan(), FaultPlan::default());
+        config.apply(ConfigFile { sidechannel_port: Some(config.metrics_addr.port()), ..ConfigFile::default() }).unwrap();
+        assert!(config.validate().is_err());
+        config.apply(ConfigFile { sidechannel_port: Some(config.bcast_port), ..ConfigFile::default() }).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.transmitter().sidechannel_port, Some(config.bcast_port));
+
+        let mut config = Config::default();
+        config.apply(ConfigFile { log_collector: Some("10.0.0.9".to_string()), ..ConfigFile::default() }).unwrap();
+        match config.validate() {
+            Err(ConfigError::Invalid("log_collector", _)) => {},
+            other => panic!("Expected a collector without a sidechannel to be refused, got {:?}", other),
+        }
+        config.apply(ConfigFile { sidechannel_port: Some(9890), ..ConfigFile::default() }).unwrap();
+        assert!(config.validate().is_ok());
+        assert!(config.apply(ConfigFile { log_collector: Some("collector".to_string()), ..ConfigFile::default() }).is_err());
+
+        let mut settings = Config::default().shared_settings();
+        assert_eq!(settings.broadcast_tick_ms, 150);
+        let peer = SharedSettings { anti_entropy: true, reliable_delivery: false, broadcast_tick_ms: 300 };
+        assert!(settings.adopt(&peer));
+        assert!(settings.anti_entropy && !settings.reliable_delivery);
+        assert_eq!(settings.broadcast_tick_ms, 150);
+        assert!(!settings.adopt(&peer));
+
+        let mut config = Config::default();
+        let file = ConfigFile {
+            multicast_group: Some("239.1.2.3".to_string()),
+            multicast_ttl: Some(4),
+            multicast_interface: Some("10.0.0.7".to_string()),
+            heartbeat_interval_ms: Some(50),
+            heartbeat_timeout_ms: Some(1000),
+            suspect_after: Some(4),
+            ..ConfigFile::default()
+        };
+        config.apply(file).unwrap();
+        assert!(config.validate().is_ok());
+        let transmitter = config.transmitter();
+        assert_eq!(transmitter.peer.interval, Duration::from_millis(50));
+        assert_eq!(transmitter.peer.timeout, Duration::from_secs(1));
+        assert_eq!(transmitter.peer.suspect_after, 4);
+        match transmitter.mode {
+            NetMode::Multicast(ref multicast) => {
+                assert_eq!(multicast.ttl, 4);
+                match multicast.interface {
+                    Interface::Addr(addr) => assert_eq!(addr, Ipv4Addr::new(10, 0, 0, 7)),
+                    other => panic!("Expected an interface address, got {:?}", other),
+                }
+            },
+            NetMode::Broadcast => panic!("Expected multicast"),
+        }
+        config.apply(ConfigFile { multicast_interface: Some("3".to_string()), ..ConfigFile::default() }).unwrap();
+        assert!(config.validate().is_err());
+        config.apply(ConfigFile { multicast_interface: Some("10.0.0.7".to_string()), suspect_after: Some(20), ..ConfigFile::default() }).unwrap();
+        match config.validate() {
+            Err(ConfigError::Invalid("suspect_after", _)) => {},
+            other => panic!("Expected the suspect window to be too long, got {:?}", other),
+        }
+        assert!(config.apply(ConfigFile { heartbeat_interval_ms: Some(0), ..ConfigFile::default() }).is_err());
+
+        let mut config = Config::default();
+        match config.apply(ConfigFile { fault_file: Some("no/such/faults.json".to_string()), ..ConfigFile::default() }) {
+            Err(ConfigError::Io(..)) => {},
+            other => panic!("Expected the missing fault file to fail, got {:?}", other),
+        }
+    }
+}
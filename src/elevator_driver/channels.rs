@@ -0,0 +1,44 @@
+#![cfg_attr(feature="clippy", allow(identity_op))]
+#![allow(clippy::identity_op)]
+
+use std::io;
+
+use elevator_driver::elev_io::{Floor, Button, N_FLOORS};
+
+// Channel numbers of the elevator hardware, shared by the real and the simulated io.
+
+const TOP: usize = N_FLOORS - 1;
+const SEC_TOP: usize = N_FLOORS - 2;
+
+pub const MOTOR: usize = 0x100+0;
+pub const MOTORDIR: usize = 0x300+15;
+pub const DOOR_LIGHT: usize = 0x300+3;
+pub const STOP_LIGHT: usize = 0x300+14;
+pub const FLOOR_LIGHT: [usize; 2] = [ 0x300+0, 0x300+1 ];
+pub const FLOOR_SENSOR: [usize; 4] = [ 0x200+4, 0x200+5, 0x200+6, 0x200+7 ];
+pub const STOP_SENSOR: usize = 0x300+22;
+pub const OBSTR_SENSOR: usize = 0x300+23;
+
+pub fn button_light(button: Button) -> io::Result<usize> {
+    const CALL_UP_ADDR: [usize; 3]   = [ 0x300+9, 0x300+8, 0x300+6 ];
+    const CALL_DOWN_ADDR: [usize; 3] = [ 0x300+7, 0x300+5, 0x300+4 ];
+    const INTERNAL_ADDR: [usize; 4]  = [ 0x300+13, 0x300+12, 0x300+11, 0x300+10 ];
+    match button {
+        Button::CallUp(Floor::At(floor @ 0..=SEC_TOP)) => Ok(CALL_UP_ADDR[floor]),
+        Button::CallDown(Floor::At(floor @ 1..=TOP)) => Ok(CALL_DOWN_ADDR[floor-1]),
+        Button::Internal(Floor::At(floor @ 0..=TOP)) => Ok(INTERNAL_ADDR[floor]),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "given floor is not supported for given button")),
+    }
+}
+
+pub fn button_signal(button: Button) -> io::Result<usize> {
+    const CALL_UP_ADDR: [usize; 3] = [ 0x300+17, 0x300+16, 0x200+1 ];
+    const CALL_DOWN_ADDR: [usize; 3] = [ 0x200+0, 0x200+2, 0x200+3 ];
+    const INTERNAL_ADDR: [usize; 4] = [ 0x300+21, 0x300+20, 0x300+19, 0x300+18 ];
+    match button {
+        Button::CallUp(Floor::At(floor @ 0..=SEC_TOP)) => Ok(CALL_UP_ADDR[floor]),
+        Button::CallDown(Floor::At(floor @ 1..=TOP)) => Ok(CALL_DOWN_ADDR[floor-1]),
+        Button::Internal(Floor::At(floor @ 0..=TOP)) => Ok(INTERNAL_ADDR[floor]),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "given floor is not supported for given button")),
+    }
+}
@@ -1,31 +1,69 @@
 #![cfg_attr(feature="clippy", allow(identity_op))]
+#![allow(clippy::identity_op)]
 #![allow(dead_code)]
 
 use std::io;
 
 use elevator_driver::hw_io::HwIo;
+use elevator_driver::sim_io::SimIo;
+use elevator_driver::channels;
+
+// Where the channels are read and written: the comedi card, or a simulation.
+enum Backend {
+    Hw(HwIo),
+    Sim(SimIo),
+}
+
+impl Backend {
+    fn set_bit(&self, channel: usize) -> io::Result<()> {
+        match *self {
+            Backend::Hw(ref io) => io.set_bit(channel),
+            Backend::Sim(ref io) => io.set_bit(channel),
+        }
+    }
+
+    fn clear_bit(&self, channel: usize) -> io::Result<()> {
+        match *self {
+            Backend::Hw(ref io) => io.clear_bit(channel),
+            Backend::Sim(ref io) => io.clear_bit(channel),
+        }
+    }
+
+    fn read_bit(&self, channel: usize) -> io::Result<usize> {
+        match *self {
+            Backend::Hw(ref io) => io.read_bit(channel),
+            Backend::Sim(ref io) => io.read_bit(channel),
+        }
+    }
+
+    fn write_analog(&self, channel: usize, value: usize) -> io::Result<()> {
+        match *self {
+            Backend::Hw(ref io) => io.write_analog(channel, value),
+            Backend::Sim(ref io) => io.write_analog(channel, value),
+        }
+    }
+}
 
 pub struct ElevIo {
-    io: HwIo,
+    io: Backend,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum Floor {
     At(usize),
     Between,
 }
 pub const N_FLOORS: usize = 4;
 const TOP: usize = N_FLOORS - 1;
-const SEC_TOP: usize = N_FLOORS - 2;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum Button {
     CallUp(Floor),
     CallDown(Floor),
     Internal(Floor),
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum MotorDir {
     Up,
     Down,
@@ -38,7 +76,7 @@ pub enum Light {
     Off,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum Signal {
     High,
     Low,
@@ -58,9 +96,8 @@ impl ElevIo {
         // Drive elevator to known floor
         self.set_motor_dir(MotorDir::Down)?;
         loop {
-            match self.get_floor_signal()? {
-                Floor::At(_) => break,
-                _ => {},
+            if let Floor::At(_) = self.get_floor_signal()? {
+                break;
             }
         }
         self.set_motor_dir(MotorDir::Stop)?;
@@ -69,7 +106,25 @@ impl ElevIo {
     }
 
     pub fn new() -> io::Result<Self> {
-        let elev = ElevIo { io: HwIo::new()? };
+        ElevIo::with_backend(Backend::Hw(HwIo::new()?))
+    }
+
+    pub fn simulated(sim: SimIo) -> io::Result<Self> {
+        ElevIo::with_backend(Backend::Sim(sim))
+    }
+
+    // Takes over the hardware as it is, without driving to a floor first. For a
+    // process that resumes the trip of the one before it.
+    pub fn attach() -> io::Result<Self> {
+        Ok(ElevIo { io: Backend::Hw(HwIo::new()?) })
+    }
+
+    pub fn attach_simulated(sim: SimIo) -> Self {
+        ElevIo { io: Backend::Sim(sim) }
+    }
+
+    fn with_backend(backend: Backend) -> io::Result<Self> {
+        let elev = ElevIo { io: backend };
         elev.set_all_light(Light::Off)?;
         elev.initialize()?;
         elev.set_floor_light(Floor::At(0))?;
@@ -77,17 +132,15 @@ impl ElevIo {
     }
 
     pub fn set_motor_dir(&self, dir: MotorDir) -> io::Result<()> {
-        const MOTOR_ADDR: usize    = 0x100+0;
-        const MOTORDIR_ADDR: usize = 0x300+15;
         match dir {
-            MotorDir::Stop => self.io.write_analog(MOTOR_ADDR, 0)?,
+            MotorDir::Stop => self.io.write_analog(channels::MOTOR, 0)?,
             MotorDir::Up => {
-                self.io.clear_bit(MOTORDIR_ADDR)?;
-                self.io.write_analog(MOTOR_ADDR, MOTOR_SPEED)?;
+                self.io.clear_bit(channels::MOTORDIR)?;
+                self.io.write_analog(channels::MOTOR, MOTOR_SPEED)?;
             },
             MotorDir::Down => {
-                self.io.set_bit(MOTORDIR_ADDR)?;
-                self.io.write_analog(MOTOR_ADDR, MOTOR_SPEED)?;
+                self.io.set_bit(channels::MOTORDIR)?;
+                self.io.write_analog(channels::MOTOR, MOTOR_SPEED)?;
             },
         };
         Ok(())
@@ -105,15 +158,7 @@ impl ElevIo {
     }
 
     pub fn set_button_light(&self, button: Button, mode: Light) -> io::Result<()> {
-        const CALL_UP_ADDR: [usize; 3]   = [ 0x300+9, 0x300+8, 0x300+6 ];
-        const CALL_DOWN_ADDR: [usize; 3] = [ 0x300+7, 0x300+5, 0x300+4 ];
-        const INTERNAL_ADDR: [usize; 4]  = [ 0x300+13, 0x300+12, 0x300+11, 0x300+10 ];
-        let addr = match button {
-            Button::CallUp(Floor::At(floor @ 0...SEC_TOP)) => CALL_UP_ADDR[floor],
-            Button::CallDown(Floor::At(floor @ 1...TOP)) => CALL_DOWN_ADDR[floor-1],
-            Button::Internal(Floor::At(floor @ 0...TOP)) => INTERNAL_ADDR[floor],
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "given floor is not supported for given button")),
-        };
+        let addr = channels::button_light(button)?;
         match mode {
             Light::On => self.io.set_bit(addr)?,
             Light::Off => self.io.clear_bit(addr)?,
@@ -123,15 +168,14 @@ impl ElevIo {
     }
 
     pub fn set_floor_light(&self, floor: Floor) -> io::Result<()> {
-        const FLOOR_LIGHT_ADDR: [usize; 2] = [ 0x300+0, 0x300+1 ];
         if let Floor::At(etg) = floor {
             if etg > TOP {
                 return Err(io::Error::new(io::ErrorKind::InvalidInput, "given floor is not supported"));
             }
-            if etg & 0x2 != 0 { self.io.set_bit(FLOOR_LIGHT_ADDR[0])?; }
-            else              { self.io.clear_bit(FLOOR_LIGHT_ADDR[0])?; }
-            if etg & 0x1 != 0 { self.io.set_bit(FLOOR_LIGHT_ADDR[1])?; }
-            else              { self.io.clear_bit(FLOOR_LIGHT_ADDR[1])?; }
+            if etg & 0x2 != 0 { self.io.set_bit(channels::FLOOR_LIGHT[0])?; }
+            else              { self.io.clear_bit(channels::FLOOR_LIGHT[0])?; }
+            if etg & 0x1 != 0 { self.io.set_bit(channels::FLOOR_LIGHT[1])?; }
+            else              { self.io.clear_bit(channels::FLOOR_LIGHT[1])?; }
             Ok(())
         } else {
             Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot set light between floors"))
@@ -139,40 +183,29 @@ impl ElevIo {
     }
 
     pub fn set_door_light(&self, mode: Light) -> io::Result<()> {
-        const DOOR_LIGHT_ADDR: usize = 0x300+3;
         match mode {
-            Light::On => self.io.set_bit(DOOR_LIGHT_ADDR)?,
-            Light::Off => self.io.clear_bit(DOOR_LIGHT_ADDR)?,
+            Light::On => self.io.set_bit(channels::DOOR_LIGHT)?,
+            Light::Off => self.io.clear_bit(channels::DOOR_LIGHT)?,
         }
         Ok(())
     }
 
     pub fn set_stop_light(&self, mode: Light) -> io::Result<()> {
-        const STOP_LIGT_ADDR: usize = 0x300+14;
         match mode {
-            Light::On => self.io.set_bit(STOP_LIGT_ADDR)?,
-            Light::Off => self.io.clear_bit(STOP_LIGT_ADDR)?,
+            Light::On => self.io.set_bit(channels::STOP_LIGHT)?,
+            Light::Off => self.io.clear_bit(channels::STOP_LIGHT)?,
         }
         Ok(())
     }
 
     pub fn get_button_signal(&self, button: Button) -> io::Result<Signal> {
-        const CALL_UP_ADDR: [usize; 3] = [ 0x300+17, 0x300+16, 0x200+1 ];
-        const CALL_DOWN_ADDR: [usize; 3] = [ 0x200+0, 0x200+2, 0x200+3 ];
-        const INTERNAL_ADDR: [usize; 4] = [ 0x300+21, 0x300+20, 0x300+19, 0x300+18 ];
-        let addr = match button {
-            Button::CallUp(Floor::At(floor @ 0...SEC_TOP)) => CALL_UP_ADDR[floor],
-            Button::CallDown(Floor::At(floor @ 1...TOP)) => CALL_DOWN_ADDR[floor-1],
-            Button::Internal(Floor::At(floor @ 0...TOP)) => INTERNAL_ADDR[floor],
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "given floor is not supported for given button")),
-        };
+        let addr = channels::button_signal(button)?;
         let value = self.io.read_bit(addr)?;
         Ok(Signal::new(value))
     }
 
     pub fn get_floor_signal(&self) -> io::Result<Floor> {
-        const FLOOR_SENSOR_ADDR: [usize; 4] = [ 0x200+4, 0x200+5, 0x200+6, 0x200+7 ];
-        for (floor, addr) in FLOOR_SENSOR_ADDR.iter().enumerate() {
+        for (floor, addr) in channels::FLOOR_SENSOR.iter().enumerate() {
             if self.io.read_bit(*addr)? != 0 {
                 return Ok(Floor::At(floor));
             }
@@ -181,13 +214,11 @@ impl ElevIo {
     }
 
     pub fn get_stop_signal(&self) -> io::Result<Signal> {
-        const STOP_SENSOR_ADDR: usize = 0x300+22;
-        Ok(Signal::new(self.io.read_bit(STOP_SENSOR_ADDR)?))
+        Ok(Signal::new(self.io.read_bit(channels::STOP_SENSOR)?))
     }
 
     pub fn get_obstr_signal(&self) -> io::Result<Signal> {
-        const OBSTR_SENSOR_ADDR: usize = 0x300+23;
-        Ok(Signal::new(self.io.read_bit(OBSTR_SENSOR_ADDR)?))
+        Ok(Signal::new(self.io.read_bit(channels::OBSTR_SENSOR)?))
     }
 
 }
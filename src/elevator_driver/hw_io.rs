@@ -10,7 +10,7 @@ use elevator_driver::libc::{c_int, c_char, c_uint};
 enum ComediT {}
 
 #[link(name = "comedi")]
-extern {
+extern "C" {
     fn comedi_open(filename: *const c_char) -> *const ComediT;
     fn comedi_dio_config(it: *const ComediT, subd: c_uint, chan: c_uint, dir: c_uint) -> c_int;
     fn comedi_dio_write(it: *const ComediT, subd: c_uint, chan: c_uint, bit: c_uint) -> c_int;
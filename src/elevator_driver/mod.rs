@@ -4,4 +4,6 @@
 extern crate libc;
 
 mod hw_io;
+mod channels;
+pub mod sim_io;
 pub mod elev_io;
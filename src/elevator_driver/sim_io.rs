@@ -0,0 +1,202 @@
+use std::io;
+use std::thread;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use elevator_driver::elev_io::{Floor, Button, Signal, Light, MotorDir};
+use elevator_driver::channels;
+use shutdown::shutdown::Shutdown;
+
+const CAR_TICK_MS: u64 = 10;
+
+// An in-memory stand-in for the comedi card. Every channel is a plain register,
+// and clones share the registers, so one clone can play the building while an
+// ElevIo drives another.
+#[derive(Clone)]
+pub struct SimIo {
+    registers: Arc<Mutex<HashMap<usize, usize>>>,
+}
+
+impl SimIo {
+    // The car starts at the ground floor.
+    pub fn new() -> Self {
+        let sim = SimIo {
+            registers: Arc::new(Mutex::new(HashMap::new())),
+        };
+        sim.set_floor(Floor::At(0));
+        sim
+    }
+
+    fn write(&self, channel: usize, value: usize) {
+        self.registers.lock().unwrap().insert(channel, value);
+    }
+
+    fn read(&self, channel: usize) -> usize {
+        *self.registers.lock().unwrap().get(&channel).unwrap_or(&0)
+    }
+
+    pub fn set_bit(&self, channel: usize) -> io::Result<()> {
+        self.write(channel, 1);
+        Ok(())
+    }
+
+    pub fn clear_bit(&self, channel: usize) -> io::Result<()> {
+        self.write(channel, 0);
+        Ok(())
+    }
+
+    pub fn read_bit(&self, channel: usize) -> io::Result<usize> {
+        Ok(self.read(channel))
+    }
+
+    pub fn write_analog(&self, channel: usize, value: usize) -> io::Result<()> {
+        self.write(channel, value);
+        Ok(())
+    }
+
+    pub fn read_analog(&self, channel: usize) -> io::Result<usize> {
+        Ok(self.read(channel))
+    }
+
+    pub fn set_floor(&self, floor: Floor) {
+        for (at, &channel) in channels::FLOOR_SENSOR.iter().enumerate() {
+            let value = match floor {
+                Floor::At(floor) if floor == at => 1,
+                _ => 0,
+            };
+            self.write(channel, value);
+        }
+    }
+
+    pub fn set_button(&self, button: Button, signal: Signal) -> io::Result<()> {
+        let channel = channels::button_signal(button)?;
+        self.write(channel, match signal { Signal::High => 1, Signal::Low => 0 });
+        Ok(())
+    }
+
+    pub fn set_stop(&self, signal: Signal) {
+        self.write(channels::STOP_SENSOR, match signal { Signal::High => 1, Signal::Low => 0 });
+    }
+
+    pub fn motor_dir(&self) -> MotorDir {
+        if self.read(channels::MOTOR) == 0 {
+            MotorDir::Stop
+        } else if self.read(channels::MOTORDIR) == 0 {
+            MotorDir::Up
+        } else {
+            MotorDir::Down
+        }
+    }
+
+    pub fn button_light(&self, button: Button) -> io::Result<Light> {
+        let channel = channels::button_light(button)?;
+        Ok(if self.read(channel) == 0 { Light::Off } else { Light::On })
+    }
+
+    pub fn door_light(&self) -> Light {
+        if self.read(channels::DOOR_LIGHT) == 0 { Light::Off } else { Light::On }
+    }
+}
+
+
+// Moves the car along the shaft the way the motor drives it, and sets the
+// floor sensors as it passes the floors.
+pub struct SimCar {
+    sim: SimIo,
+    travel_ms: u64,
+    top_ms: u64,
+    position_ms: u64,
+    jammed: bool,
+}
+
+impl SimCar {
+    pub fn new(sim: SimIo, floors: usize, start_floor: usize, floor_travel: Duration) -> Self {
+        let travel_ms = floor_travel.as_secs() * 1000 + floor_travel.subsec_nanos() as u64 / 1_000_000;
+        let top_ms = (floors as u64 - 1) * travel_ms;
+        sim.set_floor(Floor::At(start_floor.min(floors - 1)));
+        SimCar {
+            sim: sim,
+            travel_ms: travel_ms,
+            top_ms: top_ms,
+            position_ms: (start_floor as u64 * travel_ms).min(top_ms),
+            jammed: false,
+        }
+    }
+
+    // A jammed car stays where it is, whatever the motor does.
+    pub fn jam(&mut self, jammed: bool) {
+        self.jammed = jammed;
+    }
+
+    pub fn step(&mut self, elapsed_ms: u64) {
+        self.position_ms = match self.sim.motor_dir() {
+            _ if self.jammed => self.position_ms,
+            MotorDir::Up => (self.position_ms + elapsed_ms).min(self.top_ms),
+            MotorDir::Down => self.position_ms.saturating_sub(elapsed_ms),
+            MotorDir::Stop => self.position_ms,
+        };
+
+        // The sensor of a floor sees the car this close to the floor.
+        let sensor_ms = self.travel_ms / 10;
+        let nearest = (self.position_ms + self.travel_ms / 2) / self.travel_ms;
+        let distance = self.position_ms.abs_diff(nearest * self.travel_ms);
+        if distance <= sensor_ms {
+            self.sim.set_floor(Floor::At(nearest as usize));
+        } else {
+            self.sim.set_floor(Floor::Between);
+        }
+    }
+}
+
+// Runs the car in real time until the shutdown.
+pub fn run_car(sim: SimIo, floors: usize, start_floor: usize, floor_travel: Duration, shutdown: Shutdown) {
+    let mut car = SimCar::new(sim, floors, start_floor, floor_travel);
+    while !shutdown.is_triggered() {
+        thread::sleep(Duration::from_millis(CAR_TICK_MS));
+        car.step(CAR_TICK_MS);
+    }
+}
+
+// Reads a button from a passenger command: "u2" calls up at floor 2, "d2" calls
+// down and "c2" is the cab button.
+pub fn parse_button(command: &str) -> Option<Button> {
+    let mut chars = command.trim().chars();
+    let kind = chars.next();
+    let floor = match chars.as_str().parse::<usize>() {
+        Ok(floor) => Floor::At(floor),
+        Err(_) => return None,
+    };
+    match kind {
+        Some('u') => Some(Button::CallUp(floor)),
+        Some('d') => Some(Button::CallDown(floor)),
+        Some('c') => Some(Button::Internal(floor)),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elevator_driver::elev_io::*;
+
+    #[test]
+    fn elev_io_runs_on_sim() {
+        let sim = SimIo::new();
+        let io = ElevIo::simulated(sim.clone()).unwrap();
+
+        io.set_motor_dir(MotorDir::Up).unwrap();
+        assert!(match sim.motor_dir() { MotorDir::Up => true, _ => false });
+
+        sim.set_floor(Floor::At(2));
+        assert!(match io.get_floor_signal().unwrap() { Floor::At(2) => true, _ => false });
+
+        let button = Button::CallDown(Floor::At(2));
+        sim.set_button(button, Signal::High).unwrap();
+        assert!(match io.get_button_signal(button).unwrap() { Signal::High => true, _ => false });
+
+        io.set_button_light(button, Light::On).unwrap();
+        assert!(match sim.button_light(button).unwrap() { Light::On => true, _ => false });
+    }
+}
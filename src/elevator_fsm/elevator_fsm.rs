@@ -2,16 +2,30 @@
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use elevator_driver::elev_io::*;
 use request_handler::request::*;
 use request_handler::request_transmitter::*;
 use request_handler::request_handler::*;
+use request_handler::lamp_sync::LampSync;
 use elevator_timer::elevator_timer::*;
+use network::peer::PeerUpdate;
+use metrics::metrics;
+use event_log::event_log::{self, LogEvent, FsmSnapshot};
+use checkpoint::checkpoint::Checkpoint;
+use clock::clock::{self, Clock};
 
-enum State {
+const DOOR_OPEN_S: u64 = 2;
+const STUCK_S: u64 = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum State {
     Idle,
     Running,
-    DoorOpen
+    DoorOpen,
+    Stopped,
+    Fault,
 }
 
 
@@ -21,40 +35,134 @@ pub struct Elevator {
     current_direction: MotorDir,
     state: State,
     pub request_handler: RequestHandler,
-    pub door_timer: Timer,
-    pub stuck_timer: Timer,
+    request_transmitter: Rc<RequestTransmitter>,
+    lamps: LampSync,
+    travel_start: Option<Instant>,
+    // The last commands given to the hardware, so only changes are logged.
+    motor: MotorDir,
+    floor_light: Option<usize>,
+    pub timers: TimerService,
+    door_open: Duration,
+    stuck_timeout: Duration,
+    clock: Arc<dyn Clock>,
 }
 
 
 impl Elevator {
     pub fn new(request_transmitter: Rc<RequestTransmitter>) -> Self {
         let elevator_io = ElevIo::new().expect("Init of HW failed");
-        let request_handler = RequestHandler::new(request_transmitter);
-        let door_timer = Timer::new(2);
-        let stuck_timer = Timer::new(5);
+        Elevator::with_io(elevator_io, request_transmitter)
+    }
 
+    pub fn with_io(elevator_io: ElevIo, request_transmitter: Rc<RequestTransmitter>) -> Self {
         let current_floor = match elevator_io.get_floor_signal().unwrap() {
             Floor::At(floor) => floor,
             Floor::Between => unreachable!(),
         };
 
-        elevator_io.set_motor_dir(MotorDir::Down);
+        elevator_io.set_motor_dir(MotorDir::Down).unwrap();
+
+        Elevator::build(elevator_io, request_transmitter, current_floor)
+    }
+
+    // For hardware that was not initialized, where the car may be between
+    // floors. The state is expected to come from restore.
+    pub fn attached(elevator_io: ElevIo, request_transmitter: Rc<RequestTransmitter>, last_floor: usize) -> Self {
+        Elevator::build(elevator_io, request_transmitter, last_floor)
+    }
+
+    fn build(elevator_io: ElevIo, request_transmitter: Rc<RequestTransmitter>, current_floor: usize) -> Self {
+        let request_handler = RequestHandler::new(request_transmitter.clone());
 
-        let elevator = Elevator {
+        Elevator {
             io: elevator_io,
             current_floor: current_floor,
             current_direction: MotorDir::Down,
             state: State::Idle,
             request_handler: request_handler,
-            door_timer: door_timer,
-            stuck_timer: stuck_timer,
-        };
+            request_transmitter: request_transmitter,
+            lamps: LampSync::new(),
+            travel_start: None,
+            motor: MotorDir::Down,
+            floor_light: None,
+            timers: TimerService::new(),
+            door_open: Duration::from_secs(DOOR_OPEN_S),
+            stuck_timeout: Duration::from_secs(STUCK_S),
+            clock: clock::real(),
+        }
+    }
+
+    pub fn set_timeouts(&mut self, door_open: Duration, stuck: Duration) {
+        self.door_open = door_open;
+        self.stuck_timeout = stuck;
+    }
+
+    // Set before any timer is started.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.timers.set_clock(clock.clone());
+        self.request_handler.set_clock(clock.clone());
+        self.clock = clock;
+    }
 
-        return elevator;
+    fn start_stuck_timer(&mut self) {
+        self.timers.start(TimerName::Stuck, self.stuck_timeout);
     }
 
     fn get_current_floor(&self) -> Floor {
-        return self.io.get_floor_signal().unwrap();
+        self.io.get_floor_signal().unwrap()
+    }
+
+
+    fn snapshot(&self) -> FsmSnapshot {
+        let active_requests = self.request_handler.requests.iter()
+            .flat_map(|requests| requests.iter())
+            .filter(|request| request.is_active())
+            .map(|request| (request.request_type, request.floor))
+            .collect();
+
+        FsmSnapshot {
+            state: self.state,
+            floor: self.current_floor,
+            direction: format!("{:?}", self.current_direction),
+            active_requests: active_requests,
+        }
+    }
+
+
+    // Runs an event and writes it to the event log, followed by the hardware
+    // actions and messages it caused.
+    fn logged<F>(&mut self, event: LogEvent, always: bool, handle: F)
+        where F: FnOnce(&mut Elevator),
+    {
+        if !event_log::is_open() {
+            handle(self);
+            return;
+        }
+
+        let before = self.snapshot();
+        let requests_before = self.request_handler.requests.clone();
+        event_log::begin_event();
+        handle(self);
+        let after = self.snapshot();
+
+        // Acknowledgements do not show in the snapshot, but matter to a replay.
+        let always = always || requests_before != self.request_handler.requests;
+        event_log::end_event(event, before, after, always);
+    }
+
+
+    fn drive(&mut self, direction: MotorDir) {
+        if self.motor != direction {
+            self.motor = direction;
+            event_log::record(LogEvent::Motor(format!("{:?}", direction)), None, None);
+        }
+        self.io.set_motor_dir(direction).unwrap();
+    }
+
+    fn set_door_light(&mut self, light: Light) {
+        let on = match light { Light::On => true, Light::Off => false };
+        event_log::record(LogEvent::DoorLight(on), None, None);
+        self.io.set_door_light(light).unwrap();
     }
 
 
@@ -68,28 +176,36 @@ impl Elevator {
 
         self.request_handler.announce_requests_cleared(current_floor, self.current_direction);
 
-        self.io.set_motor_dir(MotorDir::Stop).unwrap();
-        self.io.set_door_light(Light::On).unwrap();
-        self.clear_lights_at_floor(current_floor);
-        self.door_timer.start();
-    }
+        self.drive(MotorDir::Stop);
+        self.set_door_light(Light::On);
+        self.sync_lamps();
+        self.timers.start(TimerName::Door, self.door_open);
 
+        metrics::record_door_cycle();
+        if let Some(travel_start) = self.travel_start.take() {
+            metrics::record_travel(self.clock.now().duration_since(travel_start));
+        }
+    }
 
-    fn clear_lights_at_floor(&mut self, floor: usize){
-        let internal_button = Button::Internal(Floor::At(floor));
-        let external_button = match self.current_direction {
-            MotorDir::Up => Button::CallUp(Floor::At(floor)),
-            MotorDir::Down => Button::CallDown(Floor::At(floor)),
-            _ => unreachable!()
-        };
 
-        self.io.set_button_light(internal_button, Light::Off);
-        self.io.set_button_light(external_button, Light::Off);
+    // Every button lamp follows the request table, and is only changed here.
+    fn sync_lamps(&mut self) {
+        for (button, light) in self.lamps.changes(&self.request_handler.requests) {
+            let (request_type, floor) = match button {
+                Button::CallUp(Floor::At(floor))   => (RequestType::CallUp, floor),
+                Button::CallDown(Floor::At(floor)) => (RequestType::CallDown, floor),
+                Button::Internal(Floor::At(floor)) => (RequestType::Internal, floor),
+                _ => unreachable!(),
+            };
+            let on = match light { Light::On => true, Light::Off => false };
+            event_log::record(LogEvent::ButtonLight(request_type, floor, on), None, None);
+            self.io.set_button_light(button, light).unwrap();
+        }
     }
 
 
     fn close_doors(&mut self) {
-        self.io.set_door_light(Light::Off).unwrap();
+        self.set_door_light(Light::Off);
     }
 
 
@@ -101,7 +217,8 @@ impl Elevator {
 
         if self.request_handler.should_continue(current_floor, self.current_direction) {
             // orders in same direction, so continue
-            self.io.set_motor_dir(self.current_direction);
+            let direction = self.current_direction;
+            self.drive(direction);
             return;
         }
 
@@ -118,100 +235,485 @@ impl Elevator {
         }
 
         // no orders in any direction, so stop
-        self.io.set_motor_dir(MotorDir::Stop);
+        self.drive(MotorDir::Stop);
     }
 
 
-    fn set_floor_lights(&self) {
-        self.io.set_floor_light(self.io.get_floor_signal().unwrap());
+    fn set_floor_lights(&mut self) {
+        let floor = self.io.get_floor_signal().unwrap();
+        if let Floor::At(floor) = floor {
+            if self.floor_light != Some(floor) {
+                self.floor_light = Some(floor);
+                event_log::record(LogEvent::FloorLight(floor), None, None);
+            }
+        }
+        self.io.set_floor_light(floor).unwrap();
     }
 
 
-    pub fn event_running(&mut self) {
-        if let State::Idle = self.state {
-            self.state = State::Running;
+    pub fn checkpoint(&self) -> Checkpoint {
+        let door_remaining_ms = match self.state {
+            State::DoorOpen => self.timers.remaining(TimerName::Door).map(|remaining| {
+                remaining.as_secs() * 1000 + remaining.subsec_nanos() as u64 / 1_000_000
+            }),
+            _ => None,
+        };
+
+        Checkpoint {
+            saved_at: now_ms(),
+            requests: self.request_handler.requests.clone(),
+            peer_positions: self.request_handler.peer_positions().clone(),
+            state: self.state,
+            floor: self.current_floor,
+            direction_up: self.current_direction == MotorDir::Up,
+            door_remaining_ms: door_remaining_ms,
+        }
+    }
+
+
+    // Picks up where the process that ran before this one stopped, down to the
+    // motor and the door.
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        let fits = checkpoint.requests.len() == self.request_handler.requests.len()
+            && checkpoint.requests.iter().all(|requests| requests.len() == N_FLOORS);
+        if !fits {
+            println!("Checkpoint does not fit this elevator, starting without it");
+            return;
         }
+        self.request_handler.requests = checkpoint.requests;
+        self.request_handler.restore_peer_positions(checkpoint.peer_positions);
+        self.current_floor = checkpoint.floor;
+        self.current_direction = if checkpoint.direction_up { MotorDir::Up } else { MotorDir::Down };
+
+        match checkpoint.state {
+            State::Running => {
+                self.state = State::Running;
+                self.travel_start = Some(self.clock.now());
+                let direction = self.current_direction;
+                self.drive(direction);
+            },
+            State::DoorOpen => {
+                self.state = State::DoorOpen;
+                self.drive(MotorDir::Stop);
+                self.set_door_light(Light::On);
+                let remaining = Duration::from_millis(checkpoint.door_remaining_ms.unwrap_or(0));
+                let remaining = if remaining > self.door_open { self.door_open } else { remaining };
+                self.timers.start(TimerName::Door, remaining);
+            },
+            State::Fault => {
+                self.state = State::Fault;
+                self.request_transmitter.leave_peer_set();
+                self.head_for_floor();
+            },
+            // The stop button ended the run before this one, and nothing would
+            // get the car out of Stopped again. It goes back into service, by
+            // way of Fault when it stopped between floors.
+            State::Stopped => {
+                self.drive(MotorDir::Stop);
+                event_log::record(LogEvent::StopLight(false), None, None);
+                self.io.set_stop_light(Light::Off).unwrap();
+                match self.get_current_floor() {
+                    Floor::At(_) => self.state = State::Idle,
+                    Floor::Between => {
+                        self.state = State::Fault;
+                        self.request_transmitter.leave_peer_set();
+                        self.head_for_floor();
+                    },
+                }
+            },
+            State::Idle => {
+                self.state = State::Idle;
+                self.drive(MotorDir::Stop);
+            },
+        }
+
+        self.start_stuck_timer();
+        self.sync_lamps();
+    }
+
+
+    pub fn event_running(&mut self) {
+        self.logged(LogEvent::Running, false, |elevator| {
+            if let State::Idle = elevator.state {
+                elevator.state = State::Running;
+                metrics::record_motor_start();
+                if elevator.travel_start.is_none() {
+                    elevator.travel_start = Some(elevator.clock.now());
+                }
+            }
+        });
     }
 
 
     pub fn event_at_floor(&mut self) {
-        self.stuck_timer.start();
+        let event = match self.get_current_floor() {
+            Floor::At(floor) => LogEvent::AtFloor(floor),
+            Floor::Between => return,
+        };
 
-        if let State::Running = self.state {
-            self.state = State::Idle;
-        }
+        self.logged(event, false, |elevator| {
+            elevator.start_stuck_timer();
 
-        if let State::Idle = self.state {
-            self.set_floor_lights();
+            if let State::Fault = elevator.state {
+                // Made it to a floor after being stuck, so take part in the system again.
+                elevator.state = State::Idle;
+                elevator.request_transmitter.rejoin_peer_set();
+                elevator.request_handler.resync_with_peers();
+            }
 
-            let floor = match self.get_current_floor() {
-                Floor::At(floor) => floor,
-                Floor::Between => return,
-            };
+            if let State::Running = elevator.state {
+                elevator.state = State::Idle;
+            }
 
-            self.current_floor = floor;
+            if let State::Idle = elevator.state {
+                elevator.set_floor_lights();
 
-            if self.request_handler.should_stop(floor, self.current_direction) {
-                self.state = State::DoorOpen;
-                self.stop_and_open_doors();
-            } else {
-                self.set_direction();
+                let floor = match elevator.get_current_floor() {
+                    Floor::At(floor) => floor,
+                    Floor::Between => return,
+                };
+
+                elevator.current_floor = floor;
+
+                if elevator.request_handler.should_stop(floor, elevator.current_direction) {
+                    elevator.state = State::DoorOpen;
+                    elevator.stop_and_open_doors();
+                } else {
+                    elevator.set_direction();
+                }
             }
-        }
+        });
     }
 
 
     pub fn event_new_floor_order(&mut self, button: Button){
-        if let Button::Internal(Floor::At(floor)) = button {
-            let internal = RequestType::Internal as usize;
-            self.request_handler.requests[internal][floor] = Request {
-                floor: floor,
-                request_type: RequestType::Internal,
-                status:RequestStatus::Active,
-                ..Request::default()
-            };
-            self.event_update_button_light(button, Light::On);
-        } else {
-            self.request_handler.announce_new_request(&button);
-        }
+        let event = match button {
+            Button::CallUp(Floor::At(floor))   => LogEvent::NewFloorOrder(RequestType::CallUp, floor),
+            Button::CallDown(Floor::At(floor)) => LogEvent::NewFloorOrder(RequestType::CallDown, floor),
+            Button::Internal(Floor::At(floor)) => LogEvent::NewFloorOrder(RequestType::Internal, floor),
+            _ => return,
+        };
 
+        self.logged(event, false, |elevator| {
+            if let Button::Internal(Floor::At(floor)) = button {
+                elevator.request_handler.add_internal_request(floor);
+            } else {
+                elevator.request_handler.announce_new_request(&button);
+            }
+            elevator.sync_lamps();
+        });
     }
 
 
     pub fn event_doors_should_close(&mut self) {
-        if let State::DoorOpen = self.state {
-            self.state = State::Idle;
-            self.close_doors();
+        self.logged(LogEvent::DoorsShouldClose, false, |elevator| {
+            if let State::DoorOpen = elevator.state {
+                elevator.state = State::Idle;
+                elevator.close_doors();
+            }
+        });
+    }
+
+    // Out of service in Fault and Stopped, when the car has left the peer set
+    // and must not be given hall calls.
+    pub fn in_service(&self) -> bool {
+        match self.state {
+            State::Fault | State::Stopped => false,
+            _ => true,
         }
     }
 
-    pub fn event_update_button_light(&mut self, button: Button, mode: Light) {
-        self.io.set_button_light(button, mode);
+    // Heads for a floor, as only arriving at one gets the car out of Fault.
+    // Every try goes the other way, starting with the floor it last left.
+    fn head_for_floor(&mut self) {
+        let direction = match self.current_direction {
+            MotorDir::Up    => MotorDir::Down,
+            _               => MotorDir::Up,
+        };
+        self.current_direction = direction;
+        self.drive(direction);
+        self.start_stuck_timer();
+    }
+
+    pub fn event_stuck(&mut self) {
+        self.logged(LogEvent::Stuck, false, |elevator| {
+            if let State::Fault = elevator.state {
+                elevator.head_for_floor();
+                return;
+            }
+
+            // Leave the peer set so the other elevators take over our hall requests.
+            elevator.state = State::Fault;
+            metrics::record_stuck();
+            elevator.request_transmitter.leave_peer_set();
+            elevator.head_for_floor();
+        });
+    }
+
+    pub fn event_shutdown(&mut self) {
+        self.logged(LogEvent::Shutdown, true, |elevator| {
+            elevator.state = State::Stopped;
+            elevator.drive(MotorDir::Stop);
+            elevator.io.set_all_light(Light::Off).unwrap();
+            elevator.request_transmitter.shutdown();
+        });
     }
 
-    pub fn event_stuck(&self) {
-        self.io.set_motor_dir(MotorDir::Stop);
+    pub fn event_stop_button(&mut self) {
+        self.logged(LogEvent::StopButton, true, |elevator| {
+            elevator.state = State::Stopped;
+            elevator.drive(MotorDir::Stop);
+            event_log::record(LogEvent::StopLight(true), None, None);
+            elevator.io.set_stop_light(Light::On).unwrap();
+            elevator.request_transmitter.leave_peer_set();
+        });
     }
 
     pub fn event_request_message(&mut self, message: &Request, remote_ip: String) {
-        let result = self.request_handler.merge_incoming_request(&message, remote_ip);
+        let event = LogEvent::RequestMessage(message.clone(), remote_ip.clone());
+        self.logged(event, false, |elevator| {
+            elevator.request_handler.merge_incoming_request(message, remote_ip);
+            elevator.sync_lamps();
+        });
+    }
+
+    // The peers keep copies of each other's cab requests. The copy of this
+    // node's own comes back after a restart.
+    pub fn event_cab_requests(&mut self, owner: String, requests: &[Request]) {
+        if self.request_handler.is_local(&owner) {
+            self.event_cab_requests_returned(requests);
+        } else {
+            self.request_handler.keep_cab_requests(owner, requests);
+        }
+    }
 
-        let button: Button = match (message.floor, message.request_type) {
-            (floor, RequestType::CallUp) => Button::CallUp(Floor::At(floor)),
-            (floor, RequestType::CallDown) => Button::CallDown(Floor::At(floor)),
-            (floor, RequestType::Internal) => Button::Internal(Floor::At(floor)),
+    pub fn event_cab_requests_returned(&mut self, requests: &[Request]) {
+        let event = LogEvent::CabRequestsReturned(requests.to_vec());
+        self.logged(event, false, |elevator| {
+            elevator.request_handler.restore_cab_requests(requests);
+            elevator.sync_lamps();
+        });
+    }
+
+    pub fn event_peer_update(&mut self, update: PeerUpdate<String>) {
+        let event = LogEvent::PeerUpdate {
+            peers: update.peers.clone(),
+            new: update.new.clone(),
+            suspected: update.suspected.clone(),
+            lost: update.lost.clone(),
         };
 
-        if let Some(Light::On) = result {
-            self.event_update_button_light(button, Light::On);
-        } else if let Some(Light::Off) = result {
-            self.event_update_button_light(button, Light::Off);
-        }
+        self.logged(event, true, |elevator| {
+            metrics::record_peers(update.new.len(), update.lost.len());
+            let changes = elevator.request_handler.handle_peer_update(update, elevator.current_floor);
+            elevator.sync_lamps();
+
+            for reassignment in &changes.reassigned {
+                println!("{}", reassignment);
+            }
+        });
+    }
+
+    pub fn event_starvation_check(&mut self) {
+        self.logged(LogEvent::StarvationCheck, false, |elevator| {
+            for request in elevator.request_handler.check_starvation() {
+                println!("Starvation alarm: {:?} at floor {} has waited for {:?}",
+                         request.request_type, request.floor, elevator.request_handler.waited(&request).unwrap());
+            }
+        });
     }
 
     pub fn event_position_message(&mut self, remote_ip: String, position: usize) {
-        self.request_handler.handle_position_update(remote_ip, position);
+        // Positions are repeated all the time, so only news is logged.
+        let news = self.request_handler.peer_position(&remote_ip) != Some(position);
+        let event = LogEvent::PositionMessage(remote_ip.clone(), position);
+        self.logged(event, news, |elevator| {
+            elevator.request_handler.handle_position_update(remote_ip, position);
+        });
     }
 
 
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use elevator_driver::elev_io::{ElevIo, Floor, Button};
+    use elevator_driver::sim_io::SimIo;
+    use elevator_timer::elevator_timer::TimerName;
+    use request_handler::request_transmitter::RequestTransmitter;
+    use shutdown::shutdown::Shutdown;
+    use clock::clock::ManualClock;
+
+    #[test]
+    fn door_and_stuck_timeouts_on_manual_clock() {
+        let sim = SimIo::new();
+        let io = ElevIo::simulated(sim.clone()).unwrap();
+        let request_transmitter = Rc::new(RequestTransmitter::detached("10.0.0.8".parse().unwrap(), Shutdown::new()));
+        let mut elevator = Elevator::with_io(io, request_transmitter);
+        let clock = ManualClock::new();
+        elevator.set_clock(Arc::new(clock.clone()));
+
+        elevator.event_new_floor_order(Button::Internal(Floor::At(0)));
+        elevator.event_at_floor();
+        assert_eq!(elevator.state, State::DoorOpen);
+
+        clock.advance(Duration::from_millis(1900));
+        assert!(!elevator.timers.take_due().contains(&TimerName::Door));
+        clock.advance(Duration::from_millis(200));
+        assert!(elevator.timers.take_due().contains(&TimerName::Door));
+        elevator.event_doors_should_close();
+        assert_eq!(elevator.state, State::Idle);
+
+        // Leaves for floor 2, and never gets past the floor sensor.
+        elevator.event_new_floor_order(Button::Internal(Floor::At(2)));
+        elevator.event_at_floor();
+        sim.set_floor(Floor::Between);
+        elevator.event_running();
+        clock.advance(Duration::from_millis(5100));
+        assert_eq!(elevator.timers.take_due(), vec![TimerName::Stuck]);
+        elevator.event_stuck();
+        assert_eq!(elevator.state, State::Fault);
+    }
+
+    #[test]
+    fn stuck_car_heads_for_a_floor_and_recovers() {
+        let sim = SimIo::new();
+        let io = ElevIo::simulated(sim.clone()).unwrap();
+        let (request_transmitter, sent) = RequestTransmitter::in_memory("10.0.0.8".parse().unwrap(), Shutdown::new());
+        let request_transmitter = Rc::new(request_transmitter);
+        let mut elevator = Elevator::with_io(io, request_transmitter);
+        let clock = ManualClock::new();
+        elevator.set_clock(Arc::new(clock.clone()));
+
+        elevator.event_new_floor_order(Button::Internal(Floor::At(2)));
+        elevator.event_at_floor();
+        elevator.event_at_floor();
+        assert_eq!(sim.motor_dir(), MotorDir::Up);
+        sim.set_floor(Floor::Between);
+        elevator.event_running();
+
+        // Stuck on the way up, so it goes back to the floor it left.
+        clock.advance(Duration::from_millis(5100));
+        assert_eq!(elevator.timers.take_due(), vec![TimerName::Stuck]);
+        elevator.event_stuck();
+        assert_eq!(elevator.state, State::Fault);
+        assert_eq!(sim.motor_dir(), MotorDir::Down);
+
+        // No luck that way either, so it tries the other way again.
+        clock.advance(Duration::from_millis(5100));
+        assert_eq!(elevator.timers.take_due(), vec![TimerName::Stuck]);
+        elevator.event_stuck();
+        assert_eq!(elevator.state, State::Fault);
+        assert_eq!(sim.motor_dir(), MotorDir::Up);
+
+        // Reaching a floor ends the fault, and the order is served after all.
+        // The peers that kept the car in their peer set get its table.
+        while sent.try_recv().is_ok() {}
+        sim.set_floor(Floor::At(1));
+        elevator.event_at_floor();
+        assert_eq!(elevator.state, State::Idle);
+        assert_eq!(sent.try_iter().count(), elevator.request_handler.requests[RequestType::CallUp as usize].len() * 2);
+        elevator.event_at_floor();
+        assert_eq!(sim.motor_dir(), MotorDir::Up);
+        sim.set_floor(Floor::At(2));
+        elevator.event_at_floor();
+        assert_eq!(elevator.state, State::DoorOpen);
+    }
+
+    // A new process on the same car, as a backup that takes over.
+    fn takeover(sim: &SimIo, checkpoint: Checkpoint, clock: &ManualClock) -> Elevator {
+        let request_transmitter = Rc::new(RequestTransmitter::detached("10.0.0.8".parse().unwrap(), Shutdown::new()));
+        let floor = checkpoint.floor;
+        let mut elevator = Elevator::attached(ElevIo::attach_simulated(sim.clone()), request_transmitter, floor);
+        elevator.set_clock(Arc::new(clock.clone()));
+        elevator.restore(checkpoint);
+        elevator
+    }
+
+    #[test]
+    fn restore_resumes_mid_trip_and_mid_door() {
+        let sim = SimIo::new();
+        let io = ElevIo::simulated(sim.clone()).unwrap();
+        let request_transmitter = Rc::new(RequestTransmitter::detached("10.0.0.8".parse().unwrap(), Shutdown::new()));
+        let mut elevator = Elevator::with_io(io, request_transmitter);
+        let clock = ManualClock::new();
+        elevator.set_clock(Arc::new(clock.clone()));
+
+        // Between floors on the way to floor 2.
+        elevator.event_new_floor_order(Button::Internal(Floor::At(2)));
+        elevator.event_at_floor();
+        elevator.event_at_floor();
+        sim.set_floor(Floor::Between);
+        elevator.event_running();
+        let checkpoint = elevator.checkpoint();
+        assert_eq!(checkpoint.state, State::Running);
+        drop(elevator);
+        // The motor stopped with the process that drove it.
+        ElevIo::attach_simulated(sim.clone()).set_motor_dir(MotorDir::Stop).unwrap();
+
+        let mut elevator = takeover(&sim, checkpoint, &clock);
+        assert_eq!(elevator.state, State::Running);
+        assert_eq!(sim.motor_dir(), MotorDir::Up);
+        sim.set_floor(Floor::At(2));
+        elevator.event_at_floor();
+        assert_eq!(elevator.state, State::DoorOpen);
+
+        // With the door open for 1.5 of its 2 seconds.
+        clock.advance(Duration::from_millis(1500));
+        let checkpoint = elevator.checkpoint();
+        assert_eq!(checkpoint.state, State::DoorOpen);
+        drop(elevator);
+
+        let mut elevator = takeover(&sim, checkpoint, &clock);
+        assert_eq!(elevator.state, State::DoorOpen);
+        assert!(match sim.door_light() { Light::On => true, Light::Off => false });
+        assert_eq!(sim.motor_dir(), MotorDir::Stop);
+        clock.advance(Duration::from_millis(400));
+        assert!(!elevator.timers.take_due().contains(&TimerName::Door));
+        clock.advance(Duration::from_millis(200));
+        assert!(elevator.timers.take_due().contains(&TimerName::Door));
+        elevator.event_doors_should_close();
+        assert_eq!(elevator.state, State::Idle);
+    }
+
+    #[test]
+    fn restore_after_the_stop_button_goes_back_into_service() {
+        let sim = SimIo::new();
+        let io = ElevIo::simulated(sim.clone()).unwrap();
+        let request_transmitter = Rc::new(RequestTransmitter::detached("10.0.0.8".parse().unwrap(), Shutdown::new()));
+        let mut elevator = Elevator::with_io(io, request_transmitter);
+        let clock = ManualClock::new();
+        elevator.set_clock(Arc::new(clock.clone()));
+
+        // Stopped at a floor, it is idle and takes orders again.
+        elevator.event_stop_button();
+        let checkpoint = elevator.checkpoint();
+        assert_eq!(checkpoint.state, State::Stopped);
+        drop(elevator);
+        let mut elevator = takeover(&sim, checkpoint, &clock);
+        assert_eq!(elevator.state, State::Idle);
+        assert!(elevator.in_service());
+        elevator.event_new_floor_order(Button::Internal(Floor::At(2)));
+        elevator.event_at_floor();
+        elevator.event_at_floor();
+        assert_eq!(sim.motor_dir(), MotorDir::Up);
+
+        // Stopped between floors, it heads for one and is back in service there.
+        sim.set_floor(Floor::Between);
+        elevator.event_running();
+        elevator.event_stop_button();
+        let checkpoint = elevator.checkpoint();
+        drop(elevator);
+        let mut elevator = takeover(&sim, checkpoint, &clock);
+        assert_eq!(elevator.state, State::Fault);
+        assert!(sim.motor_dir() != MotorDir::Stop);
+        sim.set_floor(Floor::At(1));
+        elevator.event_at_floor();
+        assert!(elevator.in_service());
+    }
+}
@@ -1,34 +1,285 @@
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
-pub struct Timer {
-    start_time: Instant,
-    duration:   Duration,
+use event_loop::event_loop::Event;
+use clock::clock::{self, Clock};
+
+// The delivery thread looks at the clock at least this often, so it notices a
+// manual clock being advanced.
+const WAKE_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimerName {
+    Door,
+    Stuck,
+    Broadcast,
+    Starvation,
+    Retransmit,
+    ShipLog,
+}
+
+// Delivered to the event loop when a timer runs out. The generation tells an
+// expiry of the current run from one that was cancelled or restarted after it
+// was sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Expiry {
+    pub name: TimerName,
+    generation: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Scheduled {
+    generation: u64,
+    deadline: Instant,
+    period: Option<Duration>,
+}
+
+enum Command {
+    Schedule(TimerName, Scheduled),
+    Cancel(TimerName),
+}
+
+fn until(deadline: Instant, now: Instant) -> Duration {
+    if deadline > now {
+        deadline - now
+    } else {
+        Duration::new(0, 0)
+    }
+}
+
+// Keeps the timers of one node. It knows when every timer runs out, and once
+// delivering, a thread sends the expiries to the event loop.
+pub struct TimerService {
+    timers: HashMap<TimerName, Scheduled>,
+    generation: u64,
+    clock: Arc<dyn Clock>,
+    commands: Option<Sender<Command>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TimerService {
+    // Nothing is delivered until deliver_to is called.
+    pub fn new() -> Self {
+        TimerService {
+            timers: HashMap::new(),
+            generation: 0,
+            clock: clock::real(),
+            commands: None,
+            thread: None,
+        }
+    }
+
+    // Starts sending expiries as events. Timers that are already running are
+    // handed over as they are.
+    pub fn deliver_to(&mut self, events: Sender<Event>) {
+        let (command_tx, command_rx) = channel::<Command>();
+        for (&name, &scheduled) in &self.timers {
+            let _ = command_tx.send(Command::Schedule(name, scheduled));
+        }
+        self.stop_delivery();
+        let clock = self.clock.clone();
+        self.commands = Some(command_tx);
+        self.thread = Some(thread::spawn(move|| run(command_rx, events, clock)));
+    }
+
+    // Set before any timer is started or delivered.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    // Starts the timer, or starts it over if it was running.
+    pub fn start(&mut self, name: TimerName, duration: Duration) {
+        self.schedule(name, duration, None);
+    }
+
+    // Runs out every period until cancelled.
+    pub fn repeat(&mut self, name: TimerName, period: Duration) {
+        self.schedule(name, period, Some(period));
+    }
+
+    pub fn cancel(&mut self, name: TimerName) {
+        if self.timers.remove(&name).is_some() {
+            self.send(Command::Cancel(name));
+        }
+    }
+
+    pub fn is_running(&self, name: TimerName) -> bool {
+        self.timers.contains_key(&name)
+    }
+
+    pub fn remaining(&self, name: TimerName) -> Option<Duration> {
+        self.timers.get(&name).map(|scheduled| until(scheduled.deadline, self.clock.now()))
+    }
+
+    // The timers that have run out by now, for a node that is stepped by hand
+    // instead of delivering to an event loop.
+    pub fn take_due(&mut self) -> Vec<TimerName> {
+        let now = self.clock.now();
+        let mut due: Vec<Expiry> = self.timers.iter()
+            .filter(|&(_, scheduled)| scheduled.deadline <= now)
+            .map(|(&name, scheduled)| Expiry { name: name, generation: scheduled.generation })
+            .collect();
+        // In the order they were started.
+        due.sort_by_key(|expiry| expiry.generation);
+        for &expiry in &due {
+            self.expired(expiry);
+        }
+        due.into_iter().map(|expiry| expiry.name).collect()
+    }
+
+    // Takes an expiry from the event loop. False if the timer was cancelled or
+    // restarted since, and the expiry should be ignored.
+    pub fn expired(&mut self, expiry: Expiry) -> bool {
+        let period = match self.timers.get(&expiry.name) {
+            Some(scheduled) if scheduled.generation == expiry.generation => scheduled.period,
+            _ => return false,
+        };
+        match period {
+            Some(period) => {
+                let scheduled = self.timers.get_mut(&expiry.name).unwrap();
+                scheduled.deadline += period;
+            },
+            None => {
+                self.timers.remove(&expiry.name);
+            },
+        }
+        true
+    }
+
+    fn schedule(&mut self, name: TimerName, duration: Duration, period: Option<Duration>) {
+        self.generation += 1;
+        let scheduled = Scheduled {
+            generation: self.generation,
+            deadline: self.clock.now() + duration,
+            period: period,
+        };
+        self.timers.insert(name, scheduled);
+        self.send(Command::Schedule(name, scheduled));
+    }
+
+    fn send(&self, command: Command) {
+        if let Some(ref commands) = self.commands {
+            let _ = commands.send(command);
+        }
+    }
+
+    fn stop_delivery(&mut self) {
+        // Hanging up ends the thread.
+        self.commands = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
 }
 
-impl Timer {
+impl Drop for TimerService {
+    fn drop(&mut self) {
+        self.stop_delivery();
+    }
+}
 
-    pub fn new(timer_duration: u64) -> Self {
-        let start_time = Instant::now();
-        let duration = Duration::new(timer_duration, 0);
+fn run(commands: Receiver<Command>, events: Sender<Event>, clock: Arc<dyn Clock>) {
+    let mut timers: HashMap<TimerName, Scheduled> = HashMap::new();
+    loop {
+        let now = clock.now();
+        let due: Vec<TimerName> = timers.iter()
+            .filter(|&(_, scheduled)| scheduled.deadline <= now)
+            .map(|(&name, _)| name)
+            .collect();
+        for name in due {
+            let scheduled = timers[&name];
+            let expiry = Expiry { name: name, generation: scheduled.generation };
+            if events.send(Event::Timer(expiry)).is_err() {
+                return;
+            }
+            match scheduled.period {
+                Some(period) => timers.get_mut(&name).unwrap().deadline += period,
+                None => {
+                    timers.remove(&name);
+                },
+            }
+        }
 
-        let timer = Timer {
-            start_time: start_time,
-            duration:   duration,
+        let next_deadline = timers.values().map(|scheduled| scheduled.deadline).min();
+        let command = match next_deadline {
+            Some(deadline) => {
+                let wait = until(deadline, clock.now());
+                let wait = if wait > Duration::from_millis(WAKE_MS) { Duration::from_millis(WAKE_MS) } else { wait };
+                match commands.recv_timeout(wait) {
+                    Ok(command) => command,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            },
+            None => match commands.recv() {
+                Ok(command) => command,
+                Err(_) => return,
+            },
         };
 
-        return timer;
+        match command {
+            Command::Schedule(name, scheduled) => {
+                timers.insert(name, scheduled);
+            },
+            Command::Cancel(name) => {
+                timers.remove(&name);
+            },
+        }
     }
+}
 
 
-    pub fn start(&mut self) {
-        self.start_time = Instant::now();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use event_loop::event_loop::Event;
+
+    fn next_expiry(events: &::std::sync::mpsc::Receiver<Event>) -> Expiry {
+        match events.recv_timeout(Duration::from_secs(1)) {
+            Ok(Event::Timer(expiry)) => expiry,
+            other => panic!("expected a timer event, got {:?}", other),
+        }
     }
 
+    #[test]
+    fn delivers_named_expiries() {
+        let (events_tx, events_rx) = channel::<Event>();
+        let mut timers = TimerService::new();
+        // Started before delivery, handed over to the thread.
+        timers.start(TimerName::Door, Duration::from_millis(30));
+        timers.deliver_to(events_tx);
+        timers.repeat(TimerName::Broadcast, Duration::from_millis(50));
+        timers.start(TimerName::Stuck, Duration::from_secs(1));
+        timers.cancel(TimerName::Stuck);
+
+        let expiry = next_expiry(&events_rx);
+        assert_eq!(expiry.name, TimerName::Door);
+        assert!(timers.expired(expiry));
+        assert!(!timers.is_running(TimerName::Door));
+
+        for _ in 0..2 {
+            let expiry = next_expiry(&events_rx);
+            assert_eq!(expiry.name, TimerName::Broadcast);
+            assert!(timers.expired(expiry));
+            assert!(timers.is_running(TimerName::Broadcast));
+        }
 
-    pub fn timeout(&self) -> bool {
-        return Instant::now() > self.start_time + self.duration;
+        // An expiry sent before a restart is stale.
+        timers.start(TimerName::Door, Duration::from_millis(0));
+        let mut stale = next_expiry(&events_rx);
+        while stale.name != TimerName::Door {
+            stale = next_expiry(&events_rx);
+        }
+        timers.start(TimerName::Door, Duration::from_secs(10));
+        assert!(!timers.expired(stale));
+        assert!(timers.remaining(TimerName::Door).unwrap() > Duration::from_secs(9));
     }
 }
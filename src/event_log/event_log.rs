@@ -0,0 +1,343 @@
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::fs::{File, OpenOptions};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use request_handler::request::{Request, RequestType, now_ms};
+use request_handler::request_transmitter::BroadcastMessage;
+use elevator_fsm::elevator_fsm::State;
+use network::fault::FaultStats;
+
+// What the FSM looked like around an event.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FsmSnapshot {
+    pub state: State,
+    pub floor: usize,
+    pub direction: String,
+    pub active_requests: Vec<(RequestType, usize)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LogEvent {
+    // Inputs to the FSM
+    Running,
+    AtFloor(usize),
+    NewFloorOrder(RequestType, usize),
+    DoorsShouldClose,
+    Stuck,
+    StopButton,
+    Shutdown,
+    StarvationCheck,
+    RequestMessage(Request, String),
+    CabRequestsReturned(Vec<Request>),
+    PositionMessage(String, usize),
+    PeerUpdate { peers: Vec<String>, new: Vec<String>, suspected: Vec<String>, lost: Vec<String> },
+
+    // Hardware actions and outgoing messages
+    Motor(String),
+    ButtonLight(RequestType, usize, bool),
+    DoorLight(bool),
+    FloorLight(usize),
+    StopLight(bool),
+    MessageSent(BroadcastMessage),
+
+    // Not caused by an event, and left out when a log is replayed
+    FaultsInjected(FaultStats),
+}
+
+// One line of the log. Only FSM events carry the state before and after.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub timestamp: u64,
+    pub node: String,
+    pub event: LogEvent,
+    pub before: Option<FsmSnapshot>,
+    pub after: Option<FsmSnapshot>,
+}
+
+impl LogRecord {
+    pub fn is_input(&self) -> bool {
+        self.before.is_some()
+    }
+
+    pub fn is_report(&self) -> bool {
+        match self.event {
+            LogEvent::FaultsInjected(_) => true,
+            _ => false,
+        }
+    }
+
+    // Whether two records describe the same decision, regardless of when and where.
+    pub fn same_as(&self, other: &LogRecord) -> bool {
+        without_times(&self.event) == without_times(&other.event)
+            && self.before == other.before
+            && self.after == other.after
+    }
+}
+
+fn request_without_times(request: &Request) -> Request {
+    let mut request = request.clone();
+    request.created_at = None;
+    request.confirmed_at = None;
+    request.served_at = None;
+    request
+}
+
+// The request times in sent messages come from the clock, so they never match a replay.
+fn without_times(event: &LogEvent) -> LogEvent {
+    match *event {
+        LogEvent::MessageSent(BroadcastMessage::RequestMessage(ref request)) => {
+            LogEvent::MessageSent(BroadcastMessage::RequestMessage(request_without_times(request)))
+        },
+        LogEvent::MessageSent(BroadcastMessage::Reliable { id, ref request }) => {
+            LogEvent::MessageSent(BroadcastMessage::Reliable { id: id, request: request_without_times(request) })
+        },
+        ref event => event.clone(),
+    }
+}
+
+enum Sink {
+    File(BufWriter<File>),
+    Memory(Vec<LogRecord>),
+}
+
+struct Log {
+    node: String,
+    sink: Sink,
+    // Actions taken while an FSM event is handled, written after the event.
+    pending: Option<Vec<LogRecord>>,
+}
+
+impl Log {
+    fn write(&mut self, record: LogRecord) {
+        match self.sink {
+            Sink::File(ref mut writer) => {
+                let line = serde_json::to_string(&record).unwrap();
+                // Flushed per record, so the log survives a crash up to the last event.
+                if writeln!(writer, "{}", line).and_then(|_| writer.flush()).is_err() {
+                    println!("Writing to the event log failed");
+                }
+            },
+            Sink::Memory(ref mut records) => records.push(record),
+        }
+    }
+}
+
+// Everything that is logged happens on the thread running the FSM, so the log
+// is kept per thread. This also keeps a replay from mixing with a live log.
+thread_local! {
+    static LOG: RefCell<Option<Log>> = const { RefCell::new(None) };
+}
+
+// Appends the records of this thread to the file at path.
+pub fn open<P: AsRef<Path>>(path: P, node: String) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    LOG.with(|log| {
+        *log.borrow_mut() = Some(Log { node: node, sink: Sink::File(BufWriter::new(file)), pending: None });
+    });
+    Ok(())
+}
+
+// Keeps the records of this thread in memory until take_captured is called.
+pub fn capture(node: String) {
+    LOG.with(|log| {
+        *log.borrow_mut() = Some(Log { node: node, sink: Sink::Memory(Vec::new()), pending: None });
+    });
+}
+
+pub fn take_captured() -> Vec<LogRecord> {
+    LOG.with(|log| {
+        match *log.borrow_mut() {
+            Some(Log { sink: Sink::Memory(ref mut records), .. }) => std::mem::take(records),
+            _ => Vec::new(),
+        }
+    })
+}
+
+pub fn close() {
+    LOG.with(|log| {
+        if let Some(Log { sink: Sink::File(ref mut writer), .. }) = *log.borrow_mut() {
+            let _ = writer.flush();
+        }
+        *log.borrow_mut() = None;
+    });
+}
+
+pub fn is_open() -> bool {
+    LOG.with(|log| log.borrow().is_some())
+}
+
+pub fn record(event: LogEvent, before: Option<FsmSnapshot>, after: Option<FsmSnapshot>) {
+    LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let log = match *log {
+            Some(ref mut log) => log,
+            None => return,
+        };
+
+        let record = LogRecord {
+            timestamp: now_ms(),
+            node: log.node.clone(),
+            event: event,
+            before: before,
+            after: after,
+        };
+
+        match log.pending {
+            Some(ref mut pending) => pending.push(record),
+            None => log.write(record),
+        }
+    });
+}
+
+// Holds back every record until end_event, so an FSM event is logged ahead of
+// the actions it caused.
+pub fn begin_event() {
+    LOG.with(|log| {
+        if let Some(ref mut log) = *log.borrow_mut() {
+            log.pending = Some(Vec::new());
+        }
+    });
+}
+
+// Writes the event and the actions it caused. An event without any effect is
+// left out unless always is set, as most events repeat on every loop.
+pub fn end_event(event: LogEvent, before: FsmSnapshot, after: FsmSnapshot, always: bool) {
+    LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let log = match *log {
+            Some(ref mut log) => log,
+            None => return,
+        };
+
+        let pending = log.pending.take().unwrap_or_else(Vec::new);
+        if !always && pending.is_empty() && before == after {
+            return;
+        }
+
+        let record = LogRecord {
+            timestamp: now_ms(),
+            node: log.node.clone(),
+            event: event,
+            before: Some(before),
+            after: Some(after),
+        };
+        log.write(record);
+        for action in pending {
+            log.write(action);
+        }
+    });
+}
+
+pub fn read_log<P: AsRef<Path>>(path: P) -> io::Result<Vec<LogRecord>> {
+    let file = File::open(path)?;
+    let mut records = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, err))
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// How much of the log is shipped at once.
+const SHIP_BYTES: u64 = 256 * 1024;
+
+// Reads what was added to the log since the last time, to ship it to the node
+// collecting the logs. Only what this run writes is shipped.
+pub struct LogShipper {
+    path: PathBuf,
+    offset: u64,
+    // Lines that could not be delivered, sent again before anything newer.
+    returned: Vec<String>,
+}
+
+impl LogShipper {
+    pub fn new<P: AsRef<Path>>(path: P) -> LogShipper {
+        let offset = File::open(path.as_ref()).and_then(|file| file.metadata()).map_or(0, |meta| meta.len());
+        LogShipper { path: path.as_ref().to_path_buf(), offset: offset, returned: Vec::new() }
+    }
+
+    // A line still being written is left for the next time.
+    pub fn take_lines(&mut self) -> io::Result<Vec<String>> {
+        if !self.returned.is_empty() {
+            return Ok(self.returned.drain(..).collect());
+        }
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut text = String::new();
+        file.take(SHIP_BYTES).read_to_string(&mut text)?;
+        let complete = match text.rfind('\n') {
+            Some(end) => end + 1,
+            None => return Ok(Vec::new()),
+        };
+        self.offset += complete as u64;
+        Ok(text[..complete].lines().filter(|line| !line.trim().is_empty()).map(|line| line.to_string()).collect())
+    }
+
+    pub fn give_back(&mut self, mut lines: Vec<String>) {
+        lines.append(&mut self.returned);
+        self.returned = lines;
+    }
+}
+
+// Adds lines shipped from a peer to the collected log. They carry the node
+// that wrote them, so read_log reads the collected log like any other.
+pub fn append_lines<P: AsRef<Path>>(path: P, lines: &[String]) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    for line in lines {
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn shipper_takes_whole_new_lines_and_resends_returned_ones() {
+        let dir = env::temp_dir();
+        let path = dir.join(format!("elevator_ship_{}.jsonl", ::std::process::id()));
+        let collected = dir.join(format!("elevator_collected_{}.jsonl", ::std::process::id()));
+        let _ = fs::remove_file(&collected);
+        fs::write(&path, "{\"before\":1}\n").unwrap();
+
+        let mut shipper = LogShipper::new(&path);
+        assert!(shipper.take_lines().unwrap().is_empty());
+
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            write!(file, "{{\"a\":1}}\n{{\"b\":").unwrap();
+        }
+        let lines = shipper.take_lines().unwrap();
+        assert_eq!(lines, vec!["{\"a\":1}".to_string()]);
+
+        shipper.give_back(lines);
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            writeln!(file, "2}}").unwrap();
+        }
+        assert_eq!(shipper.take_lines().unwrap(), vec!["{\"a\":1}".to_string()]);
+        let lines = shipper.take_lines().unwrap();
+        assert_eq!(lines, vec!["{\"b\":2}".to_string()]);
+
+        append_lines(&collected, &lines).unwrap();
+        append_lines(&collected, &lines).unwrap();
+        assert_eq!(fs::read_to_string(&collected).unwrap(), "{\"b\":2}\n{\"b\":2}\n");
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&collected).unwrap();
+    }
+}
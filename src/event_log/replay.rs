@@ -0,0 +1,217 @@
+use std::fmt;
+use std::rc::Rc;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
+
+use elevator_driver::elev_io::{ElevIo, Floor, Button};
+use elevator_driver::sim_io::SimIo;
+use elevator_fsm::elevator_fsm::Elevator;
+use request_handler::request::{RequestType, peer_ip};
+use request_handler::request_transmitter::RequestTransmitter;
+use network::peer::{PeerUpdate, PeerInfo};
+use shutdown::shutdown::Shutdown;
+use event_log::event_log::{self, LogEvent, LogRecord};
+
+// An event whose replay did not give the records that were logged for it.
+#[derive(Debug)]
+pub struct Divergence {
+    pub index: usize,
+    pub expected: Vec<LogRecord>,
+    pub actual: Vec<LogRecord>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Divergence at record {}:", self.index)?;
+        writeln!(f, "\texpected:")?;
+        for record in &self.expected {
+            writeln!(f, "\t\t{:?} {:?} -> {:?}", record.event, record.before, record.after)?;
+        }
+        writeln!(f, "\tactual:")?;
+        for record in &self.actual {
+            writeln!(f, "\t\t{:?} {:?} -> {:?}", record.event, record.before, record.after)?;
+        }
+        Ok(())
+    }
+}
+
+fn same_records(expected: &[LogRecord], actual: &[LogRecord]) -> bool {
+    expected.len() == actual.len()
+        && expected.iter().zip(actual.iter()).all(|(a, b)| a.same_as(b))
+}
+
+fn peer_update(peers: &Vec<String>, new: &Vec<String>, suspected: &Vec<String>, lost: &Vec<String>) -> PeerUpdate<String> {
+    let now = Instant::now();
+    let mut update = PeerUpdate::new();
+    for id in peers {
+        update.add_peers(id.clone());
+        update.add_info(PeerInfo {
+            id: id.clone(),
+            first_seen: now,
+            last_seen: now,
+            suspected: suspected.contains(id),
+        });
+    }
+    for id in new {
+        update.add_new(id.clone());
+    }
+    for id in suspected {
+        update.add_suspected(id.clone());
+    }
+    for id in lost {
+        update.add_lost(id.clone());
+    }
+    update
+}
+
+// Feeds one logged FSM event to the elevator. The floor sensor is set to what
+// the event says the elevator saw.
+fn apply(elevator: &mut Elevator, sim: &SimIo, event: &LogEvent) {
+    match *event {
+        LogEvent::Running => {
+            sim.set_floor(Floor::Between);
+            elevator.event_running();
+        },
+        LogEvent::AtFloor(floor) => {
+            sim.set_floor(Floor::At(floor));
+            elevator.event_at_floor();
+        },
+        LogEvent::NewFloorOrder(request_type, floor) => {
+            let button = match request_type {
+                RequestType::CallUp => Button::CallUp(Floor::At(floor)),
+                RequestType::CallDown => Button::CallDown(Floor::At(floor)),
+                RequestType::Internal => Button::Internal(Floor::At(floor)),
+            };
+            elevator.event_new_floor_order(button);
+        },
+        LogEvent::DoorsShouldClose => elevator.event_doors_should_close(),
+        LogEvent::Stuck => elevator.event_stuck(),
+        LogEvent::StopButton => elevator.event_stop_button(),
+        LogEvent::Shutdown => elevator.event_shutdown(),
+        LogEvent::StarvationCheck => elevator.event_starvation_check(),
+        LogEvent::RequestMessage(ref request, ref remote_ip) => {
+            elevator.event_request_message(request, remote_ip.clone());
+        },
+        LogEvent::CabRequestsReturned(ref requests) => elevator.event_cab_requests_returned(requests),
+        LogEvent::PositionMessage(ref remote_ip, floor) => {
+            elevator.event_position_message(remote_ip.clone(), floor);
+        },
+        LogEvent::PeerUpdate { ref peers, ref new, ref suspected, ref lost } => {
+            elevator.event_peer_update(peer_update(peers, new, suspected, lost));
+        },
+        _ => {},
+    }
+}
+
+// Runs the logged events of one node through a fresh FSM and RequestHandler on
+// simulated hardware, and returns every event that was decided differently.
+pub fn replay(records: &[LogRecord]) -> Vec<Divergence> {
+    let first_input = match records.iter().position(|record| record.is_input()) {
+        Some(index) => index,
+        None => return Vec::new(),
+    };
+
+    // The node is known by its address, which the request handler uses for
+    // acknowledgements and for breaking ties in assignment.
+    let node = records[first_input].node.clone();
+    let local_ip = peer_ip(&node).parse::<IpAddr>()
+        .unwrap_or(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+
+    let sim = SimIo::new();
+    if let Some(ref before) = records[first_input].before {
+        sim.set_floor(Floor::At(before.floor));
+    }
+    let io = ElevIo::simulated(sim.clone()).expect("Init of simulated io failed");
+    let request_transmitter = Rc::new(RequestTransmitter::detached(local_ip, Shutdown::new()));
+    let mut elevator = Elevator::with_io(io, request_transmitter);
+
+    event_log::capture(node);
+
+    let mut divergences = Vec::new();
+    let mut index = first_input;
+    while index < records.len() {
+        let end = records[index + 1..].iter()
+            .position(|record| record.is_input())
+            .map_or(records.len(), |offset| index + 1 + offset);
+
+        apply(&mut elevator, &sim, &records[index].event);
+        let actual = event_log::take_captured();
+
+        let expected: Vec<LogRecord> = records[index..end].iter()
+            .filter(|record| !record.is_report())
+            .cloned()
+            .collect();
+        if !same_records(&expected, &actual) {
+            divergences.push(Divergence {
+                index: index,
+                expected: expected,
+                actual: actual,
+            });
+        }
+        index = end;
+    }
+
+    event_log::close();
+    divergences
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::net::IpAddr;
+    use elevator_driver::elev_io::{ElevIo, Floor, Button};
+    use elevator_driver::sim_io::SimIo;
+    use elevator_fsm::elevator_fsm::Elevator;
+    use request_handler::request_transmitter::RequestTransmitter;
+    use shutdown::shutdown::Shutdown;
+    use event_log::event_log::{self, LogEvent};
+    use network::fault::FaultStats;
+
+    #[test]
+    fn replay_reproduces_recorded_run() {
+        let local_ip: IpAddr = "10.0.0.7".parse().unwrap();
+        let sim = SimIo::new();
+        let io = ElevIo::simulated(sim.clone()).unwrap();
+        let request_transmitter = Rc::new(RequestTransmitter::detached(local_ip, Shutdown::new()));
+        let mut elevator = Elevator::with_io(io, request_transmitter);
+
+        event_log::capture(local_ip.to_string());
+        elevator.event_at_floor();
+        elevator.event_new_floor_order(Button::Internal(Floor::At(2)));
+        // Turns around first, then drives off.
+        elevator.event_at_floor();
+        elevator.event_at_floor();
+        sim.set_floor(Floor::Between);
+        elevator.event_running();
+        sim.set_floor(Floor::At(1));
+        elevator.event_at_floor();
+        sim.set_floor(Floor::At(2));
+        elevator.event_at_floor();
+        elevator.event_doors_should_close();
+        let records = event_log::take_captured();
+        event_log::close();
+
+        assert!(records.iter().any(|record| record.event == LogEvent::DoorLight(true)));
+        let divergences = replay(&records);
+        assert!(divergences.is_empty(), "{}", divergences[0]);
+
+        // The injected faults are reported in between, and are no decision of the FSM.
+        let mut records = records;
+        let report = event_log::LogRecord {
+            event: LogEvent::FaultsInjected(FaultStats { dropped: 2, ..FaultStats::default() }),
+            before: None,
+            after: None,
+            ..records[0].clone()
+        };
+        records.insert(2, report);
+        assert!(replay(&records).is_empty());
+
+        // A log that disagrees with the code is reported.
+        let last = records.iter().rposition(|record| record.is_input()).unwrap();
+        let bogus = event_log::LogRecord { event: LogEvent::Stuck, ..records[last].clone() };
+        records.push(bogus);
+        assert_eq!(replay(&records).len(), 1);
+    }
+}
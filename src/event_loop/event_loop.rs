@@ -0,0 +1,124 @@
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use elevator_driver::elev_io::{Button, Floor, Signal};
+use request_handler::request::IP;
+use request_handler::request_transmitter::{BroadcastMessage, Transfer};
+use network::peer::PeerUpdate;
+use elevator_timer::elevator_timer::Expiry;
+use shutdown::shutdown::Shutdown;
+
+const POLL_MS: u64 = 100;
+
+// Everything the main loop reacts to. Each source feeds the same channel, so the
+// loop blocks on one receiver.
+#[derive(Debug)]
+pub enum Event {
+    Button(Button),
+    Floor(Floor),
+    Message(BroadcastMessage, IP),
+    Transfer(Transfer, IP),
+    TransferFailed(Transfer, IP),
+    Peer(PeerUpdate<IP>),
+    Timer(Expiry),
+    Stop,
+    Obstruction(Signal),
+}
+
+pub struct EventLoop {
+    sender: Sender<Event>,
+    receiver: Receiver<Event>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl EventLoop {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel::<Event>();
+        EventLoop {
+            sender: sender,
+            receiver: receiver,
+            threads: Vec::new(),
+        }
+    }
+
+    // For sources that run their own thread, e.g. the button polling and the timers.
+    pub fn sender(&self) -> Sender<Event> {
+        self.sender.clone()
+    }
+
+    // Turns everything arriving on receiver into events, until the far end hangs
+    // up or the shutdown is triggered.
+    pub fn forward<T, F>(&mut self, receiver: Receiver<T>, to_event: F, shutdown: Shutdown)
+        where T: Send + 'static, F: Fn(T) -> Event + Send + 'static
+    {
+        let sender = self.sender.clone();
+        self.threads.push(thread::spawn(move|| {
+            while !shutdown.is_triggered() {
+                match receiver.recv_timeout(Duration::from_millis(POLL_MS)) {
+                    Ok(item) => {
+                        if sender.send(to_event(item)).is_err() {
+                            return;
+                        }
+                    },
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        }));
+    }
+
+    // The next event, or None if nothing happened within timeout.
+    pub fn next(&self, timeout: Duration) -> Option<Event> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    // Waits for the sources started here. They stop once the shutdown is triggered.
+    pub fn join(self) {
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use elevator_driver::elev_io::{Button, Floor};
+    use shutdown::shutdown::Shutdown;
+    use elevator_timer::elevator_timer::{TimerService, TimerName};
+
+    #[test]
+    fn sources_share_one_loop() {
+        let shutdown = Shutdown::new();
+        let mut events = EventLoop::new();
+
+        let (button_tx, button_rx) = channel::<Button>();
+        events.forward(button_rx, Event::Button, shutdown.clone());
+        let mut timers = TimerService::new();
+        timers.deliver_to(events.sender());
+        timers.repeat(TimerName::Broadcast, Duration::from_millis(20));
+        events.sender().send(Event::Stop).unwrap();
+        button_tx.send(Button::Internal(Floor::At(2))).unwrap();
+
+        let (mut buttons, mut ticks, mut stops) = (0, 0, 0);
+        while ticks < 2 {
+            match events.next(Duration::from_secs(1)) {
+                Some(Event::Button(Button::Internal(Floor::At(2)))) => buttons += 1,
+                Some(Event::Timer(expiry)) => {
+                    assert!(timers.expired(expiry));
+                    ticks += 1;
+                },
+                Some(Event::Stop) => stops += 1,
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+        assert_eq!((buttons, stops), (1, 1));
+
+        shutdown.trigger();
+        events.join();
+    }
+}
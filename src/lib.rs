@@ -1,5 +1,7 @@
-#![feature(mpsc_select)]
-#![feature(type_ascription)]
+// Fields are named in full, modules are named after their directory, new()
+// comes without Default and floors are looped over by index throughout.
+#![allow(clippy::redundant_field_names, clippy::module_inception, clippy::new_without_default,
+         clippy::match_like_matches_macro, clippy::needless_range_loop)]
 
 #[macro_use]
 extern crate lazy_static;
@@ -9,11 +11,18 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate net2;
 extern crate rand;
-extern crate chrono;
-extern crate timer;
 
 pub mod elevator_driver;
 pub mod elevator_fsm;
 pub mod request_handler;
 pub mod network;
 pub mod elevator_timer;
+pub mod shutdown;
+pub mod metrics;
+pub mod event_log;
+pub mod config;
+pub mod supervisor;
+pub mod checkpoint;
+pub mod event_loop;
+pub mod clock;
+pub mod simulation;
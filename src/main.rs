@@ -1,123 +1,456 @@
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
-#![feature(mpsc_select)]
-
-extern crate chrono;
-extern crate timer;
+// Floors are looped over by index, as in the library.
+#![allow(clippy::needless_range_loop)]
 
 extern crate elevator;
 use std::{thread, time};
 use elevator::elevator_driver::elev_io::*;
 use elevator::elevator_fsm::elevator_fsm::*;
 
-use std::sync::mpsc::channel;
 use elevator::request_handler::request_transmitter::*;
 use elevator::request_handler::request_transmitter::BroadcastMessage;
+use elevator::shutdown::shutdown::{Shutdown, install_signal_handlers};
+use elevator::request_handler::request::{RequestType, peer_ip};
+use elevator::metrics::metrics;
+use elevator::metrics::exporter::{spawn_http_exporter, spawn_file_dump};
+use elevator::event_log::event_log::{self, LogEvent, LogShipper};
+use elevator::network::localip::get_localip;
+use elevator::elevator_driver::sim_io::{SimIo, run_car, parse_button};
+use elevator::checkpoint::checkpoint::{self, Checkpointer};
+use elevator::event_loop::event_loop::{EventLoop, Event};
+use elevator::elevator_timer::elevator_timer::TimerName;
+use elevator::config::config::{Config, Backend};
+use elevator::supervisor::supervisor::{run_backup, spawn_backup, spawn_heartbeat, abort_on_panic, BackupOutcome};
+use elevator::network::fault::spawn_admin;
+use elevator::request_handler::reliable::ReliableConfig;
+use std::env;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::process;
 use std::rc::Rc;
 
 
+const METRICS_DUMP_S: u64 = 10;
+const STARVATION_CHECK_S: u64 = 1;
+const RETRANSMIT_CHECK_MS: u64 = 20;
+const LOG_SHIP_S: u64 = 5;
+
+// Passengers of the simulated car type their button presses on stdin.
+fn spawn_sim_passengers(sim: SimIo, poll_period: time::Duration) {
+    thread::spawn(move|| {
+        println!("Press buttons by typing u<floor>, d<floor> or c<floor>");
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            let button = match parse_button(&line) {
+                Some(button) => button,
+                None => {
+                    println!("Unknown button {:?}", line);
+                    continue;
+                }
+            };
+            if let Err(err) = sim.set_button(button, Signal::High) {
+                println!("Cannot press {:?}. Error: {}", line, err);
+                continue;
+            }
+            // Held long enough for the polling thread to see it.
+            thread::sleep(poll_period * 3);
+            let _ = sim.set_button(button, Signal::Low);
+        }
+    });
+}
+
 fn main() {
-    let request_transmitter: Rc<RequestTransmitter> = Rc::new(
-        RequestTransmitter::new()
-    );
-    let mut elevator = Elevator::new(request_transmitter.clone());
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(2);
+        }
+    };
 
-    let ref peer_rx = request_transmitter.peer_receiver;
-    let ref request_rx = request_transmitter.bcast_receiver;
+    install_signal_handlers();
+    let shutdown = Shutdown::new();
 
-    let (button_tx, button_rx) = channel::<Button>();
-    thread::spawn(move|| {
-        let io = ElevIo::new().unwrap();
-        let TOP_FLOOR = N_FLOORS-1;
-        loop {
-            for floor in 0..N_FLOORS {
+    // As one of a process pair, wait as the backup until the primary is gone,
+    // then take over and start a new backup.
+    let mut checkpoint = None;
+    let mut heartbeat = None;
+    if config.process_pair {
+        match run_backup(config.supervisor_port, config.supervisor_timeout, &shutdown) {
+            Ok(BackupOutcome::TakeOver(latest)) => checkpoint = latest,
+            Ok(BackupOutcome::PrimaryStopped) | Ok(BackupOutcome::Shutdown) => return,
+            Err(err) => {
+                println!("Could not start as backup. Error: {}", err);
+                process::exit(1);
+            }
+        }
+        abort_on_panic();
+        if let Err(err) = spawn_backup() {
+            println!("Could not spawn a backup, running without one. Error: {}", err);
+        }
+        let heartbeat_interval = config.supervisor_timeout / 5;
+        heartbeat = match spawn_heartbeat(config.supervisor_port, heartbeat_interval, config.supervisor_timeout, shutdown.clone()) {
+            Ok(heartbeat) => Some(heartbeat),
+            Err(err) => {
+                println!("Could not send heartbeats to the backup. Error: {}", err);
+                None
+            }
+        };
+    }
+
+    let metrics_exporter = match spawn_http_exporter(config.metrics_addr, shutdown.clone()) {
+        Ok(handle) => Some(handle),
+        Err(err) => {
+            println!("Metrics endpoint unavailable. Error: {}", err);
+            None
+        }
+    };
+    let metrics_dump = spawn_file_dump(PathBuf::from(&config.metrics_file),
+                                       time::Duration::from_secs(METRICS_DUMP_S),
+                                       shutdown.clone());
+
+    let transmitter_config = config.transmitter();
+    let fault_admin = match (transmitter_config.faults.clone(), config.fault_admin_port) {
+        (Some(injector), Some(port)) => match spawn_admin(port, injector, config.fault_file.clone(), shutdown.clone()) {
+            Ok(handle) => Some(handle),
+            Err(err) => {
+                println!("Fault injection admin port unavailable. Error: {}", err);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let request_transmitter = match RequestTransmitter::with_config(&transmitter_config, shutdown.clone()) {
+        Ok(transmitter) => transmitter,
+        Err(err) => {
+            println!("Network unavailable, running as a single elevator. Error: {}", err);
+            RequestTransmitter::offline(shutdown.clone())
+        }
+    };
+    let mut request_transmitter = request_transmitter;
+
+    let mut events = EventLoop::new();
+    let bcast_rx = request_transmitter.bcast_receiver.take().unwrap();
+    events.forward(bcast_rx, |(message, remote_ip)| Event::Message(message, remote_ip), shutdown.clone());
+    let peer_rx = request_transmitter.peer_receiver.take().unwrap();
+    events.forward(peer_rx, Event::Peer, shutdown.clone());
+    if let Some(transfer_rx) = request_transmitter.transfer_receiver.take() {
+        events.forward(transfer_rx, |(transfer, remote_ip)| Event::Transfer(transfer, remote_ip), shutdown.clone());
+    }
+    if let Some(failure_rx) = request_transmitter.transfer_failures.take() {
+        events.forward(failure_rx, |(transfer, remote_ip)| Event::TransferFailed(transfer, remote_ip), shutdown.clone());
+    }
+
+    let request_transmitter: Rc<RequestTransmitter> = Rc::new(request_transmitter);
+
+    let node_id = match config.node_id {
+        Some(_) => peer_id(&config.node_id).unwrap(),
+        None => get_localip().unwrap().to_string(),
+    };
+    if let Err(err) = event_log::open(&config.event_log, node_id) {
+        println!("Event log unavailable. Error: {}", err);
+    }
+
+    // A backup has the checkpoint its primary sent last. Otherwise this may be a
+    // restart, and the previous run left its checkpoint in the file.
+    if checkpoint.is_none() {
+        checkpoint = match checkpoint::load(&config.checkpoint_file) {
+            Ok(checkpoint) => checkpoint,
+            Err(err) => {
+                println!("Ignoring checkpoint {}. Error: {}", config.checkpoint_file, err);
+                None
+            }
+        };
+    }
+    let checkpoint = checkpoint.and_then(|checkpoint| {
+        if checkpoint.age() > config.checkpoint_max_age {
+            println!("Ignoring checkpoint from {:?} ago", checkpoint.age());
+            return None;
+        }
+        Some(checkpoint)
+    });
+
+    let sim = match config.backend {
+        Backend::Sim => Some(SimIo::new()),
+        Backend::Hardware => None,
+    };
+    let sim_car = sim.clone().map(|sim| {
+        spawn_sim_passengers(sim.clone(), config.poll_period);
+        let start_floor = checkpoint.as_ref().map_or(0, |checkpoint| checkpoint.floor);
+        let (floors, floor_travel, shutdown) = (config.floors, config.sim_floor_travel, shutdown.clone());
+        thread::spawn(move|| run_car(sim, floors, start_floor, floor_travel, shutdown))
+    });
+
+    // With a checkpoint the trip goes on from where it was, without first
+    // driving down to a floor.
+    let mut elevator = match (&sim, &checkpoint) {
+        (Some(sim), Some(checkpoint)) => {
+            Elevator::attached(ElevIo::attach_simulated(sim.clone()), request_transmitter.clone(), checkpoint.floor)
+        },
+        (Some(sim), None) => {
+            let io = ElevIo::simulated(sim.clone()).expect("Init of simulated io failed");
+            Elevator::with_io(io, request_transmitter.clone())
+        },
+        (None, Some(checkpoint)) => {
+            let io = ElevIo::attach().expect("Init of HW failed");
+            Elevator::attached(io, request_transmitter.clone(), checkpoint.floor)
+        },
+        (None, None) => Elevator::new(request_transmitter.clone()),
+    };
+    elevator.set_timeouts(config.door_open, config.stuck_timeout);
+    if let Some(checkpoint) = checkpoint {
+        println!("Resuming from checkpoint: {:?} at floor {}", checkpoint.state, checkpoint.floor);
+        elevator.restore(checkpoint);
+    }
+    let mut checkpointer = Checkpointer::new(PathBuf::from(&config.checkpoint_file), config.checkpoint_interval);
+
+    let polling_tx = events.sender();
+    let polling_shutdown = shutdown.clone();
+    let (floors, poll_period) = (config.floors, config.poll_period);
+    let polling_sim = sim.clone();
+    let polling_thread = thread::spawn(move|| {
+        // The elevator has set up the hardware already.
+        let io = match polling_sim {
+            Some(sim) => ElevIo::attach_simulated(sim),
+            None => ElevIo::attach().unwrap(),
+        };
+        let top_floor = floors-1;
+        // Which buttons were held at the last poll, so a press is only counted once.
+        let mut held = vec![vec![false; N_FLOORS]; 3];
+        let mut obstructed = false;
+        while !polling_shutdown.is_triggered() {
+            // The floor is sent every poll, an idle elevator acts on new orders
+            // when it is told where it is.
+            let _ = polling_tx.send(Event::Floor(io.get_floor_signal().unwrap()));
+
+            if let Signal::High = io.get_stop_signal().expect("Get StopSignal failed") {
+                let _ = polling_tx.send(Event::Stop);
+            }
+
+            let obstruction = io.get_obstr_signal().unwrap();
+            let now_obstructed = match obstruction {
+                Signal::High => true,
+                Signal::Low  => false,
+            };
+            if now_obstructed != obstructed {
+                let _ = polling_tx.send(Event::Obstruction(obstruction));
+            }
+            obstructed = now_obstructed;
+
+            for floor in 0..floors {
                 // Buttons at current floor
                 let button_call_up = Button::CallUp(Floor::At(floor));
                 let button_call_down = Button::CallDown(Floor::At(floor));
                 let button_internal = Button::Internal(Floor::At(floor));
 
-                if floor != TOP_FLOOR {
-                    if let Signal::High = io.get_button_signal(button_call_up).unwrap() {
-                        button_tx.send(button_call_up).unwrap();
+                for &(button, request_type) in [(button_call_up, RequestType::CallUp),
+                                                (button_call_down, RequestType::CallDown),
+                                                (button_internal, RequestType::Internal)].iter() {
+                    if (request_type == RequestType::CallUp && floor == top_floor)
+                        || (request_type == RequestType::CallDown && floor == 0) {
+                        continue;
                     }
-                }
 
-                if floor != 0 {
-                    if let Signal::High = io.get_button_signal(button_call_down).unwrap() {
-                        button_tx.send(button_call_down).unwrap();
+                    let pressed = match io.get_button_signal(button).unwrap() {
+                        Signal::High => true,
+                        Signal::Low  => false,
+                    };
+                    if pressed && !held[request_type as usize][floor] {
+                        metrics::record_button_press(request_type, floor);
                     }
-                }
+                    held[request_type as usize][floor] = pressed;
 
-                if let Signal::High = io.get_button_signal(button_internal).unwrap() {
-                    button_tx.send(button_internal).unwrap();
+                    if pressed {
+                        let _ = polling_tx.send(Event::Button(button));
+                    }
                 }
             }
-            thread::sleep(time::Duration::from_millis(20));
+            thread::sleep(poll_period);
         }
     });
     println!("creating");
     thread::sleep(time::Duration::from_secs(1));
     println!("ready!");
 
-    loop {
+    elevator.timers.deliver_to(events.sender());
+    // Positions and requests are repeated every tick, for peers that missed them.
+    elevator.timers.repeat(TimerName::Broadcast, config.broadcast_tick);
+    elevator.timers.repeat(TimerName::Starvation, time::Duration::from_secs(STARVATION_CHECK_S));
+    let mut settings = config.shared_settings();
+    adopt_settings(&mut elevator, settings, &SharedSettings { anti_entropy: false, reliable_delivery: false, ..settings });
+    // The collector keeps what it is shipped, and does not ship its own log to itself.
+    let mut log_shipper = match config.log_collector {
+        Some(collector) if collector != request_transmitter.local_ip() => {
+            elevator.timers.repeat(TimerName::ShipLog, time::Duration::from_secs(LOG_SHIP_S));
+            Some(LogShipper::new(&config.event_log))
+        },
+        _ => None,
+    };
 
-        if let Floor::At(_) = elevator.io.get_floor_signal().unwrap() {
-            elevator.event_at_floor();
-        } else {
-            elevator.event_running();
-        }
+    while !shutdown.is_triggered() {
 
-        if let Signal::High = elevator.io.get_stop_signal()
-                                     .expect("Get StopSignal failed") {
-            elevator.io.set_motor_dir(MotorDir::Stop)
-                   .expect("Set MotorDir failed");
-            return;
+        if let Err(err) = checkpointer.update(elevator.checkpoint()) {
+            println!("Checkpoint failed. Error: {}", err);
         }
 
-        if elevator.door_timer.timeout() {
-            elevator.event_doors_should_close();
-        }
+        // Waking up now and then lets a signal end the loop.
+        let event = match events.next(config.poll_period) {
+            Some(event) => event,
+            None => continue,
+        };
 
-        if elevator.stuck_timer.timeout() {
-            elevator.event_stuck();
-            panic!("Elevator is stuck.");
-        }
-
-        let (timer_tx, timer_rx) = channel::<()>();
-
-        let timer = timer::Timer::new();
-        let timer_guard = timer.schedule_repeating(chrono::Duration::milliseconds(150), move|| {
-            timer_tx.send(());
-        });
-        timer_guard.ignore();
-
-        select! {
-            update_msg = peer_rx.recv() => {
-                let update = update_msg.unwrap();
-                elevator.request_handler.handle_peer_update(update);
+        match event {
+            Event::Floor(Floor::At(_)) => elevator.event_at_floor(),
+            Event::Floor(Floor::Between) => elevator.event_running(),
+            Event::Stop => {
+                elevator.event_stop_button();
+                break;
             },
-            bcast_msg = request_rx.recv() => {
-                let (message, remote_ip) = bcast_msg.unwrap();
-
-                match message {
-                    BroadcastMessage::RequestMessage(request) => {
-                        elevator.event_request_message(&request, remote_ip);
+            // Nothing keeps the doors open on an obstruction yet.
+            Event::Obstruction(_) => {},
+            Event::Peer(update) => {
+                // A joining peer may run other protocols, and adopts the ones this node runs.
+                let local_ip = request_transmitter.local_ip().to_string();
+                for ip in update.new.iter().map(|peer| peer_ip(peer)).filter(|ip| *ip != local_ip) {
+                    request_transmitter.transfer(&ip, Transfer::Settings(settings));
+                }
+                elevator.event_peer_update(update);
+            },
+            Event::Message(BroadcastMessage::RequestMessage(request), remote_ip) => {
+                elevator.event_request_message(&request, remote_ip);
+            },
+            Event::Message(BroadcastMessage::Position(floor), remote_ip) => {
+                elevator.event_position_message(remote_ip, floor);
+            },
+            Event::Message(BroadcastMessage::Reliable { id, request }, remote_ip) => {
+                elevator.event_request_message(&request, remote_ip.clone());
+                elevator.request_handler.acknowledge_delivery(id, remote_ip);
+            },
+            Event::Message(BroadcastMessage::Ack { id, to }, remote_ip) => {
+                elevator.request_handler.handle_ack(id, &to, remote_ip);
+            },
+            Event::Transfer(Transfer::Requests(requests), remote_ip) => {
+                for request in requests {
+                    elevator.event_request_message(&request, remote_ip.clone());
+                }
+            },
+            Event::Transfer(Transfer::EventLog(lines), _) => {
+                if let Err(err) = event_log::append_lines(&config.collected_log, &lines) {
+                    println!("Collecting a shipped event log failed. Error: {}", err);
+                }
+            },
+            Event::Transfer(Transfer::Settings(remote), remote_ip) => {
+                if remote.broadcast_tick_ms != settings.broadcast_tick_ms {
+                    println!("{} broadcasts every {} ms, this node every {} ms", remote_ip, remote.broadcast_tick_ms, settings.broadcast_tick_ms);
+                }
+                let before = settings;
+                if settings.adopt(&remote) {
+                    println!("Running the protocols of {}: {:?}", remote_ip, settings);
+                    adopt_settings(&mut elevator, settings, &before);
+                }
+            },
+            // Sent again with the next batch.
+            Event::TransferFailed(Transfer::EventLog(lines), _) => {
+                if let Some(ref mut shipper) = log_shipper {
+                    shipper.give_back(lines);
+                }
+            },
+            Event::TransferFailed(transfer, _) => {
+                elevator.request_handler.handle_transfer_failure(transfer);
+            },
+            Event::Message(BroadcastMessage::Digest(digest), _) => {
+                elevator.request_handler.handle_digest(digest);
+            },
+            Event::Message(BroadcastMessage::Versions(versions), _) => {
+                elevator.request_handler.handle_versions(&versions);
+            },
+            Event::Message(BroadcastMessage::CabRequests { owner, requests }, _) => {
+                elevator.event_cab_requests(owner, &requests);
+            },
+            Event::Timer(expiry) => {
+                // Cancelled or restarted since.
+                if !elevator.timers.expired(expiry) {
+                    continue;
+                }
+                match expiry.name {
+                    TimerName::Door => elevator.event_doors_should_close(),
+                    TimerName::Stuck => elevator.event_stuck(),
+                    TimerName::Broadcast => {
+                        if elevator.in_service() {
+                            request_transmitter.announce_position(elevator.current_floor);
+                        }
+                        elevator.request_handler.sync_requests();
+                        if let Some((ref checkpoint_tx, _)) = heartbeat {
+                            let _ = checkpoint_tx.send(elevator.checkpoint());
+                        }
+                        if let Some(injected) = transmitter_config.faults.as_ref().and_then(|faults| faults.take_report()) {
+                            event_log::record(LogEvent::FaultsInjected(injected), None, None);
+                        }
                     },
-                    BroadcastMessage::Position(floor) => {
-                        elevator.event_position_message(remote_ip, floor);
+                    TimerName::Starvation => elevator.event_starvation_check(),
+                    TimerName::Retransmit => elevator.request_handler.retransmit_unacknowledged(),
+                    TimerName::ShipLog => {
+                        if let (Some(shipper), Some(collector)) = (log_shipper.as_mut(), config.log_collector) {
+                            match shipper.take_lines() {
+                                Ok(ref lines) if lines.is_empty() => {},
+                                Ok(lines) => {
+                                    let collector = collector.to_string();
+                                    if !request_transmitter.transfer(&collector, Transfer::EventLog(lines.clone())) {
+                                        shipper.give_back(lines);
+                                    }
+                                },
+                                Err(err) => println!("Reading the event log to ship failed. Error: {}", err),
+                            }
+                        }
                     },
                 }
             },
-            _ = timer_rx.recv() => {
-                request_transmitter.bcast_sender.send(BroadcastMessage::Position(elevator.current_floor));
-                elevator.request_handler.announce_all_requests();
-            },
-            button_msg = button_rx.recv() => {
-                let button = button_msg.unwrap();
-                elevator.event_new_floor_order(button);
-            }
+            Event::Button(button) => elevator.event_new_floor_order(button),
         }
     }
 
+    println!("shutting down");
+    // The loop may have ended between two checkpoints, and the last one written
+    // would have a restart resume from before the final events.
+    if let Err(err) = checkpointer.flush(elevator.checkpoint()) {
+        println!("Final checkpoint failed. Error: {}", err);
+    }
+    // Leaving the main loop on the stop button does not trigger the shutdown by itself.
+    shutdown.trigger();
+    elevator.event_shutdown();
+    event_log::close();
+    polling_thread.join().unwrap();
+    events.join();
+    if let Some(handle) = metrics_exporter {
+        handle.join().unwrap();
+    }
+    metrics_dump.join().unwrap();
+    if let Some(handle) = fault_admin {
+        handle.join().unwrap();
+    }
+    if let Some((_, handle)) = heartbeat {
+        handle.join().unwrap();
+    }
+    if let Some(handle) = sim_car {
+        handle.join().unwrap();
+    }
+}
+
+// Turns on what is on in settings and was not before.
+fn adopt_settings(elevator: &mut Elevator, settings: SharedSettings, before: &SharedSettings) {
+    if settings.anti_entropy && !before.anti_entropy {
+        elevator.request_handler.enable_anti_entropy();
+    }
+    if settings.reliable_delivery && !before.reliable_delivery {
+        elevator.request_handler.enable_reliable_delivery(ReliableConfig::default());
+        elevator.timers.repeat(TimerName::Retransmit, time::Duration::from_millis(RETRANSMIT_CHECK_MS));
+    }
 }
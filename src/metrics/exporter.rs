@@ -0,0 +1,126 @@
+use std::io;
+use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use metrics::metrics;
+use shutdown::shutdown::Shutdown;
+
+const POLL_MS: u64 = 100;
+
+// Serves the metrics as plain text to anyone connecting, e.g. a Prometheus scraper.
+// The request itself is not parsed, every path gets the same page.
+pub struct HttpExporter {
+    listener: TcpListener,
+}
+
+impl HttpExporter {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        // Polling accept lets the thread notice a shutdown.
+        listener.set_nonblocking(true)?;
+        Ok(HttpExporter { listener: listener })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn spawn(self, shutdown: Shutdown) -> JoinHandle<()> {
+        let listener = self.listener;
+        thread::spawn(move || {
+            while !shutdown.is_triggered() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(err) = respond(stream) {
+                            println!("Metrics request failed. Error: {}", err);
+                        }
+                    },
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(POLL_MS));
+                    },
+                    Err(err) => println!("Accept failed for metrics exporter. Error: {}", err),
+                }
+            }
+        })
+    }
+}
+
+pub fn spawn_http_exporter(addr: SocketAddr, shutdown: Shutdown) -> io::Result<JoinHandle<()>> {
+    let exporter = HttpExporter::bind(addr)?;
+    Ok(exporter.spawn(shutdown))
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_millis(POLL_MS)))?;
+
+    let mut buf = [0u8; 1024];
+    let _ = stream.read(&mut buf);
+
+    let body = metrics::snapshot().to_prometheus();
+    write!(stream, "HTTP/1.0 200 OK\r\n\
+                         Content-Type: text/plain; version=0.0.4\r\n\
+                         Content-Length: {}\r\n\
+                         Connection: close\r\n\r\n", body.len())?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()
+}
+
+// Writes the metrics to path every interval. The file is replaced in one rename,
+// so a reader never sees half a dump.
+pub fn spawn_file_dump(path: PathBuf, interval: Duration, shutdown: Shutdown) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_dump = Instant::now();
+        while !shutdown.is_triggered() {
+            thread::sleep(Duration::from_millis(POLL_MS));
+            if last_dump.elapsed() < interval {
+                continue;
+            }
+            last_dump = Instant::now();
+            if let Err(err) = dump(&path) {
+                println!("Metrics dump to {} failed. Error: {}", path.display(), err);
+            }
+        }
+        let _ = dump(&path);
+    })
+}
+
+fn dump(path: &PathBuf) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(metrics::snapshot().to_prometheus().as_bytes())?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use shutdown::shutdown::Shutdown;
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let shutdown = Shutdown::new();
+        let addr = "127.0.0.1:9105".parse().unwrap();
+        let exporter = spawn_http_exporter(addr, shutdown.clone()).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("elevator_requests_served_total"));
+
+        shutdown.trigger();
+        exporter.join().unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use elevator_driver::elev_io::N_FLOORS;
+use request_handler::request::RequestType;
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+}
+
+// Running count and sum of a duration, exported as a Prometheus summary.
+#[derive(Debug, Default, Clone)]
+pub struct Timing {
+    count: u64,
+    sum: Duration,
+}
+
+impl Timing {
+    fn observe(&mut self, duration: Duration) {
+        self.count += 1;
+        self.sum += duration;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    button_presses: Vec<Vec<u64>>,
+    requests_served: u64,
+    wait_time: Timing,
+    travel_time: Timing,
+    door_cycles: u64,
+    motor_starts: u64,
+    stuck_events: u64,
+    starvation_alarms: u64,
+    peers_joined: u64,
+    peers_lost: u64,
+    messages_sent: u64,
+    messages_received: u64,
+    messages_dropped: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            button_presses: vec![vec![0; N_FLOORS]; 3],
+            requests_served: 0,
+            wait_time: Timing::default(),
+            travel_time: Timing::default(),
+            door_cycles: 0,
+            motor_starts: 0,
+            stuck_events: 0,
+            starvation_alarms: 0,
+            peers_joined: 0,
+            peers_lost: 0,
+            messages_sent: 0,
+            messages_received: 0,
+            messages_dropped: 0,
+        }
+    }
+
+    pub fn button_presses(&self, request_type: RequestType, floor: usize) -> u64 {
+        self.button_presses[request_type as usize][floor]
+    }
+
+    pub fn requests_served(&self) -> u64 {
+        self.requests_served
+    }
+
+    pub fn messages_dropped(&self) -> u64 {
+        self.messages_dropped
+    }
+
+    // Renders every metric in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP elevator_button_presses_total Buttons pressed, by floor and type.\n");
+        out.push_str("# TYPE elevator_button_presses_total counter\n");
+        for &(request_type, name) in [(RequestType::CallUp, "call_up"),
+                                      (RequestType::CallDown, "call_down"),
+                                      (RequestType::Internal, "internal")].iter() {
+            for floor in 0..N_FLOORS {
+                writeln!(out, "elevator_button_presses_total{{type=\"{}\",floor=\"{}\"}} {}",
+                         name, floor, self.button_presses[request_type as usize][floor]).unwrap();
+            }
+        }
+
+        counter(&mut out, "elevator_requests_served_total", "Requests served by this elevator.", self.requests_served);
+        summary(&mut out, "elevator_wait_seconds", "Time from a press until the request was served.", &self.wait_time);
+        summary(&mut out, "elevator_travel_seconds", "Time from a motor start until the doors opened.", &self.travel_time);
+        counter(&mut out, "elevator_door_cycles_total", "Times the doors have opened.", self.door_cycles);
+        counter(&mut out, "elevator_motor_starts_total", "Times the motor has started from standstill.", self.motor_starts);
+        counter(&mut out, "elevator_stuck_events_total", "Times the elevator got stuck between floors.", self.stuck_events);
+        counter(&mut out, "elevator_starvation_alarms_total", "Requests that have waited past the starvation threshold.", self.starvation_alarms);
+        counter(&mut out, "elevator_peers_joined_total", "Peers that have joined.", self.peers_joined);
+        counter(&mut out, "elevator_peers_lost_total", "Peers that have been lost.", self.peers_lost);
+        counter(&mut out, "elevator_messages_sent_total", "Broadcast messages sent.", self.messages_sent);
+        counter(&mut out, "elevator_messages_received_total", "Broadcast messages received.", self.messages_received);
+        counter(&mut out, "elevator_messages_dropped_total", "Broadcast messages dropped by the receiver.", self.messages_dropped);
+
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} counter", name).unwrap();
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+fn summary(out: &mut String, name: &str, help: &str, timing: &Timing) {
+    let sum = timing.sum.as_secs() as f64 + timing.sum.subsec_nanos() as f64 / 1e9;
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} summary", name).unwrap();
+    writeln!(out, "{}_sum {:.3}", name, sum).unwrap();
+    writeln!(out, "{}_count {}", name, timing.count).unwrap();
+}
+
+// The process wide metrics are updated through these functions, from any thread.
+
+pub fn snapshot() -> Metrics {
+    METRICS.lock().unwrap().clone()
+}
+
+pub fn record_button_press(request_type: RequestType, floor: usize) {
+    METRICS.lock().unwrap().button_presses[request_type as usize][floor] += 1;
+}
+
+pub fn record_served(wait: Duration) {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.requests_served += 1;
+    metrics.wait_time.observe(wait);
+}
+
+pub fn record_travel(travel: Duration) {
+    METRICS.lock().unwrap().travel_time.observe(travel);
+}
+
+pub fn record_door_cycle() {
+    METRICS.lock().unwrap().door_cycles += 1;
+}
+
+pub fn record_motor_start() {
+    METRICS.lock().unwrap().motor_starts += 1;
+}
+
+pub fn record_stuck() {
+    METRICS.lock().unwrap().stuck_events += 1;
+}
+
+pub fn record_starvation() {
+    METRICS.lock().unwrap().starvation_alarms += 1;
+}
+
+pub fn record_peers(joined: usize, lost: usize) {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.peers_joined += joined as u64;
+    metrics.peers_lost += lost as u64;
+}
+
+pub fn record_message_sent() {
+    METRICS.lock().unwrap().messages_sent += 1;
+}
+
+pub fn record_message_received() {
+    METRICS.lock().unwrap().messages_received += 1;
+}
+
+pub fn record_message_dropped() {
+    METRICS.lock().unwrap().messages_dropped += 1;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use request_handler::request::RequestType;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let mut metrics = Metrics::new();
+        metrics.button_presses[RequestType::CallUp as usize][1] += 2;
+        metrics.requests_served += 1;
+        metrics.wait_time.observe(Duration::from_millis(1500));
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("elevator_button_presses_total{type=\"call_up\",floor=\"1\"} 2\n"));
+        assert!(text.contains("elevator_requests_served_total 1\n"));
+        assert!(text.contains("elevator_wait_seconds_sum 1.500\n"));
+        assert!(text.contains("elevator_wait_seconds_count 1\n"));
+        assert!(text.contains("# TYPE elevator_messages_dropped_total counter\n"));
+    }
+}
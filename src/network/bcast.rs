@@ -1,90 +1,126 @@
 
 use std::io;
-use std::net::UdpSocket;
 use std::str::from_utf8;
 use std::sync::mpsc;
+use std::time::Duration;
 
 extern crate serde;
 extern crate serde_json;
 extern crate net2;
 
+use network::socket::NetMode;
+use network::transport::{Transport, UdpTransport};
+use shutdown::shutdown::Shutdown;
+use metrics::metrics;
+
+const POLL_MS: u64 = 100;
+
 pub struct BcastTransmitter {
-    conn: UdpSocket,
+    conn: Box<dyn Transport>,
 }
 
 impl BcastTransmitter {
     pub fn new(port: u16) -> io::Result<Self> {
-        let conn = {
-            let udp = try!(net2::UdpBuilder::new_v4());
-            try!(udp.reuse_address(true));
-            let socket = try!(udp.bind("0.0.0.0:0"));
-            try!(socket.set_broadcast(true));
-            try!(socket.connect(("255.255.255.255", port)));
-            socket
-        };
-        Ok(BcastTransmitter {
+        BcastTransmitter::with_mode(port, &NetMode::Broadcast)
+    }
+
+    pub fn with_mode(port: u16, mode: &NetMode) -> io::Result<Self> {
+        let conn = UdpTransport::transmitter(port, mode)?;
+        Ok(BcastTransmitter::with_transport(Box::new(conn)))
+    }
+
+    pub fn with_transport(conn: Box<dyn Transport>) -> Self {
+        BcastTransmitter {
             conn: conn,
-        })
+        }
     }
 
-    pub fn transmit<'a, T>(&self, data: &'a T) -> io::Result<()>
+    pub fn transmit<T>(&self, data: &T) -> io::Result<()>
         where T: serde::ser::Serialize,
     {
         let serialized = serde_json::to_string(&data).unwrap();
-        try!(self.conn.send(serialized.as_bytes()));
+        self.conn.send(serialized.as_bytes())?;
+        metrics::record_message_sent();
         Ok(())
     }
 
-    pub fn run<T>(self, bcast_rx: mpsc::Receiver<T>) -> !
+    pub fn run<T>(self, bcast_rx: mpsc::Receiver<T>, shutdown: Shutdown)
         where T: serde::ser::Serialize,
     {
-        loop {
-            let msg = bcast_rx.recv().unwrap();
-            self.transmit(&msg);
+        while !shutdown.is_triggered() {
+            match bcast_rx.recv_timeout(Duration::from_millis(POLL_MS)) {
+                Ok(msg) => {
+                    if let Err(err) = self.transmit(&msg) {
+                        println!("Transmit failed for BcastTransmitter. Error: {}", err);
+                    }
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
         }
     }
 }
 
 pub struct BcastReceiver {
-    conn: UdpSocket,
+    conn: Box<dyn Transport>,
 }
 
 impl BcastReceiver {
     pub fn new(port: u16) -> io::Result<Self> {
-        let conn = {
-            let udp = try!(net2::UdpBuilder::new_v4());
-            try!(udp.reuse_address(true));
-            let socket = try!(udp.bind(("255.255.255.255", port)));
-            try!(socket.set_broadcast(true));
-            socket
-        };
-        Ok(BcastReceiver {
+        BcastReceiver::with_mode(port, &NetMode::Broadcast)
+    }
+
+    pub fn with_mode(port: u16, mode: &NetMode) -> io::Result<Self> {
+        let conn = UdpTransport::receiver(port, mode)?;
+        Ok(BcastReceiver::with_transport(Box::new(conn)))
+    }
+
+    pub fn with_transport(conn: Box<dyn Transport>) -> Self {
+        BcastReceiver {
             conn: conn,
-        })
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.conn.set_read_timeout(timeout)
     }
 
     pub fn receive<T>(&self) -> io::Result<(T, String)>
-        where T: serde::de::Deserialize,
+        where T: serde::de::DeserializeOwned,
     {
         let mut buf = [0u8; 1024];
-        let (amt, addr) = try!(self.conn.recv_from(&mut buf));
-        let msg = from_utf8(&buf[..amt]).unwrap();
-        let json = serde_json::from_str(&msg).unwrap();
-        Ok((json, addr.ip().to_string()))
+        let (amt, addr) = self.conn.recv(&mut buf)?;
+        let msg = from_utf8(&buf[..amt]).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let json = serde_json::from_str(msg).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok((json, addr.to_string()))
     }
 
-    pub fn run<T>(self, bcast_tx: mpsc::Sender<(T, String)>) -> !
-        where T: serde::de::Deserialize,
+    pub fn run<T>(self, bcast_tx: mpsc::Sender<(T, String)>, shutdown: Shutdown)
+        where T: serde::de::DeserializeOwned,
     {
-        loop {
+        // The timeout lets the loop notice a shutdown while nobody is talking.
+        self.conn.set_read_timeout(Some(Duration::from_millis(POLL_MS))).unwrap();
+
+        while !shutdown.is_triggered() {
             let (msg, addr) = match self.receive() {
                 Ok(msg) => msg,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                             || err.kind() == io::ErrorKind::TimedOut => continue,
+                Err(ref err) if err.kind() == io::ErrorKind::InvalidData => {
+                    // Garbled or foreign packets on our port are dropped.
+                    metrics::record_message_dropped();
+                    continue;
+                },
                 Err(err) => {
                     println!("Recv failed for BcastReceiver. Error: {}", err);
                     continue;
                 }
             };
-            bcast_tx.send((msg, addr)).unwrap();
+            metrics::record_message_received();
+            if bcast_tx.send((msg, addr)).is_err() {
+                metrics::record_message_dropped();
+                return;
+            }
         }
     }
 }
@@ -94,10 +130,11 @@ impl BcastReceiver {
 mod tests {
     use super::*;
     use std::thread;
-    use std::time::Duration;
     use std::net::IpAddr;
 
-    use localip::get_localip;
+    use network::localip::get_localip;
+    use network::socket::{NetMode, MulticastConfig};
+    use network::transport::LoopbackNetwork;
 
     // Custom Type
     #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -107,49 +144,80 @@ mod tests {
         Float(f32),
     }
 
+    fn loopback_pair(port: u16) -> (BcastTransmitter, BcastReceiver) {
+        let network = LoopbackNetwork::new();
+        let address = "10.0.0.1".parse().unwrap();
+        let receiver = BcastReceiver::with_transport(Box::new(network.receiver(port, address)));
+        let transmitter = BcastTransmitter::with_transport(Box::new(network.transmitter(port, address)));
+        (transmitter, receiver)
+    }
+
     #[test]
     fn transmitter_works() {
-        let port = 7000;
-        let transmitter = BcastTransmitter::new(port).unwrap();
+        let (transmitter, _receiver) = loopback_pair(7000);
         let msg = "Test String".to_string();
-        assert_eq!(transmitter.transmit(&msg).is_ok(), true);
+        assert!(transmitter.transmit(&msg).is_ok());
     }
 
     #[test]
     fn transmit_localip_to_reciever() {
-        let port = 8000;
         let num_transfers = 10;
-        let localip = get_localip().unwrap();
+        let localip = get_localip().unwrap_or("127.0.0.1".parse().unwrap());
+        let (transmitter, receiver) = loopback_pair(8000);
         thread::spawn(move || {
-            let transmitter = BcastTransmitter::new(port).unwrap();
             for _ in 0..num_transfers {
                 thread::sleep(Duration::new(0, 1_000_000));
                 transmitter.transmit(&localip).unwrap();
             }
         });
-        let receiver = BcastReceiver::new(port).unwrap();
         for _ in 0..num_transfers {
-            assert_eq!(receiver.receive::<IpAddr>().unwrap(), localip);
+            let (ip, from) = receiver.receive::<IpAddr>().unwrap();
+            assert_eq!(ip, localip);
+            assert_eq!(from, "10.0.0.1");
         }
     }
 
     #[test]
     fn transmit_customtype_to_receiver() {
-        let port = 9999;
         let values = vec![Values::Hello, Values::Integer(4), Values::Float(-3.3)];
+        let (transmitter, receiver) = loopback_pair(9999);
         {
             let values = values.clone();
             thread::spawn(move || {
-                let transmitter = BcastTransmitter::new(port).unwrap();
                 for value in &values {
                     thread::sleep(Duration::new(0, 1_000_000));
                     transmitter.transmit(value).unwrap();
                 }
             });
         }
-        let receiver = BcastReceiver::new(port).unwrap();
         for value in values {
-            assert_eq!(receiver.receive::<Values>().unwrap(), value);
+            assert_eq!(receiver.receive::<Values>().unwrap().0, value);
+        }
+    }
+
+    #[test]
+    fn receive_times_out_when_nobody_talks() {
+        let (_transmitter, receiver) = loopback_pair(9997);
+        receiver.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let err = receiver.receive::<Values>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn transmit_multicast_to_receiver() {
+        let port = 9998;
+        let mode = NetMode::Multicast(MulticastConfig::new("239.255.41.45".parse().unwrap()));
+        let receiver = BcastReceiver::with_mode(port, &mode).unwrap();
+        thread::spawn(move || {
+            let transmitter = BcastTransmitter::with_mode(port, &mode).unwrap();
+            for i in 0..10 {
+                thread::sleep(Duration::new(0, 1_000_000));
+                transmitter.transmit(&Values::Integer(i)).unwrap();
+            }
+        });
+        for i in 0..10 {
+            let (value, _) = receiver.receive::<Values>().unwrap();
+            assert_eq!(value, Values::Integer(i));
         }
     }
 
@@ -0,0 +1,482 @@
+use std::io;
+use std::io::Read;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::File;
+use std::net::{UdpSocket, IpAddr, SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::str::from_utf8;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng, XorShiftRng};
+use serde_json;
+
+use network::transport::Transport;
+use shutdown::shutdown::Shutdown;
+
+const POLL_MS: u64 = 100;
+// A reordered datagram is held back this much longer than the others, so the
+// ones after it overtake it.
+const REORDER_MS: u64 = 50;
+// At most this many bytes of a corrupted datagram are changed.
+const CORRUPTED_BYTES: usize = 3;
+
+// What goes wrong with the datagrams a node receives. The probabilities are
+// drawn for every datagram.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FaultPlan {
+    #[serde(default)]
+    pub loss: f64,
+    #[serde(default)]
+    pub duplication: f64,
+    #[serde(default)]
+    pub reorder: f64,
+    // A corrupted datagram has a few of its bytes changed.
+    #[serde(default)]
+    pub corruption: f64,
+    #[serde(default)]
+    pub delay_min_ms: u64,
+    #[serde(default)]
+    pub delay_max_ms: u64,
+    // Datagrams from these addresses are dropped. Set on one side only, the
+    // partition is one way.
+    #[serde(default)]
+    pub drop_from: Vec<IpAddr>,
+}
+
+impl FaultPlan {
+    pub fn load(path: &str) -> io::Result<FaultPlan> {
+        let mut text = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut text))?;
+        let plan: FaultPlan = serde_json::from_str(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        plan.validate().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(plan)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for &(name, p) in &[("loss", self.loss), ("duplication", self.duplication), ("reorder", self.reorder), ("corruption", self.corruption)] {
+            if !(0.0..=1.0).contains(&p) {
+                return Err(format!("{} must be between 0 and 1, not {}", name, p));
+            }
+        }
+        if self.delay_min_ms > self.delay_max_ms {
+            return Err("delay_min_ms must not be more than delay_max_ms".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct FaultStats {
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub delayed: u64,
+    #[serde(default)]
+    pub corrupted: u64,
+}
+
+impl FaultStats {
+    fn since(&self, earlier: &FaultStats) -> FaultStats {
+        FaultStats {
+            dropped: self.dropped - earlier.dropped,
+            duplicated: self.duplicated - earlier.duplicated,
+            reordered: self.reordered - earlier.reordered,
+            delayed: self.delayed - earlier.delayed,
+            corrupted: self.corrupted - earlier.corrupted,
+        }
+    }
+}
+
+struct Faults {
+    plan: FaultPlan,
+    stats: FaultStats,
+    // The stats when take_report was called last.
+    reported: FaultStats,
+    rng: XorShiftRng,
+}
+
+// The faults of one node, shared by all of its wrapped transports and the
+// admin socket, so a changed plan applies everywhere at once.
+#[derive(Clone)]
+pub struct FaultInjector {
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl fmt::Debug for FaultInjector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FaultInjector({:?})", self.plan())
+    }
+}
+
+impl FaultInjector {
+    pub fn new(plan: FaultPlan) -> Self {
+        FaultInjector {
+            faults: Arc::new(Mutex::new(Faults {
+                plan: plan,
+                stats: FaultStats::default(),
+                reported: FaultStats::default(),
+                rng: rand::weak_rng(),
+            })),
+        }
+    }
+
+    pub fn plan(&self) -> FaultPlan {
+        self.faults.lock().unwrap().plan.clone()
+    }
+
+    pub fn set_plan(&self, plan: FaultPlan) {
+        println!("Fault injection plan is now {:?}", plan);
+        self.faults.lock().unwrap().plan = plan;
+    }
+
+    pub fn stats(&self) -> FaultStats {
+        self.faults.lock().unwrap().stats
+    }
+
+    // The faults injected since the last report, None if there were none. The
+    // injector runs on the receiving threads, so the thread keeping the event
+    // log takes the reports from here.
+    pub fn take_report(&self) -> Option<FaultStats> {
+        let mut faults = self.faults.lock().unwrap();
+        let report = faults.stats.since(&faults.reported);
+        faults.reported = faults.stats;
+        if report == FaultStats::default() {
+            None
+        } else {
+            Some(report)
+        }
+    }
+
+    // The faults are applied to what the transport receives, as that is where
+    // the sender of a datagram is known.
+    pub fn wrap(&self, inner: Box<dyn Transport>) -> Box<dyn Transport> {
+        Box::new(FaultyTransport {
+            inner: inner,
+            injector: self.clone(),
+            timeout: Cell::new(None),
+            held: RefCell::new(Vec::new()),
+        })
+    }
+
+    // How long each copy of a datagram from the address is held back. None
+    // are delivered if it is dropped. A corrupted datagram is changed in place.
+    fn decide(&self, from: IpAddr, data: &mut [u8]) -> Vec<Duration> {
+        let mut guard = self.faults.lock().unwrap();
+        let faults = &mut *guard;
+        if faults.plan.drop_from.contains(&from) {
+            faults.stats.dropped += 1;
+            return Vec::new();
+        }
+        if faults.rng.gen::<f64>() < faults.plan.loss {
+            faults.stats.dropped += 1;
+            return Vec::new();
+        }
+        if !data.is_empty() && faults.rng.gen::<f64>() < faults.plan.corruption {
+            faults.stats.corrupted += 1;
+            for _ in 0..faults.rng.gen_range(1, CORRUPTED_BYTES + 1) {
+                let at = faults.rng.gen_range(0, data.len());
+                data[at] ^= faults.rng.gen_range(1, 256) as u8;
+            }
+        }
+        let mut copies = 1;
+        if faults.rng.gen::<f64>() < faults.plan.duplication {
+            faults.stats.duplicated += 1;
+            copies = 2;
+        }
+        let mut delays = Vec::new();
+        for _ in 0..copies {
+            let mut delay = faults.rng.gen_range(faults.plan.delay_min_ms, faults.plan.delay_max_ms + 1);
+            if faults.rng.gen::<f64>() < faults.plan.reorder {
+                faults.stats.reordered += 1;
+                delay += REORDER_MS;
+            }
+            if delay > 0 {
+                faults.stats.delayed += 1;
+            }
+            delays.push(Duration::from_millis(delay));
+        }
+        delays
+    }
+}
+
+fn until(deadline: Instant, now: Instant) -> Duration {
+    if deadline > now {
+        deadline - now
+    } else {
+        Duration::new(0, 0)
+    }
+}
+
+pub struct FaultyTransport {
+    inner: Box<dyn Transport>,
+    injector: FaultInjector,
+    timeout: Cell<Option<Duration>>,
+    // Datagrams waiting out their delay, with when they are due.
+    held: RefCell<Vec<(Instant, Vec<u8>, IpAddr)>>,
+}
+
+impl FaultyTransport {
+    fn take_due(&self, now: Instant) -> Option<(Vec<u8>, IpAddr)> {
+        let mut held = self.held.borrow_mut();
+        let mut first: Option<usize> = None;
+        for (i, &(due, _, _)) in held.iter().enumerate() {
+            if due <= now && first.map_or(true, |first| due < held[first].0) {
+                first = Some(i);
+            }
+        }
+        first.map(|i| {
+            let (_, data, from) = held.remove(i);
+            (data, from)
+        })
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.held.borrow().iter().map(|&(due, _, _)| due).min()
+    }
+}
+
+impl Transport for FaultyTransport {
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.inner.send(data)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, IpAddr)> {
+        let deadline = self.timeout.get().map(|timeout| Instant::now() + timeout);
+        loop {
+            let now = Instant::now();
+            if let Some((data, from)) = self.take_due(now) {
+                let amt = if data.len() < buf.len() { data.len() } else { buf.len() };
+                buf[..amt].copy_from_slice(&data[..amt]);
+                return Ok((amt, from));
+            }
+
+            // Wait for the next datagram, but no longer than until a held one
+            // is due or the read times out.
+            let mut wait = deadline.map(|deadline| until(deadline, now));
+            if wait == Some(Duration::new(0, 0)) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no datagram"));
+            }
+            if let Some(due) = self.next_due() {
+                let until_due = until(due, now);
+                wait = Some(match wait {
+                    Some(wait) if wait < until_due => wait,
+                    _ => until_due,
+                });
+            }
+            if wait == Some(Duration::new(0, 0)) {
+                continue;
+            }
+            self.inner.set_read_timeout(wait)?;
+
+            match self.inner.recv(buf) {
+                Ok((amt, from)) => {
+                    let received = Instant::now();
+                    for delay in self.injector.decide(from, &mut buf[..amt]) {
+                        self.held.borrow_mut().push((received + delay, buf[..amt].to_vec(), from));
+                    }
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                             || err.kind() == io::ErrorKind::TimedOut => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout.set(timeout);
+        Ok(())
+    }
+}
+
+fn parse_probability(value: Option<&str>) -> Result<f64, String> {
+    match value.map(|value| value.parse::<f64>()) {
+        Some(Ok(p)) => Ok(p),
+        _ => Err("expected a probability".to_string()),
+    }
+}
+
+fn parse_ms(value: Option<&str>) -> Result<u64, String> {
+    match value.map(|value| value.parse::<u64>()) {
+        Some(Ok(ms)) => Ok(ms),
+        _ => Err("expected milliseconds".to_string()),
+    }
+}
+
+fn parse_ip(value: Option<&str>) -> Result<IpAddr, String> {
+    match value.map(|value| value.parse::<IpAddr>()) {
+        Some(Ok(ip)) => Ok(ip),
+        _ => Err("expected an address".to_string()),
+    }
+}
+
+// Runs one admin command, and gives the answer to send back.
+pub fn apply_command(injector: &FaultInjector, command: &str, plan_file: Option<&str>) -> Result<String, String> {
+    let mut words = command.split_whitespace();
+    let mut plan = injector.plan();
+    match words.next() {
+        Some("show") => return Ok(serde_json::to_string(&plan).unwrap()),
+        Some("stats") => return Ok(serde_json::to_string(&injector.stats()).unwrap()),
+        Some("set") => {
+            let json = command.trim()[3..].trim();
+            plan = serde_json::from_str(json).map_err(|err| err.to_string())?;
+        },
+        Some("reload") => {
+            let path = plan_file.ok_or("no fault file was configured".to_string())?;
+            plan = FaultPlan::load(path).map_err(|err| err.to_string())?;
+        },
+        Some("clear") => plan = FaultPlan::default(),
+        Some("loss") => plan.loss = parse_probability(words.next())?,
+        Some("duplication") => plan.duplication = parse_probability(words.next())?,
+        Some("reorder") => plan.reorder = parse_probability(words.next())?,
+        Some("corruption") => plan.corruption = parse_probability(words.next())?,
+        Some("delay") => {
+            plan.delay_min_ms = parse_ms(words.next())?;
+            plan.delay_max_ms = parse_ms(words.next())?;
+        },
+        Some("drop-from") => {
+            let ip = parse_ip(words.next())?;
+            if !plan.drop_from.contains(&ip) {
+                plan.drop_from.push(ip);
+            }
+        },
+        Some("accept-from") => {
+            let ip = parse_ip(words.next())?;
+            plan.drop_from.retain(|&dropped| dropped != ip);
+        },
+        Some("heal") => plan.drop_from.clear(),
+        Some(other) => return Err(format!("unknown command {:?}", other)),
+        None => return Err("empty command".to_string()),
+    }
+    plan.validate()?;
+    injector.set_plan(plan);
+    Ok("ok".to_string())
+}
+
+// Takes admin commands on a UDP port on localhost, one per datagram, and
+// answers each with the result or an error, e.g.
+//     echo "drop-from 10.0.0.2" | nc -u -w1 127.0.0.1 <port>
+pub fn spawn_admin(port: u16, injector: FaultInjector, plan_file: Option<String>, shutdown: Shutdown) -> io::Result<JoinHandle<()>> {
+    let socket = UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port)))?;
+    // The timeout lets the thread notice a shutdown.
+    socket.set_read_timeout(Some(Duration::from_millis(POLL_MS)))?;
+
+    Ok(thread::spawn(move|| {
+        let mut buf = [0u8; 1024];
+        while !shutdown.is_triggered() {
+            let (amt, addr) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                             || err.kind() == io::ErrorKind::TimedOut => continue,
+                Err(err) => {
+                    println!("Recv failed for fault injection admin. Error: {}", err);
+                    continue;
+                }
+            };
+            let answer = match from_utf8(&buf[..amt]) {
+                Ok(command) => apply_command(&injector, command, plan_file.as_deref()),
+                Err(_) => Err("command is not text".to_string()),
+            };
+            let answer = match answer {
+                Ok(answer) => answer + "\n",
+                Err(err) => format!("error: {}\n", err),
+            };
+            let _ = socket.send_to(answer.as_bytes(), addr);
+        }
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::time::Duration;
+    use network::transport::{Transport, LoopbackNetwork};
+
+    #[test]
+    fn drops_duplicates_and_delays_received_datagrams() {
+        let network = LoopbackNetwork::new();
+        let port = 4000;
+        let injector = FaultInjector::new(FaultPlan {
+            duplication: 1.0,
+            drop_from: vec!["10.0.0.2".parse().unwrap()],
+            ..FaultPlan::default()
+        });
+        let receiver = injector.wrap(Box::new(network.receiver(port, "10.0.0.1".parse().unwrap())));
+        receiver.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+
+        network.transmitter(port, "10.0.0.2".parse().unwrap()).send(b"partitioned").unwrap();
+        network.transmitter(port, "10.0.0.3".parse().unwrap()).send(b"twice").unwrap();
+
+        let mut buf = [0u8; 16];
+        for _ in 0..2 {
+            let (amt, from) = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..amt], b"twice");
+            assert_eq!(from.to_string(), "10.0.0.3");
+        }
+        assert_eq!(receiver.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        injector.set_plan(FaultPlan { delay_min_ms: 100, delay_max_ms: 100, ..FaultPlan::default() });
+        network.transmitter(port, "10.0.0.3".parse().unwrap()).send(b"late").unwrap();
+        assert_eq!(receiver.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let (amt, _) = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..amt], b"late");
+
+        let stats = injector.stats();
+        assert_eq!((stats.dropped, stats.duplicated, stats.delayed), (1, 1, 1));
+        assert_eq!(injector.take_report(), Some(stats));
+        assert_eq!(injector.take_report(), None);
+    }
+
+    #[test]
+    fn corrupts_received_datagrams() {
+        let network = LoopbackNetwork::new();
+        let port = 4001;
+        let injector = FaultInjector::new(FaultPlan { corruption: 1.0, ..FaultPlan::default() });
+        let receiver = injector.wrap(Box::new(network.receiver(port, "10.0.0.1".parse().unwrap())));
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let sent = b"{\"floor\": 3}";
+        network.transmitter(port, "10.0.0.3".parse().unwrap()).send(sent).unwrap();
+        let mut buf = [0u8; 32];
+        let (amt, _) = receiver.recv(&mut buf).unwrap();
+        assert_eq!(amt, sent.len());
+        assert!(buf[..amt] != sent[..]);
+        assert_eq!(injector.take_report(), Some(FaultStats { corrupted: 1, ..FaultStats::default() }));
+
+        assert!(apply_command(&injector, "corruption 1.5", None).is_err());
+        assert!(apply_command(&injector, "corruption 0", None).is_ok());
+        network.transmitter(port, "10.0.0.3".parse().unwrap()).send(sent).unwrap();
+        let (amt, _) = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..amt], &sent[..]);
+    }
+
+    #[test]
+    fn admin_commands_edit_the_plan() {
+        let injector = FaultInjector::new(FaultPlan::default());
+        assert!(apply_command(&injector, "loss 0.25", None).is_ok());
+        assert!(apply_command(&injector, "delay 10 30", None).is_ok());
+        assert!(apply_command(&injector, "drop-from 10.0.0.2", None).is_ok());
+        assert!(apply_command(&injector, "drop-from 10.0.0.3", None).is_ok());
+        assert!(apply_command(&injector, "accept-from 10.0.0.2", None).is_ok());
+
+        let plan = injector.plan();
+        assert_eq!(plan.loss, 0.25);
+        assert_eq!((plan.delay_min_ms, plan.delay_max_ms), (10, 30));
+        assert_eq!(plan.drop_from, vec!["10.0.0.3".parse::<IpAddr>().unwrap()]);
+
+        // A bad command leaves the plan as it was.
+        assert!(apply_command(&injector, "loss 2", None).is_err());
+        assert!(apply_command(&injector, "delay 30 10", None).is_err());
+        assert!(apply_command(&injector, "reload", None).is_err());
+        assert!(apply_command(&injector, "flood", None).is_err());
+        assert_eq!(injector.plan(), plan);
+
+        assert!(apply_command(&injector, "set {\"duplication\": 0.5}", None).is_ok());
+        assert_eq!(injector.plan(), FaultPlan { duplication: 0.5, ..FaultPlan::default() });
+        assert_eq!(apply_command(&injector, "show", None).unwrap(), serde_json::to_string(&injector.plan()).unwrap());
+    }
+}
@@ -8,19 +8,28 @@ lazy_static! {
 }
 
 pub fn get_localip() -> Result<IpAddr> {
-    let mut local_ip = LOCAL_IP.lock().unwrap();
-    let old_ip = local_ip.clone();
+    let old_ip = *LOCAL_IP.lock().unwrap();
     match old_ip {
-        None => {
-            let socket = try!(TcpStream::connect("8.8.8.8:53"));
-            let ip = try!(socket.local_addr()).ip();
-            *local_ip = Some(ip);
-            Ok(ip)
-        }
+        None => resolve_localip(false),
         Some(ip) => Ok(ip),
     }
 }
 
+// Looks up the address of the interface used to reach the internet, and caches it
+// for get_localip. Nodes on an IPv6 multicast group must identify themselves by
+// their IPv6 address, so they resolve it up front.
+pub fn resolve_localip(ipv6: bool) -> Result<IpAddr> {
+    let remote = if ipv6 { "[2001:4860:4860::8888]:53" } else { "8.8.8.8:53" };
+    let socket = TcpStream::connect(remote)?;
+    let ip = socket.local_addr()?.ip();
+    *LOCAL_IP.lock().unwrap() = Some(ip);
+    Ok(ip)
+}
+
+pub fn set_localip(ip: IpAddr) {
+    *LOCAL_IP.lock().unwrap() = Some(ip);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -28,7 +37,7 @@ mod tests {
 
     #[test]
     fn check_connection() {
-        assert_eq!(TcpStream::connect("8.8.8.8:53").is_ok(), true);
+        assert!(TcpStream::connect("8.8.8.8:53").is_ok());
     }
 
     #[test]
@@ -36,10 +45,10 @@ mod tests {
         //assert_eq!(LOCAL_IP.lock().unwrap().is_none(), true); 
         // this will sometimes fail, as I cannot figure out how to control the test order
         let ip1 = get_localip();
-        assert_eq!(ip1.is_ok(), true);
-        assert_eq!(LOCAL_IP.lock().unwrap().is_some(), true);
+        assert!(ip1.is_ok());
+        assert!(LOCAL_IP.lock().unwrap().is_some());
         let ip2 = get_localip();
-        assert_eq!(ip2.is_ok(), true);
+        assert!(ip2.is_ok());
         assert_eq!(ip1.unwrap(), ip2.unwrap());
     }
 }
@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
+
+use network::peer::{PeerInfo, PeerUpdate};
+use clock::clock::{self, Clock};
+
+const HISTORY_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerEvent<T> {
+    Joined(T),
+    Left(T),
+}
+
+// The view of the peer set built from the stream of PeerUpdates, together with
+// the most recent joins and leaves.
+pub struct Membership<T> {
+    peers: BTreeMap<T, PeerInfo<T>>,
+    history: VecDeque<(Instant, PeerEvent<T>)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<T> Membership<T>
+    where T: Ord + Clone,
+{
+    pub fn new() -> Self {
+        Membership {
+            peers: BTreeMap::new(),
+            history: VecDeque::new(),
+            clock: clock::real(),
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn apply(&mut self, update: &PeerUpdate<T>) {
+        let now = self.clock.now();
+
+        for id in &update.new {
+            self.record(now, PeerEvent::Joined(id.clone()));
+        }
+        for id in &update.lost {
+            self.record(now, PeerEvent::Left(id.clone()));
+        }
+
+        self.peers.clear();
+        for info in &update.info {
+            self.peers.insert(info.id.clone(), info.clone());
+        }
+    }
+
+    fn record(&mut self, time: Instant, event: PeerEvent<T>) {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back((time, event));
+    }
+
+    pub fn peers(&self) -> Vec<T> {
+        self.peers.keys().cloned().collect()
+    }
+
+    pub fn contains(&self, id: &T) -> bool {
+        self.peers.contains_key(id)
+    }
+
+    pub fn is_suspected(&self, id: &T) -> bool {
+        self.peers.get(id).map_or(false, |info| info.suspected)
+    }
+
+    pub fn info(&self, id: &T) -> Option<&PeerInfo<T>> {
+        self.peers.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn history(&self) -> &VecDeque<(Instant, PeerEvent<T>)> {
+        &self.history
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use network::peer::{PeerInfo, PeerUpdate};
+
+    fn info(id: &str, suspected: bool) -> PeerInfo<String> {
+        let now = Instant::now();
+        PeerInfo { id: id.to_string(), first_seen: now, last_seen: now, suspected: suspected }
+    }
+
+    #[test]
+    fn applies_joins_and_leaves() {
+        let mut membership = Membership::new();
+
+        let mut update = PeerUpdate::new();
+        update.add_new("a".to_string());
+        update.add_new("b".to_string());
+        update.add_info(info("a", false));
+        update.add_info(info("b", false));
+        membership.apply(&update);
+
+        assert_eq!(membership.peers(), vec!["a".to_string(), "b".to_string()]);
+
+        let mut update = PeerUpdate::new();
+        update.add_lost("a".to_string());
+        update.add_info(info("b", true));
+        membership.apply(&update);
+
+        assert_eq!(membership.peers(), vec!["b".to_string()]);
+        assert!(membership.is_suspected(&"b".to_string()));
+        assert!(!membership.contains(&"a".to_string()));
+
+        let events: Vec<PeerEvent<String>> = membership.history().iter()
+            .map(|(_, event)| event.clone())
+            .collect();
+        assert_eq!(events, vec![PeerEvent::Joined("a".to_string()),
+                                PeerEvent::Joined("b".to_string()),
+                                PeerEvent::Left("a".to_string())]);
+    }
+}
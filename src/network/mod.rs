@@ -1,3 +1,8 @@
 pub mod localip;
+pub mod socket;
+pub mod transport;
+pub mod fault;
 pub mod bcast;
 pub mod peer;
+pub mod sidechannel;
+pub mod membership;
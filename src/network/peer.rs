@@ -1,8 +1,7 @@
 
 use std::io;
 use std::thread;
-use std::net::UdpSocket;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
 use std::time::{Duration, Instant};
 use std::str::from_utf8;
@@ -14,16 +13,67 @@ extern crate serde;
 extern crate serde_json;
 extern crate net2;
 
-use net2::UdpBuilder;
+use network::socket::NetMode;
+use network::transport::{Transport, UdpTransport};
+use shutdown::shutdown::Shutdown;
+use clock::clock::{self, Clock};
 
 const INTERVAL_NS: u32 = 20_000_000; // 20 ms
-const TIMEOUT_NS: u32 = 500_000_000; // 100 ms
+const TIMEOUT_NS: u32 = 500_000_000; // 500 ms
+const SUSPECT_AFTER: u32 = 5; // missed heartbeats
+
+// A peer that has missed `suspect_after` heartbeats is reported as suspected, and
+// a peer that has been silent for `timeout` is reported as lost. Lossy test rigs
+// want a longer timeout than wired production.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub suspect_after: u32,
+}
+
+impl Default for PeerConfig {
+    fn default() -> PeerConfig {
+        PeerConfig {
+            interval: Duration::new(0, INTERVAL_NS),
+            timeout: Duration::new(0, TIMEOUT_NS),
+            suspect_after: SUSPECT_AFTER,
+        }
+    }
+}
+
+impl PeerConfig {
+    fn suspect_window(&self) -> Duration {
+        self.interval * self.suspect_after
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerInfo<T> {
+    pub id: T,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    pub suspected: bool,
+}
+
+impl<T> PeerInfo<T> {
+    fn new(id: T, now: Instant) -> Self {
+        PeerInfo {
+            id: id,
+            first_seen: now,
+            last_seen: now,
+            suspected: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct PeerUpdate<T> {
     pub peers: Vec<T>,
-    pub new: Option<T>,
+    pub new: Vec<T>,
+    pub suspected: Vec<T>,
     pub lost: Vec<T>,
+    pub info: Vec<PeerInfo<T>>,
 }
 
 impl<T> PeerUpdate<T>
@@ -32,8 +82,10 @@ impl<T> PeerUpdate<T>
     pub fn new() -> Self {
         PeerUpdate {
             peers: Vec::new(),
-            new: None,
+            new: Vec::new(),
+            suspected: Vec::new(),
             lost: Vec::new(),
+            info: Vec::new(),
         }
     }
 
@@ -41,173 +93,374 @@ impl<T> PeerUpdate<T>
         self.peers.push(id);
     }
 
-    pub fn set_new(&mut self, id: T) {
-        self.new = Some(id);
+    pub fn add_new(&mut self, id: T) {
+        self.new.push(id);
+    }
+
+    pub fn add_suspected(&mut self, id: T) {
+        self.suspected.push(id);
     }
 
     pub fn add_lost(&mut self, id: T) {
         self.lost.push(id);
     }
 
+    pub fn add_info(&mut self, info: PeerInfo<T>) {
+        self.info.push(info);
+    }
+
     fn sort(&mut self) {
         self.peers.sort();
+        self.new.sort();
+        self.suspected.sort();
         self.lost.sort();
+        self.info.sort_by(|a, b| a.id.cmp(&b.id));
     }
 }
 
-impl<T: fmt::Display> fmt::Display for PeerUpdate<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        try!(write!(f, "Peer update:\n"));
-        match self.peers.len() {
-            0 => try!(write!(f, "\tpeers: []\n")),
-            1 => try!(write!(f, "\tpeers: [{}]\n", self.peers[0])),
-            n @ _ => {
-                try!(write!(f, "\tpeers: [{},\n", self.peers[0]));
-                for i in 1..n-1 {
-                    try!(write!(f, "\t        {},\n", self.peers[i]));
-                }
-                try!(write!(f, "\t        {}]\n", self.peers[n-1]));
-            }
-        }
-        match self.new {
-            Some(ref new) => try!(write!(f, "\tnew:   [{}]\n", new)),
-            None => try!(write!(f, "\tnew:   [None]\n"))
-        }
-        match self.lost.len() {
-            0 => try!(write!(f, "\tlost:  []\n")),
-            1 => try!(write!(f, "\tlost:  [{}]\n", self.peers[0])),
-            n @ _ => {
-                try!(write!(f, "\tlost:  [{},\n", self.peers[0]));
-                for i in 1..n-1 {
-                    try!(write!(f, "\t        {},\n", self.peers[i]));
-                }
-                try!(write!(f, "\t        {}]\n", self.peers[n-1]));
+fn write_list<T: fmt::Display>(f: &mut fmt::Formatter, label: &str, list: &[T]) -> fmt::Result {
+    match list.len() {
+        0 => writeln!(f, "\t{:<7}[]", label)?,
+        1 => writeln!(f, "\t{:<7}[{}]", label, list[0])?,
+        n => {
+            writeln!(f, "\t{:<7}[{},", label, list[0])?;
+            for item in &list[1..n-1] {
+                writeln!(f, "\t        {},", item)?;
             }
+            writeln!(f, "\t        {}]", list[n-1])?;
         }
+    }
+    Ok(())
+}
+
+impl<T: fmt::Display> fmt::Display for PeerUpdate<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Peer update:")?;
+        write_list(f, "peers:", &self.peers)?;
+        write_list(f, "new:", &self.new)?;
+        write_list(f, "susp:", &self.suspected)?;
+        write_list(f, "lost:", &self.lost)?;
         Ok(())
     }
 }
 
+// What goes on the wire. Leaving lets the other nodes drop a peer right away
+// instead of waiting for the timeout, and Rejoined makes them report it as new
+// even if the goodbye was lost, so they resynchronize with it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PeerMessage<T> {
+    Heartbeat(T),
+    Leaving(T),
+    Rejoined(T),
+}
+
+const LEAVING_REPEATS: usize = 3;
+
+pub enum PeerCommand<T> {
+    Enable,
+    Disable,
+    SetPayload(T),
+    Shutdown,
+}
+
 pub struct PeerTransmitter {
-    conn: UdpSocket,
-    enabled: Mutex<bool>,
+    conn: Box<dyn Transport>,
+    config: PeerConfig,
 }
 
 impl PeerTransmitter {
     pub fn new(port: u16) -> io::Result<Self> {
-        let conn = {
-            let udp = try!(net2::UdpBuilder::new_v4());
-            try!(udp.reuse_address(true));
-            let socket = try!(udp.bind("0.0.0.0:0"));
-            try!(socket.set_broadcast(true));
-            try!(socket.connect(("255.255.255.255", port)));
-            socket
-        };
-        Ok(PeerTransmitter {
-            conn: conn,
-            enabled: Mutex::new(true),
-        })
+        PeerTransmitter::with_mode(port, &NetMode::Broadcast)
     }
 
-    pub fn enable(&self) {
-        let mut enabled = self.enabled.lock().unwrap();
-        *enabled = true;
+    pub fn with_mode(port: u16, mode: &NetMode) -> io::Result<Self> {
+        PeerTransmitter::with_config(port, mode, PeerConfig::default())
     }
 
-    pub fn disable(&self) {
-        let mut enabled = self.enabled.lock().unwrap();
-        *enabled = false;
+    pub fn with_config(port: u16, mode: &NetMode, config: PeerConfig) -> io::Result<Self> {
+        let conn = UdpTransport::transmitter(port, mode)?;
+        Ok(PeerTransmitter::with_transport(Box::new(conn), config))
     }
 
-    pub fn transmit<'a, T>(&self, data: &'a T) -> io::Result<()>
+    pub fn with_transport(conn: Box<dyn Transport>, config: PeerConfig) -> Self {
+        PeerTransmitter {
+            conn: conn,
+            config: config,
+        }
+    }
+
+    pub fn transmit<T>(&self, data: &T) -> io::Result<()>
         where T: serde::ser::Serialize,
     {
         let serialized = serde_json::to_string(&data).unwrap();
-        try!(self.conn.send(serialized.as_bytes()));
+        self.conn.send(serialized.as_bytes())?;
         Ok(())
     }
 
-    pub fn run<'a, T>(self, data: &'a T) -> !
+    fn send_repeated<T>(&self, message: PeerMessage<T>)
         where T: serde::ser::Serialize,
     {
+        // Sent a few times, as a lost goodbye or rejoin only costs the peers a timeout.
+        for _ in 0..LEAVING_REPEATS {
+            let _ = self.transmit(&message);
+        }
+    }
+
+    // Sends heartbeats until a Shutdown command arrives or every handle is dropped.
+    // The heartbeats keep to the interval however many commands come in between.
+    pub fn run<T>(self, data: T, command_rx: mpsc::Receiver<PeerCommand<T>>)
+        where T: serde::ser::Serialize + Clone,
+    {
+        let mut data = data;
+        let mut enabled = true;
+        let mut next_heartbeat = Instant::now();
         loop {
-            thread::sleep(Duration::new(0, INTERVAL_NS));
-            let enabled = self.enabled.lock().unwrap();
-            if !*enabled {
+            let now = Instant::now();
+            if now >= next_heartbeat {
+                if enabled {
+                    if let Err(err) = self.transmit(&PeerMessage::Heartbeat(data.clone())) {
+                        println!("Transmit failed for PeerTransmitter. Error: {}", err);
+                    }
+                }
+                next_heartbeat = now + self.config.interval;
+            }
+
+            match command_rx.recv_timeout(next_heartbeat - now) {
+                Ok(PeerCommand::Enable) => {
+                    if !enabled {
+                        self.send_repeated(PeerMessage::Rejoined(data.clone()));
+                    }
+                    enabled = true;
+                },
+                Ok(PeerCommand::Disable) => {
+                    if enabled {
+                        self.send_repeated(PeerMessage::Leaving(data.clone()));
+                    }
+                    enabled = false;
+                },
+                Ok(PeerCommand::SetPayload(payload)) => data = payload,
+                Ok(PeerCommand::Shutdown) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    if enabled {
+                        self.send_repeated(PeerMessage::Leaving(data));
+                    }
+                    return;
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+            }
+        }
+    }
+
+    pub fn spawn<T>(self, data: T) -> PeerHandle<T>
+        where T: serde::ser::Serialize + Clone + Send + 'static,
+    {
+        let (command_tx, command_rx) = mpsc::channel();
+        let thread = thread::spawn(move|| {
+            self.run(data, command_rx);
+        });
+        PeerHandle {
+            command_tx: command_tx,
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+}
+
+// Controls a PeerTransmitter running in its own thread. Disabling the heartbeat
+// says goodbye to the other nodes, and enabling it again announces a rejoin.
+pub struct PeerHandle<T> {
+    command_tx: mpsc::Sender<PeerCommand<T>>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl<T> PeerHandle<T> {
+    pub fn enable(&self) {
+        let _ = self.command_tx.send(PeerCommand::Enable);
+    }
+
+    pub fn disable(&self) {
+        let _ = self.command_tx.send(PeerCommand::Disable);
+    }
+
+    pub fn set_payload(&self, payload: T) {
+        let _ = self.command_tx.send(PeerCommand::SetPayload(payload));
+    }
+
+    pub fn shutdown(&self) {
+        let _ = self.command_tx.send(PeerCommand::Shutdown);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+// The bookkeeping of PeerReceiver, apart from the socket and the clock, so the
+// timeouts can be stepped through in tests.
+pub struct PeerTracker<T> {
+    members: HashMap<T, PeerInfo<T>>,
+    config: PeerConfig,
+}
+
+impl<T> PeerTracker<T>
+    where T: Hash + Eq + Clone + Ord,
+{
+    pub fn new(config: PeerConfig) -> Self {
+        PeerTracker {
+            members: HashMap::new(),
+            config: config,
+        }
+    }
+
+    // Takes what was heard during one interval, and returns an update if the
+    // peer set changed.
+    pub fn update(&mut self, heard: Vec<T>, leaving: Vec<T>, rejoined: Vec<T>, now: Instant) -> Option<PeerUpdate<T>> {
+        let mut peer_update = PeerUpdate::new();
+        let mut updated = false;
+
+        // Adding new connections
+        for id in heard {
+            let info = self.members.entry(id.clone()).or_insert_with(|| {
+                updated = true;
+                peer_update.add_new(id.clone());
+                PeerInfo::new(id.clone(), now)
+            });
+            if info.suspected {
+                info.suspected = false;
+                updated = true;
+            }
+            info.last_seen = now;
+        }
+        for id in rejoined {
+            if !peer_update.new.contains(&id) {
+                peer_update.add_new(id);
+                updated = true;
+            }
+        }
+
+        // Suspecting silent connections and removing dead or leaving ones
+        let mut lost: Vec<T> = leaving.into_iter()
+            .filter(|id| self.members.contains_key(id))
+            .collect();
+        lost.sort();
+        lost.dedup();
+        if !lost.is_empty() {
+            updated = true;
+        }
+        for (id, info) in self.members.iter_mut() {
+            if lost.contains(id) {
                 continue;
             }
-            drop(enabled);
-            self.transmit(data);
+            let silence = now.duration_since(info.last_seen);
+            if silence > self.config.timeout {
+                lost.push(id.clone());
+                updated = true;
+            } else if silence > self.config.suspect_window() && !info.suspected {
+                info.suspected = true;
+                updated = true;
+            }
+        }
+        for id in lost {
+            self.members.remove(&id);
+            peer_update.add_lost(id);
+        }
+
+        if !updated {
+            return None;
+        }
+        for (id, info) in &self.members {
+            peer_update.add_peers(id.clone());
+            if info.suspected {
+                peer_update.add_suspected(id.clone());
+            }
+            peer_update.add_info(info.clone());
         }
+        peer_update.sort();
+        Some(peer_update)
     }
 }
 
 
 pub struct PeerReceiver {
-    conn: UdpSocket,
+    conn: Box<dyn Transport>,
+    config: PeerConfig,
+    clock: Arc<dyn Clock>,
 }
 
 impl PeerReceiver {
     pub fn new(port: u16) -> io::Result<Self> {
-        let conn = {
-            let udp = try!(net2::UdpBuilder::new_v4());
-            try!(udp.reuse_address(true));
-            let socket = try!(udp.bind(("255.255.255.255", port)));
-            try!(socket.set_broadcast(true));
-            socket
-        };
-        Ok(PeerReceiver{
+        PeerReceiver::with_mode(port, &NetMode::Broadcast)
+    }
+
+    pub fn with_mode(port: u16, mode: &NetMode) -> io::Result<Self> {
+        PeerReceiver::with_config(port, mode, PeerConfig::default())
+    }
+
+    pub fn with_config(port: u16, mode: &NetMode, config: PeerConfig) -> io::Result<Self> {
+        let conn = UdpTransport::receiver(port, mode)?;
+        Ok(PeerReceiver::with_transport(Box::new(conn), config))
+    }
+
+    pub fn with_transport(conn: Box<dyn Transport>, config: PeerConfig) -> Self {
+        PeerReceiver {
             conn: conn,
-        })
+            config: config,
+            clock: clock::real(),
+        }
     }
 
-    pub fn receive<T>(&self) -> io::Result<T>
-        where T: serde::de::Deserialize,
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn receive<T>(&self) -> io::Result<PeerMessage<T>>
+        where T: serde::de::DeserializeOwned,
     {
         let mut buf = [0u8; 256];
-        let (amt, _) = try!(self.conn.recv_from(&mut buf));
-        let msg = from_utf8(&buf[..amt]).unwrap();
-        Ok(serde_json::from_str(&msg).unwrap())
+        let (amt, _) = self.conn.recv(&mut buf)?;
+        let msg = from_utf8(&buf[..amt]).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        serde_json::from_str(msg).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
     }
 
-    pub fn run<T>(self, update_tx: mpsc::Sender<PeerUpdate<T>>) -> !
-        where T: serde::de::Deserialize + Hash + Eq + Clone + Ord,
+    pub fn run<T>(self, update_tx: mpsc::Sender<PeerUpdate<T>>, shutdown: Shutdown)
+        where T: serde::de::DeserializeOwned + Hash + Eq + Clone + Ord,
     {
-        let mut last_seen = HashMap::new();
-        loop {
-            let mut peer_update = PeerUpdate::new();
-            let mut updated = false;
+        let mut tracker = PeerTracker::new(self.config);
 
-            self.conn.set_read_timeout(Some(Duration::new(0, TIMEOUT_NS))).unwrap();
-            let new_id: Option<T> = self.receive().ok();
-
-            // Adding new connection
-            if let Some(id) = new_id {
-                if !last_seen.contains_key(&id) {
-                    peer_update.set_new(id.clone());
-                    updated = true;
+        while !shutdown.is_triggered() {
+            // Collect heartbeats for one interval, so peers that join at the same
+            // time are reported in the same update. Each read waits for what is
+            // left of the interval on the clock, and a read that times out ends
+            // the batch, so a clock that is stepped by hand ends it too.
+            let batch_end = self.clock.now() + self.config.interval;
+            let mut heard = Vec::new();
+            let mut leaving = Vec::new();
+            let mut rejoined = Vec::new();
+            loop {
+                let now = self.clock.now();
+                if now >= batch_end {
+                    break;
                 }
-                last_seen.insert(id, Instant::now());
-            }
-
-            // Removing dead connection
-            for (id, time) in &last_seen {
-                if Instant::now().duration_since(*time) > Duration::new(0, TIMEOUT_NS) {
-                    peer_update.add_lost(id.clone());
-                    updated = true;
+                self.conn.set_read_timeout(Some(batch_end - now)).unwrap();
+                match self.receive::<T>() {
+                    Ok(PeerMessage::Heartbeat(id)) => heard.push(id),
+                    Ok(PeerMessage::Leaving(id)) => leaving.push(id),
+                    Ok(PeerMessage::Rejoined(id)) => {
+                        heard.push(id.clone());
+                        rejoined.push(id);
+                    },
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                                 || err.kind() == io::ErrorKind::TimedOut => break,
+                    Err(_) => {},
+                }
+                if shutdown.is_triggered() {
+                    return;
                 }
-            }
-            for id in &peer_update.lost {
-                last_seen.remove(id);
             }
 
             // Sending update
-            if updated {
-                for (id, _) in &last_seen {
-                    peer_update.add_peers(id.clone());
+            if let Some(peer_update) = tracker.update(heard, leaving, rejoined, self.clock.now()) {
+                if update_tx.send(peer_update).is_err() {
+                    return;
                 }
-                peer_update.sort();
-                update_tx.send(peer_update).unwrap();
             }
         }
     }
@@ -220,27 +473,196 @@ mod tests {
     use super::*;
     use std::thread;
     use std::sync::mpsc::channel;
-    use localip::get_localip;
+    use std::time::Duration;
+    use clock::clock::{Clock, ManualClock};
+    use network::transport::LoopbackNetwork;
 
     #[test]
     fn it_works() {
+        let network = LoopbackNetwork::new();
         let port = 9887;
-        thread::spawn(move || {
-            let id = format!("{}:{}", get_localip().unwrap(), "unique");
-            let transmitter = PeerTransmitter::new(port).unwrap();
-            transmitter.run(&id);
-        });
+        let address = "10.0.0.1".parse().unwrap();
+        let id = format!("{}:{}", address, "unique");
+        let receiver = PeerReceiver::with_transport(Box::new(network.receiver(port, address)), PeerConfig::default());
+        let handle = PeerTransmitter::with_transport(Box::new(network.transmitter(port, address)), PeerConfig::default())
+            .spawn(id.clone());
         let (tx, rx) = channel::<PeerUpdate<String>>();
-        thread::spawn(move|| {
-            let receiver = PeerReceiver::new(port).unwrap();
-            receiver.run(tx);
-        });
-        for _ in 0..10 {
-            let update = rx.recv().unwrap();
-            println!("Peer update");
-            println!("\tPeers:\t{:?}", update.peers);
-            println!("\tNew:\t{:?}", update.new);
-            println!("\tLost:\t{:?}", update.lost);
+        let shutdown = Shutdown::new();
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move|| receiver.run(tx, shutdown))
+        };
+
+        let update = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(update.new, vec![id.clone()]);
+        assert_eq!(update.peers, vec![id.clone()]);
+
+        // The goodbye drops the peer right away.
+        handle.shutdown();
+        let update = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(update.lost, vec![id]);
+        assert!(update.peers.is_empty());
+
+        shutdown.trigger();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn receive_refuses_a_datagram_that_is_not_text() {
+        let network = LoopbackNetwork::new();
+        let port = 9891;
+        let address = "10.0.0.1".parse().unwrap();
+        let receiver = PeerReceiver::with_transport(Box::new(network.receiver(port, address)), PeerConfig::default());
+        receiver.conn.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        network.transmitter(port, address).send(&[b'{', 0xff, 0xfe, b'}']).unwrap();
+        let err = receiver.receive::<String>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    // What the transmitter sends over the next while.
+    fn wire(receiver: &dyn Transport, during: Duration) -> Vec<PeerMessage<String>> {
+        let mut buf = [0u8; 1024];
+        let mut messages = Vec::new();
+        let until = Instant::now() + during;
+        let mut now = Instant::now();
+        while now < until {
+            receiver.set_read_timeout(Some(until - now)).unwrap();
+            if let Ok((amt, _)) = receiver.recv(&mut buf) {
+                messages.push(serde_json::from_slice(&buf[..amt]).unwrap());
+            }
+            now = Instant::now();
         }
+        messages
+    }
+
+    fn count<F>(messages: &[PeerMessage<String>], matches: F) -> usize
+        where F: Fn(&PeerMessage<String>) -> bool,
+    {
+        messages.iter().filter(|message| matches(message)).count()
+    }
+
+    #[test]
+    fn transmitter_puts_heartbeats_and_goodbyes_on_the_wire() {
+        let network = LoopbackNetwork::new();
+        let address = "10.0.0.1".parse().unwrap();
+        let receiver = network.receiver(9888, address);
+        let handle = PeerTransmitter::with_transport(Box::new(network.transmitter(9888, address)), PeerConfig::default())
+            .spawn("a".to_string());
+        let is_heartbeat = |message: &PeerMessage<String>| match *message { PeerMessage::Heartbeat(_) => true, _ => false };
+
+        // Commands coming in faster than the interval do not hold the
+        // heartbeats back.
+        for _ in 0..40 {
+            handle.set_payload("a".to_string());
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(count(&wire(&receiver, Duration::from_millis(10)), is_heartbeat) >= 5);
+
+        handle.disable();
+        let messages = wire(&receiver, Duration::from_millis(100));
+        assert_eq!(count(&messages, |message| match *message { PeerMessage::Leaving(_) => true, _ => false }), LEAVING_REPEATS);
+        // Nothing but the goodbye once it has been sent.
+        let leaving_at = messages.iter().position(|message| !is_heartbeat(message)).unwrap();
+        assert_eq!(messages.len(), leaving_at + LEAVING_REPEATS);
+
+        handle.enable();
+        let messages = wire(&receiver, Duration::from_millis(100));
+        assert_eq!(count(&messages, |message| match *message { PeerMessage::Rejoined(_) => true, _ => false }), LEAVING_REPEATS);
+        assert!(count(&messages, is_heartbeat) >= 1);
+
+        handle.shutdown();
+        let messages = wire(&receiver, Duration::from_millis(100));
+        let leaving = count(&messages, |message| match *message { PeerMessage::Leaving(_) => true, _ => false });
+        assert_eq!(leaving, LEAVING_REPEATS);
+        assert_eq!(messages.len() - count(&messages, is_heartbeat), LEAVING_REPEATS);
+        assert!(wire(&receiver, Duration::from_millis(50)).is_empty());
+    }
+
+    #[test]
+    fn tracker_suspects_and_loses_silent_peers() {
+        let clock = ManualClock::new();
+        let config = PeerConfig::default();
+        let mut tracker = PeerTracker::new(config);
+        let (a, b) = ("a".to_string(), "b".to_string());
+
+        let update = tracker.update(vec![a.clone(), b.clone()], vec![], vec![], clock.now()).unwrap();
+        assert_eq!(update.new, vec![a.clone(), b.clone()]);
+
+        // Only a keeps sending heartbeats.
+        clock.advance(config.suspect_window() + config.interval);
+        let update = tracker.update(vec![a.clone()], vec![], vec![], clock.now()).unwrap();
+        assert_eq!(update.suspected, vec![b.clone()]);
+        assert!(tracker.update(vec![a.clone()], vec![], vec![], clock.now()).is_none());
+
+        clock.advance(config.timeout);
+        let update = tracker.update(vec![a.clone()], vec![], vec![], clock.now()).unwrap();
+        assert_eq!(update.lost, vec![b]);
+        assert_eq!(update.peers, vec![a]);
+    }
+
+    #[test]
+    fn receiver_loses_a_peer_on_a_manual_clock() {
+        use network::membership::{Membership, PeerEvent};
+
+        let network = LoopbackNetwork::new();
+        let port = 9889;
+        let address = "10.0.0.1".parse().unwrap();
+        let clock = ManualClock::new();
+        let config = PeerConfig::default();
+        let mut receiver = PeerReceiver::with_transport(Box::new(network.receiver(port, address)), config);
+        receiver.set_clock(Arc::new(clock.clone()));
+        let (tx, rx) = channel::<PeerUpdate<String>>();
+        let shutdown = Shutdown::new();
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move|| receiver.run(tx, shutdown))
+        };
+        let mut membership = Membership::new();
+        membership.set_clock(Arc::new(clock.clone()));
+
+        let a = "a".to_string();
+        let heartbeat = serde_json::to_string(&PeerMessage::Heartbeat(a.clone())).unwrap();
+        network.transmitter(port, address).send(heartbeat.as_bytes()).unwrap();
+        let update = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(update.new, vec![a.clone()]);
+        let joined_at = clock.now();
+        membership.apply(&update);
+
+        // The clock alone moves a against the timeout, while the receiver keeps
+        // finishing its batches on the reads that time out.
+        clock.advance(config.suspect_window() + config.interval);
+        let update = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(update.suspected, vec![a.clone()]);
+        membership.apply(&update);
+        assert!(membership.is_suspected(&a));
+
+        clock.advance(config.timeout);
+        let update = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(update.lost, vec![a.clone()]);
+        membership.apply(&update);
+        assert!(membership.is_empty());
+        let history: Vec<(Instant, PeerEvent<String>)> = membership.history().iter().cloned().collect();
+        assert_eq!(history, vec![(joined_at, PeerEvent::Joined(a.clone())), (clock.now(), PeerEvent::Left(a))]);
+
+        shutdown.trigger();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn display_lists_lost_peers() {
+        let mut update = PeerUpdate::new();
+        update.add_peers("a".to_string());
+        update.add_new("a".to_string());
+        update.add_lost("b".to_string());
+        update.add_lost("c".to_string());
+
+        let expected = "Peer update:\n\
+                        \tpeers: [a]\n\
+                        \tnew:   [a]\n\
+                        \tsusp:  []\n\
+                        \tlost:  [b,\n\
+                        \t        c]\n";
+        assert_eq!(format!("{}", update), expected);
     }
 }
@@ -0,0 +1,360 @@
+use std::io;
+use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream, IpAddr, SocketAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+extern crate serde;
+extern crate serde_json;
+
+use shutdown::shutdown::Shutdown;
+
+const POLL_MS: u64 = 100;
+const CONNECT_TIMEOUT_MS: u64 = 500;
+const WRITE_TIMEOUT_MS: u64 = 1000;
+const ACK_TIMEOUT_MS: u64 = 1000;
+// Guards against a garbled length making us allocate without bound.
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+// A frame is the length of the payload as four bytes, most significant first,
+// followed by the payload. The receiver answers every frame with an empty one,
+// as a write that succeeds says nothing about the peer having read it.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame is too long"));
+    }
+    let len = payload.len() as u32;
+    let header = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header)?;
+    let len = header.iter().fold(0usize, |len, &byte| (len << 8) | byte as usize);
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame is too long"));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+// Reads from a stream with a read timeout, waiting out the timeouts until
+// there is data or the shutdown.
+struct UntilShutdown<'a> {
+    stream: &'a mut TcpStream,
+    shutdown: &'a Shutdown,
+}
+
+impl<'a> Read for UntilShutdown<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                             || err.kind() == io::ErrorKind::TimedOut => {
+                    if self.shutdown.is_triggered() {
+                        return Err(io::Error::new(io::ErrorKind::Other, "shutting down"));
+                    }
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+pub enum PoolCommand<T> {
+    Send(IpAddr, T),
+    // Closes the connections to peers that are gone.
+    Forget(Vec<IpAddr>),
+}
+
+// Keeps one connection open to each peer it has sent to. Every node listens on
+// the same port, so a peer is addressed by the ip of its heartbeat.
+pub struct ConnectionPool {
+    port: u16,
+    connections: HashMap<IpAddr, TcpStream>,
+}
+
+impl ConnectionPool {
+    pub fn new(port: u16) -> Self {
+        ConnectionPool {
+            port: port,
+            connections: HashMap::new(),
+        }
+    }
+
+    pub fn send<T>(&mut self, to: IpAddr, message: &T) -> io::Result<()>
+        where T: serde::ser::Serialize,
+    {
+        let payload = serde_json::to_string(message).unwrap();
+        // The peer may have closed a pooled connection since it was last used,
+        // so a message that is not acknowledged there is sent once more on a new
+        // connection. A peer that got it but whose acknowledgement was lost gets
+        // it twice.
+        if let Some(mut stream) = self.connections.remove(&to) {
+            if deliver(&mut stream, payload.as_bytes()).is_ok() {
+                self.connections.insert(to, stream);
+                return Ok(());
+            }
+        }
+        // A peer without a sidechannel may drop the connection attempt instead of
+        // refusing it, and the pool has other peers to serve.
+        let mut stream = TcpStream::connect_timeout(&SocketAddr::new(to, self.port),
+                                                         Duration::from_millis(CONNECT_TIMEOUT_MS))?;
+        stream.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MS)))?;
+        stream.set_read_timeout(Some(Duration::from_millis(ACK_TIMEOUT_MS)))?;
+        stream.set_nodelay(true)?;
+        deliver(&mut stream, payload.as_bytes())?;
+        self.connections.insert(to, stream);
+        Ok(())
+    }
+
+    pub fn forget(&mut self, peer: &IpAddr) {
+        self.connections.remove(peer);
+    }
+
+    pub fn is_connected(&self, peer: &IpAddr) -> bool {
+        self.connections.contains_key(peer)
+    }
+
+    // Connecting can block for a while, so the pool runs in its own thread.
+    // Messages that could not be delivered are handed back through failed, with
+    // the address they were for, so the sender can get them through otherwise.
+    pub fn run<T>(mut self, commands: mpsc::Receiver<PoolCommand<T>>, failed: mpsc::Sender<(T, String)>, shutdown: Shutdown)
+        where T: serde::ser::Serialize,
+    {
+        while !shutdown.is_triggered() {
+            match commands.recv_timeout(Duration::from_millis(POLL_MS)) {
+                Ok(PoolCommand::Send(to, message)) => {
+                    if let Err(err) = self.send(to, &message) {
+                        println!("Send to {} failed for ConnectionPool. Error: {}", to, err);
+                        let _ = failed.send((message, to.to_string()));
+                    }
+                },
+                Ok(PoolCommand::Forget(peers)) => {
+                    for peer in &peers {
+                        self.forget(peer);
+                    }
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+fn deliver(stream: &mut TcpStream, payload: &[u8]) -> io::Result<()> {
+    write_frame(stream, payload)?;
+    let ack = read_frame(stream)?;
+    if !ack.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "expected an acknowledgement"));
+    }
+    Ok(())
+}
+
+pub struct SidechannelListener {
+    listener: TcpListener,
+}
+
+impl SidechannelListener {
+    // Listens on the address family the peers are reached by.
+    pub fn bind(port: u16, ipv6: bool) -> io::Result<Self> {
+        let any = if ipv6 { IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)) } else { IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)) };
+        let listener = TcpListener::bind(SocketAddr::new(any, port))?;
+        // Polling accept lets the thread notice a shutdown.
+        listener.set_nonblocking(true)?;
+        Ok(SidechannelListener {
+            listener: listener,
+        })
+    }
+
+    pub fn local_port(&self) -> io::Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    // Hands on every message received, with the address of its sender. Each
+    // connection is read in its own thread. The handles of threads that are
+    // done are dropped as the next connections come in, and the rest are
+    // joined on the shutdown.
+    pub fn run<T>(self, message_tx: mpsc::Sender<(T, String)>, shutdown: Shutdown)
+        where T: serde::de::DeserializeOwned + Send + 'static,
+    {
+        let mut connections: Vec<thread::JoinHandle<()>> = Vec::new();
+        while !shutdown.is_triggered() {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    connections.retain(|connection| !connection.is_finished());
+                    let (message_tx, shutdown) = (message_tx.clone(), shutdown.clone());
+                    connections.push(thread::spawn(move|| {
+                        if let Err(err) = serve(stream, addr.ip(), message_tx, shutdown) {
+                            println!("Connection from {} failed for SidechannelListener. Error: {}", addr.ip(), err);
+                        }
+                    }));
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(POLL_MS));
+                },
+                Err(err) => println!("Accept failed for SidechannelListener. Error: {}", err),
+            }
+        }
+        for connection in connections {
+            let _ = connection.join();
+        }
+    }
+}
+
+fn serve<T>(mut stream: TcpStream, from: IpAddr, message_tx: mpsc::Sender<(T, String)>, shutdown: Shutdown) -> io::Result<()>
+    where T: serde::de::DeserializeOwned,
+{
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_millis(POLL_MS)))?;
+    loop {
+        let payload = match read_frame(&mut UntilShutdown { stream: &mut stream, shutdown: &shutdown }) {
+            Ok(payload) => payload,
+            // The peer closed the connection between frames.
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(ref err) if shutdown.is_triggered() && err.kind() == io::ErrorKind::Other => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        // Acknowledged once read, as sending a garbled frame again would not help.
+        write_frame(&mut stream, &[])?;
+        let message = match serde_json::from_slice(&payload) {
+            Ok(message) => message,
+            Err(err) => {
+                println!("Dropping garbled frame from {}. Error: {}", from, err);
+                continue;
+            }
+        };
+        if message_tx.send((message, from.to_string())).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::thread;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use std::net::TcpListener;
+    use shutdown::shutdown::Shutdown;
+
+    #[test]
+    fn frames_round_trip_and_bound_their_length() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").unwrap();
+        write_frame(&mut buf, b"").unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 5]);
+
+        let mut reader = io::Cursor::new(buf);
+        assert_eq!(read_frame(&mut reader).unwrap(), b"hello".to_vec());
+        assert_eq!(read_frame(&mut reader).unwrap(), Vec::<u8>::new());
+        assert_eq!(read_frame(&mut reader).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut reader = io::Cursor::new(vec![0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(read_frame(&mut reader).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn pool_sends_large_messages_over_one_connection() {
+        let listener = SidechannelListener::bind(0, false).unwrap();
+        let port = listener.local_port().unwrap();
+        let (message_tx, message_rx) = channel::<(String, String)>();
+        let shutdown = Shutdown::new();
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move|| listener.run(message_tx, shutdown))
+        };
+
+        let localhost = "127.0.0.1".parse().unwrap();
+        let mut pool = ConnectionPool::new(port);
+        // Far more than fits in one of the broadcast datagrams.
+        let large: String = (0..10_000).map(|i| if i % 2 == 0 { 'a' } else { 'b' }).collect();
+        pool.send(localhost, &large).unwrap();
+        pool.send(localhost, &"small".to_string()).unwrap();
+        assert!(pool.is_connected(&localhost));
+
+        let (message, from) = message_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(message, large);
+        assert_eq!(from, "127.0.0.1");
+        assert_eq!(message_rx.recv_timeout(Duration::from_secs(1)).unwrap().0, "small");
+
+        pool.forget(&localhost);
+        assert!(!pool.is_connected(&localhost));
+        shutdown.trigger();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn pool_reconnects_when_the_peer_closed_a_pooled_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let localhost = "127.0.0.1".parse().unwrap();
+        let peer = thread::spawn(move|| {
+            // Reads and acknowledges one frame, then hangs up.
+            let (mut first, _) = listener.accept().unwrap();
+            assert_eq!(read_frame(&mut first).unwrap(), b"\"first\"".to_vec());
+            write_frame(&mut first, &[]).unwrap();
+            drop(first);
+            let (mut second, _) = listener.accept().unwrap();
+            let payload = read_frame(&mut second).unwrap();
+            write_frame(&mut second, &[]).unwrap();
+            payload
+        });
+
+        let mut pool = ConnectionPool::new(port);
+        pool.send(localhost, &"first".to_string()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        // The write to the closed connection succeeds locally, but nothing
+        // acknowledges it, so it goes out again on a new connection.
+        pool.send(localhost, &"second".to_string()).unwrap();
+        assert_eq!(peer.join().unwrap(), b"\"second\"".to_vec());
+    }
+
+    #[test]
+    fn listens_on_ipv6() {
+        let listener = SidechannelListener::bind(0, true).unwrap();
+        let port = listener.local_port().unwrap();
+        let (message_tx, message_rx) = channel::<(String, String)>();
+        let shutdown = Shutdown::new();
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move|| listener.run(message_tx, shutdown))
+        };
+
+        ConnectionPool::new(port).send("::1".parse().unwrap(), &"hello".to_string()).unwrap();
+        let (message, from) = message_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!((message.as_str(), from.as_str()), ("hello", "::1"));
+
+        shutdown.trigger();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn pool_hands_back_what_it_could_not_deliver() {
+        // Nobody listens on the port any longer.
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (command_tx, command_rx) = channel::<PoolCommand<String>>();
+        let (failed_tx, failed_rx) = channel::<(String, String)>();
+        let shutdown = Shutdown::new();
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move|| ConnectionPool::new(port).run(command_rx, failed_tx, shutdown))
+        };
+
+        command_tx.send(PoolCommand::Send("127.0.0.1".parse().unwrap(), "lost".to_string())).unwrap();
+        let (message, to) = failed_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!((message.as_str(), to.as_str()), ("lost", "127.0.0.1"));
+
+        shutdown.trigger();
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+use std::io;
+use std::net::{UdpSocket, IpAddr, Ipv4Addr};
+
+extern crate net2;
+
+use net2::{UdpBuilder, UdpSocketExt};
+
+// Every transmitter and receiver in the network module talks either to the
+// limited broadcast address or to a multicast group. The sockets are set up
+// here so bcast and peer share the exact same behaviour.
+#[derive(Debug, Clone)]
+#[derive(Default)]
+pub enum NetMode {
+    #[default]
+    Broadcast,
+    Multicast(MulticastConfig),
+}
+
+
+#[derive(Debug, Clone, Copy)]
+pub enum Interface {
+    Default,
+    Addr(Ipv4Addr), // IPv4 groups select the interface by address
+    Index(u32),     // IPv6 groups select the interface by index
+}
+
+#[derive(Debug, Clone)]
+pub struct MulticastConfig {
+    pub group: IpAddr,
+    pub ttl: u32,
+    pub interface: Interface,
+}
+
+impl MulticastConfig {
+    pub fn new(group: IpAddr) -> Self {
+        MulticastConfig {
+            group: group,
+            ttl: 1,
+            interface: Interface::Default,
+        }
+    }
+}
+
+impl NetMode {
+    pub fn is_ipv6(&self) -> bool {
+        match *self {
+            NetMode::Multicast(ref config) => config.group.is_ipv6(),
+            NetMode::Broadcast => false,
+        }
+    }
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+pub fn transmit_socket(port: u16, mode: &NetMode) -> io::Result<UdpSocket> {
+    match *mode {
+        NetMode::Broadcast => {
+            let udp = UdpBuilder::new_v4()?;
+            udp.reuse_address(true)?;
+            let socket = udp.bind("0.0.0.0:0")?;
+            socket.set_broadcast(true)?;
+            socket.connect(("255.255.255.255", port))?;
+            Ok(socket)
+        },
+        NetMode::Multicast(ref config) => match config.group {
+            IpAddr::V4(group) => {
+                if !group.is_multicast() {
+                    return Err(invalid_input("given address is not an IPv4 multicast group"));
+                }
+                let udp = UdpBuilder::new_v4()?;
+                udp.reuse_address(true)?;
+                let socket = udp.bind("0.0.0.0:0")?;
+                socket.set_multicast_ttl_v4(config.ttl)?;
+                match config.interface {
+                    Interface::Default => {},
+                    Interface::Addr(ref addr) => socket.set_multicast_if_v4(addr)?,
+                    Interface::Index(_) => return Err(invalid_input("IPv4 groups need an interface address")),
+                }
+                socket.connect((group, port))?;
+                Ok(socket)
+            },
+            IpAddr::V6(group) => {
+                if !group.is_multicast() {
+                    return Err(invalid_input("given address is not an IPv6 multicast group"));
+                }
+                let udp = UdpBuilder::new_v6()?;
+                udp.reuse_address(true)?;
+                udp.only_v6(true)?;
+                let socket = udp.bind("[::]:0")?;
+                socket.set_multicast_hops_v6(config.ttl)?;
+                match config.interface {
+                    Interface::Default => {},
+                    Interface::Index(index) => socket.set_multicast_if_v6(index)?,
+                    Interface::Addr(_) => return Err(invalid_input("IPv6 groups need an interface index")),
+                }
+                socket.connect((group, port))?;
+                Ok(socket)
+            },
+        },
+    }
+}
+
+pub fn receive_socket(port: u16, mode: &NetMode) -> io::Result<UdpSocket> {
+    match *mode {
+        NetMode::Broadcast => {
+            let udp = UdpBuilder::new_v4()?;
+            udp.reuse_address(true)?;
+            let socket = udp.bind(("255.255.255.255", port))?;
+            socket.set_broadcast(true)?;
+            Ok(socket)
+        },
+        NetMode::Multicast(ref config) => match config.group {
+            IpAddr::V4(group) => {
+                let interface = match config.interface {
+                    Interface::Default => Ipv4Addr::new(0, 0, 0, 0),
+                    Interface::Addr(addr) => addr,
+                    Interface::Index(_) => return Err(invalid_input("IPv4 groups need an interface address")),
+                };
+                let udp = UdpBuilder::new_v4()?;
+                udp.reuse_address(true)?;
+                let socket = udp.bind(("0.0.0.0", port))?;
+                socket.join_multicast_v4(&group, &interface)?;
+                Ok(socket)
+            },
+            IpAddr::V6(group) => {
+                let interface = match config.interface {
+                    Interface::Default => 0,
+                    Interface::Index(index) => index,
+                    Interface::Addr(_) => return Err(invalid_input("IPv6 groups need an interface index")),
+                };
+                let udp = UdpBuilder::new_v6()?;
+                udp.reuse_address(true)?;
+                udp.only_v6(true)?;
+                let socket = udp.bind(("::", port))?;
+                socket.join_multicast_v6(&group, interface)?;
+                Ok(socket)
+            },
+        },
+    }
+}
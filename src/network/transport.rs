@@ -0,0 +1,196 @@
+use std::io;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::{UdpSocket, IpAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use network::socket::{NetMode, transmit_socket, receive_socket};
+
+// Carries the datagrams of bcast and peer. A transport is made either for
+// sending to everyone on a port, or for receiving what is sent there.
+pub trait Transport: Send {
+    fn send(&self, data: &[u8]) -> io::Result<()>;
+    // Gives the length and the address of the sender. Fails with WouldBlock or
+    // TimedOut when nothing arrived within the read timeout.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, IpAddr)>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn transmitter(port: u16, mode: &NetMode) -> io::Result<Self> {
+        Ok(UdpTransport { socket: transmit_socket(port, mode)? })
+    }
+
+    pub fn receiver(port: u16, mode: &NetMode) -> io::Result<Self> {
+        Ok(UdpTransport { socket: receive_socket(port, mode)? })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.socket.send(data)?;
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, IpAddr)> {
+        let (amt, addr) = self.socket.recv_from(buf)?;
+        Ok((amt, addr.ip()))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+}
+
+type Datagram = (Vec<u8>, IpAddr);
+
+// A broadcast domain inside this process. Whatever is sent to a port reaches
+// every receiver on that port, including the sender's own, like the UDP
+// broadcast does. Clones share the domain.
+#[derive(Debug, Clone)]
+pub struct LoopbackNetwork {
+    ports: Arc<Mutex<HashMap<u16, Vec<Sender<Datagram>>>>>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        LoopbackNetwork {
+            ports: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Sends as the node at address.
+    pub fn transmitter(&self, port: u16, address: IpAddr) -> ChannelTransport {
+        ChannelTransport {
+            network: self.clone(),
+            port: port,
+            address: address,
+            incoming: None,
+            timeout: Cell::new(None),
+        }
+    }
+
+    pub fn receiver(&self, port: u16, address: IpAddr) -> ChannelTransport {
+        let (sender, receiver) = channel::<Datagram>();
+        self.ports.lock().unwrap().entry(port).or_default().push(sender);
+        ChannelTransport {
+            network: self.clone(),
+            port: port,
+            address: address,
+            incoming: Some(receiver),
+            timeout: Cell::new(None),
+        }
+    }
+
+    fn deliver(&self, port: u16, data: &[u8], from: IpAddr) {
+        let mut ports = self.ports.lock().unwrap();
+        if let Some(receivers) = ports.get_mut(&port) {
+            // Receivers that were dropped are forgotten.
+            receivers.retain(|receiver| receiver.send((data.to_vec(), from)).is_ok());
+        }
+    }
+}
+
+pub struct ChannelTransport {
+    network: LoopbackNetwork,
+    port: u16,
+    address: IpAddr,
+    incoming: Option<Receiver<Datagram>>,
+    timeout: Cell<Option<Duration>>,
+}
+
+impl Transport for ChannelTransport {
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.network.deliver(self.port, data, self.address);
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, IpAddr)> {
+        let incoming = match self.incoming {
+            Some(ref incoming) => incoming,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "transport only sends")),
+        };
+        let (data, from) = match self.timeout.get() {
+            Some(timeout) => match incoming.recv_timeout(timeout) {
+                Ok(datagram) => datagram,
+                Err(RecvTimeoutError::Timeout) => return Err(io::Error::new(io::ErrorKind::WouldBlock, "no datagram")),
+                Err(RecvTimeoutError::Disconnected) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "network is gone")),
+            },
+            None => match incoming.recv() {
+                Ok(datagram) => datagram,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "network is gone")),
+            },
+        };
+        // Too long datagrams are cut, as with UDP.
+        let amt = if data.len() < buf.len() { data.len() } else { buf.len() };
+        buf[..amt].copy_from_slice(&data[..amt]);
+        Ok((amt, from))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout.set(timeout);
+        Ok(())
+    }
+}
+
+// Where a node gets its transports from.
+#[derive(Debug, Clone)]
+#[derive(Default)]
+pub enum TransportKind {
+    #[default]
+    Udp,
+    // On a loopback network, as the node at the address.
+    Loopback(LoopbackNetwork, IpAddr),
+}
+
+
+impl TransportKind {
+    pub fn transmitter(&self, port: u16, mode: &NetMode) -> io::Result<Box<dyn Transport>> {
+        match *self {
+            TransportKind::Udp => Ok(Box::new(UdpTransport::transmitter(port, mode)?)),
+            TransportKind::Loopback(ref network, address) => Ok(Box::new(network.transmitter(port, address))),
+        }
+    }
+
+    pub fn receiver(&self, port: u16, mode: &NetMode) -> io::Result<Box<dyn Transport>> {
+        match *self {
+            TransportKind::Udp => Ok(Box::new(UdpTransport::receiver(port, mode)?)),
+            TransportKind::Loopback(ref network, address) => Ok(Box::new(network.receiver(port, address))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn loopback_reaches_every_receiver_on_the_port() {
+        let network = LoopbackNetwork::new();
+        let a = "10.0.0.1".parse().unwrap();
+        let first = network.receiver(4000, a);
+        let second = network.receiver(4000, a);
+        let other_port = network.receiver(4001, a);
+        other_port.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+
+        network.transmitter(4000, "10.0.0.2".parse().unwrap()).send(b"hello").unwrap();
+
+        let mut buf = [0u8; 16];
+        for receiver in &[first, second] {
+            let (amt, from) = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..amt], b"hello");
+            assert_eq!(from.to_string(), "10.0.0.2");
+        }
+        let err = other_port.recv(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,89 @@
+use elevator_driver::elev_io::{N_FLOORS, Floor, Button, Light};
+
+use request_handler::request::*;
+
+// Derives every button lamp from the request table. A lamp is lit exactly when
+// its request is active, which for hall requests means every alive peer has
+// acknowledged it, so a lit hall lamp is always a request that will be served.
+pub struct LampSync {
+    lit: Vec<Vec<Option<bool>>>,
+}
+
+impl LampSync {
+    pub fn new() -> Self {
+        // Nothing is known about the lamps at start, so the first sync sets all of them.
+        LampSync {
+            lit: vec![vec![None; N_FLOORS]; 3],
+        }
+    }
+
+    // Returns the lamps that have to change to match the table.
+    pub fn changes(&mut self, requests: &[Vec<Request>]) -> Vec<(Button, Light)> {
+        let mut changes = Vec::new();
+
+        for &request_type in [RequestType::CallDown, RequestType::CallUp, RequestType::Internal].iter() {
+            for floor in 0..N_FLOORS {
+                let button = match request_type {
+                    RequestType::CallUp if floor == N_FLOORS-1 => continue,
+                    RequestType::CallDown if floor == 0 => continue,
+                    RequestType::CallUp => Button::CallUp(Floor::At(floor)),
+                    RequestType::CallDown => Button::CallDown(Floor::At(floor)),
+                    RequestType::Internal => Button::Internal(Floor::At(floor)),
+                };
+
+                let should_be_lit = requests[request_type as usize][floor].is_active();
+                let lit = &mut self.lit[request_type as usize][floor];
+                if *lit != Some(should_be_lit) {
+                    *lit = Some(should_be_lit);
+                    changes.push((button, if should_be_lit { Light::On } else { Light::Off }));
+                }
+            }
+        }
+
+        changes
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elevator_driver::elev_io::{N_FLOORS, Light};
+
+    fn table() -> Vec<Vec<Request>> {
+        let mut requests = vec![vec![]; 3];
+        for &t in [RequestType::CallDown, RequestType::CallUp, RequestType::Internal].iter() {
+            for floor in 0..N_FLOORS {
+                requests[t as usize].push(Request { floor: floor, request_type: t, ..Request::default() });
+            }
+        }
+        requests
+    }
+
+    #[test]
+    fn lights_only_active_requests() {
+        let mut lamps = LampSync::new();
+        let mut requests = table();
+
+        // Every existing button is set off first.
+        assert_eq!(lamps.changes(&requests).len(), 3*N_FLOORS - 2);
+        assert_eq!(lamps.changes(&requests).len(), 0);
+
+        requests[RequestType::CallUp as usize][1].press("10.0.0.1".to_string(), 0);
+        assert_eq!(lamps.changes(&requests).len(), 0);
+
+        requests[RequestType::CallUp as usize][1].activate(0);
+        let changes = lamps.changes(&requests);
+        assert_eq!(changes.len(), 1);
+        if let Light::Off = changes[0].1 {
+            panic!("Active request was not lit");
+        }
+
+        requests[RequestType::CallUp as usize][1].clear(0);
+        let changes = lamps.changes(&requests);
+        assert_eq!(changes.len(), 1);
+        if let Light::On = changes[0].1 {
+            panic!("Cleared request was not turned off");
+        }
+    }
+}
@@ -4,3 +4,6 @@
 pub mod request;
 pub mod request_transmitter;
 pub mod request_handler;
+pub mod reliable;
+pub mod sync;
+pub mod lamp_sync;
@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use request_handler::request::{Request, IP};
+
+const INITIAL_BACKOFF_MS: u64 = 40;
+const MAX_BACKOFF_MS: u64 = 1000;
+const MAX_ATTEMPTS: u32 = 10;
+
+// A change is sent again after initial_backoff, and the wait doubles up to
+// max_backoff. After max_attempts sends it is left to the periodic rebroadcast
+// of the whole table.
+#[derive(Debug, Clone, Copy)]
+pub struct ReliableConfig {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_attempts: u32,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> ReliableConfig {
+        ReliableConfig {
+            initial_backoff: Duration::from_millis(INITIAL_BACKOFF_MS),
+            max_backoff: Duration::from_millis(MAX_BACKOFF_MS),
+            max_attempts: MAX_ATTEMPTS,
+        }
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+struct Outstanding {
+    id: u64,
+    request: Request,
+    waiting_for: Vec<IP>,
+    attempts: u32,
+    backoff_ms: u64,
+    next_at: u64,
+}
+
+// Keeps track of which peers have acknowledged each announced state change.
+// Merging a request is idempotent, so the receivers need not filter out the
+// copies, only acknowledge every one of them.
+// Only hall requests go through it, as cab requests are never shared.
+pub struct ReliableChannel {
+    next_id: u64,
+    outstanding: Vec<Outstanding>,
+    config: ReliableConfig,
+}
+
+impl ReliableChannel {
+    pub fn new(config: ReliableConfig) -> Self {
+        ReliableChannel {
+            next_id: 1,
+            outstanding: Vec::new(),
+            config: config,
+        }
+    }
+
+    // Takes a change that is broadcast now, and gives the id the peers
+    // acknowledge it by. An older change to the same request is not waited for
+    // any longer, as the newer one carries it along.
+    pub fn send(&mut self, request: Request, peers: Vec<IP>, now: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.outstanding.retain(|outstanding| {
+            outstanding.request.floor != request.floor || outstanding.request.request_type != request.request_type
+        });
+        if !peers.is_empty() {
+            let backoff_ms = millis(self.config.initial_backoff);
+            self.outstanding.push(Outstanding {
+                id: id,
+                request: request,
+                waiting_for: peers,
+                attempts: 1,
+                backoff_ms: backoff_ms,
+                next_at: now + backoff_ms,
+            });
+        }
+        id
+    }
+
+    pub fn acknowledged(&mut self, id: u64, from: &IP) {
+        for outstanding in self.outstanding.iter_mut().filter(|outstanding| outstanding.id == id) {
+            outstanding.waiting_for.retain(|ip| ip != from);
+        }
+        self.outstanding.retain(|outstanding| !outstanding.waiting_for.is_empty());
+    }
+
+    // Lost peers are not waited for.
+    pub fn forget_peers(&mut self, lost: &[IP]) {
+        for outstanding in &mut self.outstanding {
+            outstanding.waiting_for.retain(|ip| !lost.contains(ip));
+        }
+        self.outstanding.retain(|outstanding| !outstanding.waiting_for.is_empty());
+    }
+
+    // The changes to send again by now, with their ids.
+    pub fn due(&mut self, now: u64) -> Vec<(u64, Request)> {
+        let max_attempts = self.config.max_attempts;
+        let max_backoff_ms = millis(self.config.max_backoff);
+
+        self.outstanding.retain(|outstanding| {
+            let given_up = outstanding.next_at <= now && outstanding.attempts >= max_attempts;
+            if given_up {
+                println!("No acknowledgement of {:?} at floor {} from {:?} after {} attempts",
+                         outstanding.request.request_type, outstanding.request.floor,
+                         outstanding.waiting_for, outstanding.attempts);
+            }
+            !given_up
+        });
+
+        let mut due = Vec::new();
+        for outstanding in self.outstanding.iter_mut().filter(|outstanding| outstanding.next_at <= now) {
+            outstanding.attempts += 1;
+            outstanding.backoff_ms = if outstanding.backoff_ms * 2 < max_backoff_ms { outstanding.backoff_ms * 2 } else { max_backoff_ms };
+            outstanding.next_at = now + outstanding.backoff_ms;
+            due.push((outstanding.id, outstanding.request.clone()));
+        }
+        due
+    }
+
+    // How many changes are still waiting for acknowledgements.
+    pub fn unacknowledged(&self) -> usize {
+        self.outstanding.len()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+    use request_handler::request::{Request, RequestType};
+
+    fn request(floor: usize, counter: u64) -> Request {
+        Request { floor: floor, request_type: RequestType::CallUp, counter: counter, ..Request::default() }
+    }
+
+    #[test]
+    fn retransmits_with_backoff_until_every_peer_acknowledges() {
+        let mut channel = ReliableChannel::new(ReliableConfig::default());
+        let (a, b) = ("10.0.0.2".to_string(), "10.0.0.3".to_string());
+
+        let id = channel.send(request(1, 1), vec![a.clone(), b.clone()], 0);
+        assert!(channel.due(39).is_empty());
+        assert_eq!(channel.due(40), vec![(id, request(1, 1))]);
+        // The wait has doubled.
+        assert!(channel.due(119).is_empty());
+        assert_eq!(channel.due(120).len(), 1);
+
+        channel.acknowledged(id, &a);
+        channel.acknowledged(id + 1, &b);
+        assert_eq!(channel.unacknowledged(), 1);
+        channel.acknowledged(id, &b);
+        assert_eq!(channel.unacknowledged(), 0);
+
+        // A newer change to the request replaces the older one, and a lost peer
+        // is not waited for.
+        channel.send(request(2, 1), vec![a.clone()], 1000);
+        let id = channel.send(request(2, 2), vec![a.clone(), b.clone()], 1000);
+        assert_eq!(channel.due(1040), vec![(id, request(2, 2))]);
+        channel.forget_peers(slice::from_ref(&a));
+        channel.acknowledged(id, &b);
+        assert_eq!(channel.unacknowledged(), 0);
+
+        // Without acknowledgements it gives up after the last attempt.
+        channel.send(request(3, 1), vec![a], 2000);
+        let mut now = 2000;
+        let mut sent = 1;
+        while channel.unacknowledged() > 0 {
+            now += 10;
+            sent += channel.due(now).len();
+        }
+        assert_eq!(sent, MAX_ATTEMPTS as usize);
+    }
+}
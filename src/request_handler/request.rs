@@ -1,101 +1,347 @@
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
-#![feature(type_ascription)]
-
-use rand;
-
-use std::thread;
-use std::sync::mpsc::{channel, Sender, Receiver};
-
-use rand::Rng;
-
-use network::localip::get_localip;
-use network::peer::{PeerTransmitter, PeerReceiver, PeerUpdate};
-use network::bcast::{BcastTransmitter, BcastReceiver};
-
-use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use self::RequestStatus::*;
 
-const PEER_PORT: u16 = 9877;
-const BCAST_PORT: u16 = 9876;
-
-const N_FLOORS: usize = 4;
-
 pub type IP = String;
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+// Peer ids are on the form "ip:unique". IPv6 addresses contain colons
+// themselves, so the id is split at the last one.
+pub fn peer_ip(peer_id: &str) -> IP {
+    match peer_id.rfind(':') {
+        Some(i) => peer_id[..i].to_string(),
+        None => peer_id.to_string(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[derive(Default)]
 pub enum RequestType {
     Internal = 2,
+    #[default]
     CallUp = 1,
     CallDown = 0,
 }
 
-impl Default for RequestType {
-    fn default() -> RequestType { RequestType::CallUp }
-}
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum RequestStatus {
     Active,
     Pending,
     Inactive,
-    Unknown
 }
 
-impl Default for RequestStatus {
-    fn default() -> RequestStatus { RequestStatus::Unknown }
+// Each request cycles through Inactive -> Pending -> Active -> Inactive -> ...,
+// and the counter counts every step it has taken. The status is the counter
+// modulo three, so a higher counter always means a later state, and merging two
+// copies is just taking the highest counter. Copies at the same counter agree on
+// the status and only differ in who has acknowledged it, so those are united.
+//
+// This merge is commutative, associative and idempotent, which makes the nodes
+// converge no matter how request messages are reordered, duplicated or lost.
+const PHASES: u64 = 3;
+
+// Milliseconds since the Unix epoch, which is what the request timestamps hold.
+pub fn now_ms() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64
+}
+
+fn earliest(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+        (a, None) => a,
+        (None, b) => b,
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct Request {
     pub floor: usize,
     pub request_type: RequestType,
-    pub status: RequestStatus,
+    pub counter: u64,
     pub acknowledged_by: Vec<IP>,
+    // When the current cycle was pressed, became active and was served. Copies
+    // at the same counter keep the earliest time any node saw.
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    #[serde(default)]
+    pub confirmed_at: Option<u64>,
+    #[serde(default)]
+    pub served_at: Option<u64>,
 }
 
 impl Request {
-    pub fn move_to_active(&mut self) -> RequestStatus {
-        self.status = Active;
-        Active
+    pub fn status(&self) -> RequestStatus {
+        match self.counter % PHASES {
+            0 => Inactive,
+            1 => Pending,
+            _ => Active,
+        }
     }
 
-    pub fn move_to_pending(&mut self) -> RequestStatus {
-        self.status = Pending;
-        Pending
+    pub fn is_active(&self) -> bool {
+        self.status() == Active
     }
 
-    pub fn move_to_inactive(&mut self) -> RequestStatus {
-        self.status = Inactive;
-        Inactive
+    // How long an unserved request has been waiting.
+    pub fn age(&self, now: u64) -> Option<Duration> {
+        match (self.status(), self.created_at) {
+            (Inactive, _) | (_, None) => None,
+            (_, Some(created_at)) => Some(Duration::from_millis(now.saturating_sub(created_at))),
+        }
+    }
+
+    // The time from press to service, once the request has been served.
+    pub fn wait_time(&self) -> Option<Duration> {
+        match (self.status(), self.created_at, self.served_at) {
+            (Inactive, Some(created_at), Some(served_at)) =>
+                Some(Duration::from_millis(served_at.saturating_sub(created_at))),
+            _ => None,
+        }
     }
 
-    pub fn handle_unknown_local(&mut self, remote: &Request) -> RequestStatus {
-        self.floor = remote.floor;
-        self.request_type = remote.request_type;
-        self.status = remote.status;
-        self.acknowledged_by = remote.acknowledged_by.clone();
+    fn step(&mut self, now: u64) {
+        self.counter += 1;
+        self.acknowledged_by.clear();
+        match self.status() {
+            Pending => {
+                self.created_at = Some(now);
+                self.confirmed_at = None;
+                self.served_at = None;
+            },
+            Active => self.confirmed_at = Some(now),
+            Inactive => self.served_at = Some(now),
+        }
+    }
 
-        self.status
+    // A button press. Only an inactive request can be pressed again.
+    pub fn press(&mut self, local_ip: IP, now: u64) -> RequestStatus {
+        if let Inactive = self.status() {
+            self.step(now);
+            self.acknowledge(local_ip);
+        }
+        self.status()
     }
 
-    pub fn update_acknowledgements(&mut self, peers: &Vec<String>, remote_ip: String) -> RequestStatus {
-        let ref mut acknowledged_by = self.acknowledged_by;
-        acknowledged_by.push(remote_ip);
-        acknowledged_by.sort();
-        acknowledged_by.dedup();
+    pub fn acknowledge(&mut self, ip: IP) {
+        if let Pending = self.status() {
+            if let Err(i) = self.acknowledged_by.binary_search(&ip) {
+                self.acknowledged_by.insert(i, ip);
+            }
+        }
+    }
 
-        // If all elevators have acknowledged, upgrade the request to active.
-        for addr in peers.iter() {
-            let ip = addr.split(":").next().unwrap().to_string();
-            if !acknowledged_by.contains(&ip) {
-                return Pending;
+    pub fn check_acknowledgements(&mut self, peers: &[String], now: u64) -> RequestStatus {
+        if let Pending = self.status() {
+            // If all elevators have acknowledged, upgrade the request to active.
+            for addr in peers.iter() {
+                let ip = peer_ip(addr);
+                if !self.acknowledged_by.contains(&ip) {
+                    return Pending;
+                }
             }
+            self.step(now);
         }
+        self.status()
+    }
 
-        self.status = Active;
+    // Makes the request active without waiting for acknowledgements.
+    pub fn activate(&mut self, now: u64) -> RequestStatus {
+        while !self.is_active() {
+            self.step(now);
+        }
         Active
     }
+
+    pub fn clear(&mut self, now: u64) -> RequestStatus {
+        while self.status() != Inactive {
+            self.step(now);
+        }
+        Inactive
+    }
+
+    pub fn merge(&mut self, remote: &Request) {
+        if remote.counter > self.counter {
+            self.counter = remote.counter;
+            self.acknowledged_by = remote.acknowledged_by.clone();
+            self.created_at = remote.created_at;
+            self.confirmed_at = remote.confirmed_at;
+            self.served_at = remote.served_at;
+        } else if remote.counter == self.counter {
+            for ip in &remote.acknowledged_by {
+                self.acknowledge(ip.clone());
+            }
+            self.created_at = earliest(self.created_at, remote.created_at);
+            self.confirmed_at = earliest(self.confirmed_at, remote.confirmed_at);
+            self.served_at = earliest(self.served_at, remote.served_at);
+        }
+    }
+}
+
+// A hall request that was assigned to a lost peer, and the node that takes it over.
+#[derive(Debug, Clone)]
+pub struct Reassignment {
+    pub floor: usize,
+    pub request_type: RequestType,
+    pub from: IP,
+    pub to: IP,
+}
+
+impl fmt::Display for Reassignment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reassigned {:?} at floor {} from {} to {}", self.request_type, self.floor, self.from, self.to)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PeerChanges {
+    pub reassigned: Vec<Reassignment>,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use rand::{thread_rng, Rng, SeedableRng, XorShiftRng};
+
+    const IPS: [&str; 3] = ["10.0.0.1", "10.0.0.2", "10.0.0.3"];
+
+    // Random unless ELEVATOR_TEST_SEED is set, and reported with every failure so
+    // the failing run can be repeated.
+    fn seed() -> u32 {
+        match env::var("ELEVATOR_TEST_SEED").ok().and_then(|seed| seed.parse().ok()) {
+            Some(seed) => seed,
+            None => thread_rng().gen(),
+        }
+    }
+
+    fn seeded(seed: u32) -> XorShiftRng {
+        XorShiftRng::from_seed([seed, seed ^ 0x9e37_79b9, 0x85eb_ca6b, 0xc2b2_ae35])
+    }
+
+    fn peers() -> Vec<String> {
+        IPS.iter().map(|ip| format!("{}:1", ip)).collect()
+    }
+
+    fn random_ip(rng: &mut XorShiftRng) -> IP {
+        rng.choose(&IPS).unwrap().to_string()
+    }
+
+    // Applies one of the operations a node can do locally.
+    fn random_operation(request: &mut Request, rng: &mut XorShiftRng) {
+        let now = rng.gen_range(0, 1000);
+        match rng.gen_range(0, 4) {
+            0 => { request.press(random_ip(rng), now); },
+            1 => request.acknowledge(random_ip(rng)),
+            2 => { request.check_acknowledgements(&peers(), now); },
+            _ => { request.clear(now); },
+        }
+    }
+
+    fn random_request(rng: &mut XorShiftRng) -> Request {
+        let mut request = Request::default();
+        for _ in 0..rng.gen_range(0, 12) {
+            random_operation(&mut request, rng);
+        }
+        request
+    }
+
+    fn merged(a: &Request, b: &Request) -> Request {
+        let mut result = a.clone();
+        result.merge(b);
+        result
+    }
+
+    #[test]
+    fn merge_is_commutative_associative_and_idempotent() {
+        let seed = seed();
+        let mut rng = seeded(seed);
+        for _ in 0..10_000 {
+            let a = random_request(&mut rng);
+            let b = random_request(&mut rng);
+            let c = random_request(&mut rng);
+
+            assert_eq!(merged(&a, &b), merged(&b, &a), "seed {}", seed);
+            assert_eq!(merged(&merged(&a, &b), &c), merged(&a, &merged(&b, &c)), "seed {}", seed);
+            assert_eq!(merged(&a, &a), a, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn replicas_converge_under_reordering_duplication_and_loss() {
+        let seed = seed();
+        let mut rng = seeded(seed);
+        for _ in 0..1_000 {
+            let mut replicas = vec![Request::default(); IPS.len()];
+            let mut in_flight: Vec<(usize, Request)> = Vec::new();
+
+            for _ in 0..rng.gen_range(0, 40) {
+                if rng.gen() || in_flight.is_empty() {
+                    // A local change, broadcast to the other replicas.
+                    let node = rng.gen_range(0, replicas.len());
+                    random_operation(&mut replicas[node], &mut rng);
+                    for other in 0..replicas.len() {
+                        if other != node {
+                            in_flight.push((other, replicas[node].clone()));
+                        }
+                    }
+                } else {
+                    // Deliver, drop or duplicate a message in random order.
+                    rng.shuffle(&mut in_flight);
+                    let (node, message) = in_flight.pop().unwrap();
+                    match rng.gen_range(0, 4) {
+                        0 => {},
+                        1 => in_flight.push((node, message.clone())),
+                        _ => replicas[node].merge(&message),
+                    }
+                }
+            }
+
+            // One round of anti-entropy is enough to agree.
+            let snapshot = replicas.clone();
+            for replica in replicas.iter_mut() {
+                for state in &snapshot {
+                    replica.merge(state);
+                }
+            }
+            for replica in &replicas {
+                assert_eq!(*replica, replicas[0], "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn press_during_partition_is_not_lost() {
+        let mut a = Request::default();
+        a.activate(0);
+        let mut b = a.clone();
+
+        // a serves the request and is pressed again while cut off from b.
+        a.clear(10);
+        a.press("10.0.0.1".to_string(), 20);
+
+        b.merge(&a);
+        assert_eq!(b.status(), Pending);
+        a.merge(&b);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn tracks_request_times() {
+        let mut request = Request::default();
+        request.press("10.0.0.1".to_string(), 1000);
+        assert_eq!(request.age(1500), Some(Duration::from_millis(500)));
+
+        request.check_acknowledgements(&["10.0.0.1:1".to_string()], 1200);
+        assert_eq!(request.confirmed_at, Some(1200));
+
+        request.clear(4000);
+        assert_eq!(request.age(5000), None);
+        assert_eq!(request.wait_time(), Some(Duration::from_millis(3000)));
+    }
 }
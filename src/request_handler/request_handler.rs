@@ -1,31 +1,55 @@
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
-#![feature(type_ascription)]
-
-use rand;
-use rand::Rng;
-
 use std::rc::Rc;
+use std::slice;
+use std::sync::Arc;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
-use std::sync::mpsc::{channel, Sender, Receiver};
 
-use elevator_driver::elev_io::{N_FLOORS, Floor, Button, MotorDir, Light};
+use elevator_driver::elev_io::{N_FLOORS, Floor, Button, MotorDir};
 
-use network::localip::get_localip;
-use network::peer::{PeerTransmitter, PeerReceiver, PeerUpdate};
-use network::bcast::{BcastTransmitter, BcastReceiver};
+use network::peer::PeerUpdate;
+use network::membership::Membership;
 
 use request_handler::request::*;
 use request_handler::request::RequestStatus::*;
 use request_handler::request_transmitter::*;
+use request_handler::reliable::{ReliableChannel, ReliableConfig};
+use request_handler::sync::{self, RowVersion};
+use metrics::metrics;
+use clock::clock::{self, Clock};
+
 
+const STARVATION_THRESHOLD_S: u64 = 60;
+// Versions are sent at most this often, however many digests differ.
+const VERSIONS_INTERVAL_MS: u64 = 50;
 
 pub struct RequestHandler {
     pub requests: Vec<Vec<Request>>,
-    peers: Vec<String>,
+    membership: Membership<String>,
     peer_positions: HashMap<IP, usize>,
     request_transmitter: Rc<RequestTransmitter>,
+    starvation_threshold: Duration,
+    alarmed: Vec<(usize, usize, u64)>,
+    // When this node first saw each request waiting, by type and floor, with the
+    // cycle it was in. The creation times come from other nodes' clocks, so they
+    // are no good for telling how long a request has waited.
+    waiting_since: HashMap<(usize, usize), (u64, Instant)>,
+    clock: Arc<dyn Clock>,
+    local_ip: IP,
+    // Announced changes are retransmitted until acknowledged when set.
+    reliable: Option<ReliableChannel>,
+    // Digests instead of the whole table every tick when set.
+    anti_entropy: bool,
+    versions_sent_at: Option<u64>,
+    // Whether a peer update was applied yet. Before that, no peers only means
+    // they have not been heard from.
+    peers_known: bool,
+    // The peers' cab requests, handed back to a peer that rejoins, as it
+    // lost its own if it restarted.
+    peer_cab_requests: HashMap<IP, Vec<Request>>,
 }
 
 impl RequestHandler {
@@ -40,35 +64,260 @@ impl RequestHandler {
                 let request = Request {floor: floor, request_type: t, ..Request::default()};
                 rs.push(request);
             }
-            requests[t: RequestType as usize] = rs;
+            requests[t as usize] = rs;
         }
+        let local_ip = request_transmitter.local_ip().to_string();
 
         RequestHandler {
             requests: requests,
-            peers: Vec::new(),
+            membership: Membership::new(),
             peer_positions: HashMap::new(),
             request_transmitter: request_transmitter,
+            starvation_threshold: Duration::from_secs(STARVATION_THRESHOLD_S),
+            alarmed: Vec::new(),
+            waiting_since: HashMap::new(),
+            clock: clock::real(),
+            local_ip: local_ip,
+            reliable: None,
+            anti_entropy: false,
+            versions_sent_at: None,
+            peers_known: false,
+            peer_cab_requests: HashMap::new(),
         }
     }
 
-    pub fn handle_peer_update(&mut self, peers: PeerUpdate<String>) {
-        self.peers = peers.peers;
+    pub fn handle_peer_update(&mut self, update: PeerUpdate<String>, local_position: usize) -> PeerChanges {
+        self.membership.apply(&update);
+        let first_update = !self.peers_known;
+        self.peers_known = true;
+        let mut changes = PeerChanges::default();
+
+        let lost_ips: Vec<IP> = update.lost.iter().map(|peer_addr| peer_ip(peer_addr)).collect();
+
+        if !lost_ips.is_empty() {
+            // Find the hall requests the lost peers were going to serve, before
+            // their positions are forgotten.
+            let mut orphaned = Vec::new();
+            for request in self.hall_requests() {
+                if request.is_active() {
+                    let owner = self.assignee(&request, local_position);
+                    if lost_ips.contains(&owner) {
+                        orphaned.push((request, owner));
+                    }
+                }
+            }
 
-        for peer_addr in peers.lost {
-            let ip = peer_addr.split(":").next().unwrap().to_string();
-            self.peer_positions.remove(&ip);
+            for ip in &lost_ips {
+                self.peer_positions.remove(ip);
+            }
+            if let Some(ref mut reliable) = self.reliable {
+                reliable.forget_peers(&lost_ips);
+            }
+            self.request_transmitter.forget_peers(&lost_ips);
+
+            for (request, owner) in orphaned {
+                changes.reassigned.push(Reassignment {
+                    floor: request.floor,
+                    request_type: request.request_type,
+                    from: owner,
+                    to: self.assignee(&request, local_position),
+                });
+            }
         }
 
-        if let Some(peer_addr) = peers.new {
-            let ip = peer_addr.split(":").next().unwrap().to_string();
+        // Pending requests no longer wait for the lost peers. Without any peers
+        // left, or none in the first update, they are accepted locally.
+        if !lost_ips.is_empty() || first_update {
+            let peers = self.membership.peers();
+            let offline = self.is_offline();
+            let now = self.clock.now_ms();
+            for request in self.requests.iter_mut().flat_map(|rs| rs.iter_mut()) {
+                if let Pending = request.status() {
+                    if offline {
+                        request.activate(now);
+                    } else {
+                        request.check_acknowledgements(&peers, now);
+                    }
+                }
+            }
+        }
+
+        for peer_addr in &update.new {
+            let ip = peer_ip(peer_addr);
             self.peer_positions.insert(ip, 0);
         }
+
+        // Joining and rejoining peers get the whole table right away instead of
+        // waiting for the next broadcast tick, over the sidechannel if there is one.
+        // Requests accepted while offline have the highest counters for their
+        // buttons, so the peers that came back adopt them from it.
+        if !update.new.is_empty() {
+            let local_ip = self.local_ip.clone();
+            let joined: Vec<IP> = update.new.iter()
+                .map(|peer| peer_ip(peer))
+                .filter(|ip| *ip != local_ip)
+                .collect();
+            self.push_table(&joined);
+            for ip in &joined {
+                if let Some(requests) = self.peer_cab_requests.get(ip) {
+                    self.request_transmitter.announce_cab_requests(ip.clone(), requests.clone());
+                }
+            }
+        }
+
+        self.note_waiting();
+        changes
+    }
+
+    // For a node that comes back to the peer set it never dropped out of, such
+    // as an elevator that was stuck. Its peers kept it and see nothing new, so
+    // it hands them its table itself.
+    pub fn resync_with_peers(&mut self) {
+        let peers = self.remote_peer_ips();
+        self.push_table(&peers);
+    }
+
+    // What the sidechannel could not deliver is broadcast instead. The table
+    // is sent as it is now, which carries the failed one along. Logs and
+    // settings are not the request handler's.
+    pub fn handle_transfer_failure(&mut self, transfer: Transfer) {
+        match transfer {
+            Transfer::Requests(_) => self.announce_all_requests(),
+            Transfer::EventLog(_) | Transfer::Settings(_) => {},
+        }
+    }
+
+    fn push_table(&mut self, to: &[IP]) {
+        let table = self.hall_requests();
+        let transferred = !to.is_empty() && to.iter()
+            .all(|ip| self.request_transmitter.transfer(ip, Transfer::Requests(table.clone())));
+        if !transferred {
+            self.announce_all_requests();
+        }
+    }
+
+    pub fn membership(&self) -> &Membership<String> {
+        &self.membership
     }
 
+    // Offline when the network could not be set up, or when the peer updates
+    // show no other node. Requests are then accepted and served locally.
+    pub fn is_offline(&self) -> bool {
+        if self.request_transmitter.is_offline() {
+            return true;
+        }
+        let local_ip = self.local_ip.clone();
+        self.peers_known && !self.membership.peers().iter().any(|peer| peer_ip(peer) != local_ip)
+    }
+
+    pub fn set_starvation_threshold(&mut self, threshold: Duration) {
+        self.starvation_threshold = threshold;
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.membership.set_clock(clock.clone());
+        self.clock = clock;
+    }
+
+    pub fn enable_reliable_delivery(&mut self, config: ReliableConfig) {
+        self.reliable = Some(ReliableChannel::new(config));
+    }
+
+    pub fn enable_anti_entropy(&mut self) {
+        self.anti_entropy = true;
+    }
+
+    pub fn unacknowledged(&self) -> usize {
+        self.reliable.as_ref().map_or(0, |reliable| reliable.unacknowledged())
+    }
+
+    // Notes the requests that started waiting since the last time, and forgets
+    // the ones that were served. Done after every change to the table.
+    fn note_waiting(&mut self) {
+        let now = self.clock.now();
+        for request in self.requests.iter().flat_map(|rs| rs.iter()) {
+            let key = (request.request_type as usize, request.floor);
+            if let Inactive = request.status() {
+                self.waiting_since.remove(&key);
+                continue;
+            }
+            // Pending and active belong to the same cycle.
+            let cycle = request.counter / 3;
+            let known = match self.waiting_since.get(&key) {
+                Some(&(since_cycle, _)) => since_cycle == cycle,
+                None => false,
+            };
+            if !known {
+                self.waiting_since.insert(key, (cycle, now));
+            }
+        }
+    }
+
+    // How long the request has waited, as far as this node has seen.
+    pub fn waited(&self, request: &Request) -> Option<Duration> {
+        let key = (request.request_type as usize, request.floor);
+        match (request.status(), self.waiting_since.get(&key)) {
+            (Inactive, _) | (_, None) => None,
+            (_, Some(&(_, since))) => Some(self.clock.now().duration_since(since)),
+        }
+    }
+
+    fn is_starved(&self, request: &Request) -> bool {
+        match self.waited(request) {
+            Some(waited) => waited > self.starvation_threshold,
+            None => false,
+        }
+    }
+
+    // Returns the requests that have started starving since the last check.
+    // Starving hall requests are served by every elevator until one gets there.
+    pub fn check_starvation(&mut self) -> Vec<Request> {
+        self.note_waiting();
+        let mut starving = Vec::new();
+
+        for request in self.requests.iter().flat_map(|rs| rs.iter()) {
+            if !self.is_starved(request) {
+                continue;
+            }
+            let key = (request.request_type as usize, request.floor, request.counter);
+            if !self.alarmed.contains(&key) {
+                starving.push(request.clone());
+            }
+        }
+
+        for request in &starving {
+            self.alarmed.push((request.request_type as usize, request.floor, request.counter));
+            metrics::record_starvation();
+        }
+
+        // Forget alarms for requests that have moved on.
+        let requests = &self.requests;
+        self.alarmed.retain(|&(request_type, floor, counter)| requests[request_type][floor].counter == counter);
+
+        starving
+    }
+
+    // Only peers in the peer set are given calls. A car that left it, or was
+    // lost, keeps its position out until it is back.
     pub fn handle_position_update(&mut self, remote_ip: IP, position: usize) {
+        if !self.remote_peer_ips().contains(&remote_ip) {
+            return;
+        }
         self.peer_positions.insert(remote_ip, position);
     }
 
+    pub fn peer_position(&self, remote_ip: &IP) -> Option<usize> {
+        self.peer_positions.get(remote_ip).cloned()
+    }
+
+    pub fn peer_positions(&self) -> &HashMap<IP, usize> {
+        &self.peer_positions
+    }
+
+    pub fn restore_peer_positions(&mut self, peer_positions: HashMap<IP, usize>) {
+        self.peer_positions = peer_positions;
+    }
+
     fn get_local_request(&mut self, remote_request: &Request) -> &mut Request {
         let floor = remote_request.floor;
         let request_type = remote_request.request_type as usize;
@@ -80,51 +329,152 @@ impl RequestHandler {
         self.requests[RequestType::Internal as usize].clone()
     }
 
-    pub fn merge_incoming_request(&mut self, remote_request: &Request, remote_ip: IP) -> Option<Light> {
-        let peers = self.peers.clone();
+    pub fn add_internal_request(&mut self, floor: usize) {
+        // Cab requests only concern this elevator, so they need no agreement.
+        // The peers are told anyway, to keep a copy.
+        let now = self.clock.now_ms();
+        let request = {
+            let local_request = &mut self.requests[RequestType::Internal as usize][floor];
+            if local_request.is_active() {
+                return;
+            }
+            local_request.activate(now);
+            local_request.clone()
+        };
+        self.note_waiting();
+        self.announce_request(request);
+    }
 
-        let ref mut local_request = self.get_local_request(&remote_request);
+    pub fn is_local(&self, ip: &IP) -> bool {
+        *ip == self.local_ip
+    }
 
-        let local_status = local_request.status.clone();
-        let remote_status = remote_request.status;
+    // Cab requests of the peer at owner.
+    pub fn keep_cab_requests(&mut self, owner: IP, requests: &[Request]) {
+        let copy = self.peer_cab_requests.entry(owner).or_insert_with(|| {
+            (0..N_FLOORS)
+                .map(|floor| Request {floor: floor, request_type: RequestType::Internal, ..Request::default()})
+                .collect()
+        });
+        for request in requests {
+            if request.request_type == RequestType::Internal && request.floor < N_FLOORS {
+                copy[request.floor].merge(request);
+            }
+        }
+    }
 
-        let new_status = match (local_status, remote_status) {
-            (Active, Inactive)  => local_request.move_to_inactive(),
-            (Inactive, Pending) => local_request.move_to_pending(),
-            (Pending, Active)   => local_request.move_to_active(),
-            (Pending, Pending)  => local_request.update_acknowledgements(&peers, remote_ip),
-            (Unknown, _)        => local_request.handle_unknown_local(&remote_request),
-            _                   => return None,
-        };
+    // Cab requests of this node that a peer kept. Those that were made or
+    // cleared after the copy have higher counters here and stay as they are.
+    pub fn restore_cab_requests(&mut self, requests: &[Request]) {
+        for request in requests {
+            if request.request_type == RequestType::Internal && request.floor < N_FLOORS {
+                self.requests[RequestType::Internal as usize][request.floor].merge(request);
+            }
+        }
+        self.note_waiting();
+    }
 
-        // The return value determines if the button light needs to be turned on.
-        match (local_status, new_status) {
-            (Pending, Active)   => return Some(Light::On),
-            (Active, Inactive)  => return Some(Light::Off),
-            _                   => return None,
+    pub fn merge_incoming_request(&mut self, remote_request: &Request, remote_ip: IP) {
+        if let RequestType::Internal = remote_request.request_type {
+            self.keep_cab_requests(remote_ip, slice::from_ref(remote_request));
+            return;
         }
+
+        let peers = self.membership.peers();
+        let local_ip = self.local_ip.clone();
+        let now = self.clock.now_ms();
+
+        let local_request = &mut self.get_local_request(remote_request);
+
+        local_request.merge(remote_request);
+        local_request.acknowledge(local_ip);
+        local_request.acknowledge(remote_ip);
+        local_request.check_acknowledgements(&peers, now);
+        self.note_waiting();
     }
 
     fn announce_request(&mut self, request: Request) {
-        self.request_transmitter.announce_request(request);
+        let now = self.clock.now_ms();
+        let peers = self.remote_peer_ips();
+        match self.reliable {
+            Some(ref mut reliable) => {
+                let id = reliable.send(request.clone(), peers, now);
+                self.request_transmitter.announce_reliable(id, request);
+            },
+            None => self.request_transmitter.announce_request(request),
+        }
+    }
+
+    fn remote_peer_ips(&self) -> Vec<IP> {
+        let mut ips: Vec<IP> = self.membership.peers().iter()
+            .map(|peer| peer_ip(peer))
+            .filter(|ip| *ip != self.local_ip)
+            .collect();
+        ips.sort();
+        ips.dedup();
+        ips
+    }
+
+    pub fn retransmit_unacknowledged(&mut self) {
+        let now = self.clock.now_ms();
+        let due = match self.reliable {
+            Some(ref mut reliable) => reliable.due(now),
+            None => return,
+        };
+        for (id, request) in due {
+            self.request_transmitter.resend_reliable(id, request);
+        }
+    }
+
+    // Every copy of a reliable message is acknowledged, as an earlier
+    // acknowledgement may have been lost. Nodes without reliable delivery
+    // acknowledge too.
+    pub fn acknowledge_delivery(&self, id: u64, remote_ip: IP) {
+        if remote_ip != self.local_ip {
+            self.request_transmitter.acknowledge(id, remote_ip);
+        }
+    }
+
+    pub fn handle_ack(&mut self, id: u64, to: &IP, remote_ip: IP) {
+        if *to != self.local_ip {
+            return;
+        }
+        if let Some(ref mut reliable) = self.reliable {
+            reliable.acknowledged(id, &remote_ip);
+        }
     }
 
     pub fn announce_new_request(&mut self, button: &Button) {
-        let (request_type, floor) = match button {
-            &Button::Internal(Floor::At(floor)) => (RequestType::Internal,  floor),
-            &Button::CallUp(Floor::At(floor))   => (RequestType::CallUp,    floor),
-            &Button::CallDown(Floor::At(floor)) => (RequestType::CallDown,  floor),
+        let (request_type, floor) = match *button {
+            Button::Internal(Floor::At(floor)) => (RequestType::Internal,  floor),
+            Button::CallUp(Floor::At(floor))   => (RequestType::CallUp,    floor),
+            Button::CallDown(Floor::At(floor)) => (RequestType::CallDown,  floor),
             _                                   => return,
         };
 
-        let request = Request {
-            floor: floor,
-            request_type: request_type,
-            status: Pending,
-            ..Request::default()
+        let offline = self.is_offline();
+        let local_ip = self.local_ip.clone();
+        let now = self.clock.now_ms();
+
+        let request = {
+            let local_request = &mut self.requests[request_type as usize][floor];
+            if local_request.is_active() {
+                return;
+            }
+
+            if offline {
+                local_request.activate(now);
+                None
+            } else {
+                local_request.press(local_ip, now);
+                Some(local_request.clone())
+            }
         };
 
-        self.announce_request(request);
+        self.note_waiting();
+        if let Some(request) = request {
+            self.announce_request(request);
+        }
     }
 
     pub fn announce_requests_cleared(&mut self, floor: usize, direction: MotorDir) {
@@ -135,30 +485,65 @@ impl RequestHandler {
             _               => unreachable!(),
         };
 
-        let hall_request = Request {
-            floor: floor,
-            request_type: hall_request_type,
-            status: Inactive,
-            ..Request::default()
-        };
-
-        let internal_request = Request {
-            floor: floor,
-            request_type: RequestType::Internal,
-            status: Inactive,
-            ..Request::default()
-        };
+        let now = self.clock.now_ms();
+        for &request_type in [RequestType::Internal, hall_request_type].iter() {
+            let request = &mut self.requests[request_type as usize][floor];
+            if !request.is_active() {
+                request.clear(now);
+                continue;
+            }
+            request.clear(now);
+            if let Some(wait) = request.wait_time() {
+                metrics::record_served(wait);
+            }
+        }
 
-        self.announce_request(internal_request);
-        self.announce_request(hall_request);
+        for &request_type in [RequestType::Internal, hall_request_type].iter() {
+            let request = self.requests[request_type as usize][floor].clone();
+            self.announce_request(request);
+        }
     }
 
     pub fn announce_all_requests(&mut self) {
-        let call_up = &self.requests[RequestType::CallUp as usize];
-        let call_down = &self.requests[RequestType::CallDown as usize];
+        for request in self.hall_requests() {
+            self.request_transmitter.rebroadcast_request(request);
+        }
+    }
+
+    // Done every broadcast tick, so peers that missed a change catch up.
+    pub fn sync_requests(&mut self) {
+        let internal_requests = self.get_internal_requests();
+        self.request_transmitter.announce_cab_requests(self.local_ip.clone(), internal_requests);
+        if self.anti_entropy {
+            self.request_transmitter.announce_digest(self.digest());
+        } else {
+            self.announce_all_requests();
+        }
+    }
 
-        for request in call_up.iter().chain(call_down.iter()) {
-            self.request_transmitter.announce_request(request.clone());
+    pub fn digest(&self) -> u64 {
+        sync::digest(&sync::hall_versions(&self.requests))
+    }
+
+    pub fn handle_digest(&mut self, digest: u64) {
+        if digest == self.digest() {
+            return;
+        }
+        let now = self.clock.now_ms();
+        if let Some(sent_at) = self.versions_sent_at {
+            if now.saturating_sub(sent_at) < VERSIONS_INTERVAL_MS {
+                return;
+            }
+        }
+        self.versions_sent_at = Some(now);
+        self.request_transmitter.announce_versions(sync::hall_versions(&self.requests));
+    }
+
+    // Sends the rows the remote node is behind on. The rows it is ahead on come
+    // from its own answer to our digest.
+    pub fn handle_versions(&mut self, versions: &[RowVersion]) {
+        for request in sync::rows_ahead(&self.requests, versions) {
+            self.request_transmitter.rebroadcast_request(request);
         }
     }
 
@@ -180,7 +565,7 @@ impl RequestHandler {
             _               => unreachable!(),
         };
 
-        if let Active = request_opposite.status {
+        if request_opposite.is_active() {
             return true;
         }
 
@@ -202,19 +587,13 @@ impl RequestHandler {
             _               => unreachable!(),
         };
 
-        let internal_is_requested = match internal_requests[floor].status {
-            Active  => true,
-            _       => false,
-        };
+        let internal_is_requested = internal_requests[floor].is_active();
 
-        let hall_is_requested = match hall_requests[floor].status {
-            Active  => true,
-            _       => false,
-        };
+        let hall_is_requested = hall_requests[floor].is_active();
 
-        let should_stop = internal_is_requested || hall_is_requested;
+        
 
-        should_stop
+        internal_is_requested || hall_is_requested
     }
 
     fn requests_in_direction(&self, floor: usize, direction: MotorDir) -> bool {
@@ -229,64 +608,162 @@ impl RequestHandler {
         };
 
         let i_iter = requests_internal .iter().skip(lower_bound).take(num_elements);
-        let u_iter = requests_up       .iter().skip(lower_bound).take(num_elements).filter(|request| self.request_is_assigned_locally(&request, floor));
-        let d_iter = requests_down     .iter().skip(lower_bound).take(num_elements).filter(|request| self.request_is_assigned_locally(&request, floor));
+        let u_iter = requests_up       .iter().skip(lower_bound).take(num_elements).filter(|request| self.request_is_assigned_locally(request, floor));
+        let d_iter = requests_down     .iter().skip(lower_bound).take(num_elements).filter(|request| self.request_is_assigned_locally(request, floor));
 
         let requests = i_iter.chain(u_iter).chain(d_iter);
 
         requests
-            .filter(|request| self.request_is_ordered(&request))
+            .filter(|request| self.request_is_ordered(request))
             .count() > 0
     }
 
     fn request_is_ordered(&self, request: &Request) -> bool {
-        if let Active = request.status {
-            return true;
-        } else {
-            return false;
-        }
+        request.is_active()
     }
 
     fn calculate_cost(&self, request: &Request, position: usize) -> usize {
         let cost = (request.floor as isize) - (position as isize);
 
         if cost < 0 {
-            return (cost*-1) as usize;
+            return -cost as usize;
         }
 
-        return cost as usize;
+        cost as usize
     }
 
-    fn request_is_assigned_locally(&self, request: &Request, local_position: usize) -> bool {
-        let local_cost = self.calculate_cost(&request, local_position);
+    fn hall_requests(&self) -> Vec<Request> {
+        let call_up = &self.requests[RequestType::CallUp as usize];
+        let call_down = &self.requests[RequestType::CallDown as usize];
+
+        call_up.iter().chain(call_down.iter()).cloned().collect()
+    }
+
+    // The node closest to the request serves it. Ties go to the lowest address.
+    fn assignee(&self, request: &Request, local_position: usize) -> IP {
+        let local_ip = self.local_ip.clone();
 
-        let local_ip = &get_localip().unwrap().to_string();
-        let mut min_peer_ip = &get_localip().unwrap().to_string();
-        let mut min_peer_cost = 2*N_FLOORS;
+        let mut min_ip = local_ip.clone();
+        let mut min_cost = self.calculate_cost(request, local_position);
 
         for (peer, position) in &self.peer_positions {
-            if peer != local_ip {
-                let cost = self.calculate_cost(&request, *position);
-                if cost < min_peer_cost {
-                    min_peer_ip = &peer;
-                    min_peer_cost = cost
-                }
+            if *peer == local_ip {
+                continue;
+            }
+            let cost = self.calculate_cost(request, *position);
+            if cost < min_cost || (cost == min_cost && address_below(peer, &min_ip)) {
+                min_ip = peer.clone();
+                min_cost = cost;
             }
         }
 
-        if local_cost == min_peer_cost {
-            let ip_to_cost = |ip: &IP| {
-                ip
-                    .split(":").next().unwrap()
-                    .split(".").skip(3).take(1).next().unwrap()
-                    .to_string()
-            };
-            let local: i32 = str::parse(&ip_to_cost(local_ip)).unwrap();
-            let remote: i32 = str::parse(&ip_to_cost(min_peer_ip)).unwrap();
+        min_ip
+    }
 
-            return local <= remote;
+    fn request_is_assigned_locally(&self, request: &Request, local_position: usize) -> bool {
+        if self.is_starved(request) {
+            return true;
         }
+        self.assignee(request, local_position) == self.local_ip
+    }
+}
+
+fn address_below(ip: &IP, other: &IP) -> bool {
+    // Compares the addresses themselves, which works for both IPv4 and IPv6.
+    let ip: IpAddr = ip.parse().unwrap();
+    let other: IpAddr = other.parse().unwrap();
+
+    ip < other
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use request_handler::request::{Request, RequestType};
+    use request_handler::request_transmitter::RequestTransmitter;
+    use shutdown::shutdown::Shutdown;
+    use clock::clock::ManualClock;
+
+    #[test]
+    fn starvation_is_timed_by_the_local_clock() {
+        let request_transmitter = Rc::new(RequestTransmitter::detached("10.0.0.8".parse().unwrap(), Shutdown::new()));
+        let mut handler = RequestHandler::new(request_transmitter);
+        let clock = ManualClock::new();
+        handler.set_clock(Arc::new(clock.clone()));
+        handler.set_starvation_threshold(Duration::from_secs(10));
+
+        // Pressed at a node whose clock is far behind.
+        let remote = Request {
+            floor: 1,
+            request_type: RequestType::CallUp,
+            counter: 2,
+            created_at: Some(0),
+            ..Request::default()
+        };
+        handler.merge_incoming_request(&remote, "10.0.0.9".to_string());
+        assert!(handler.check_starvation().is_empty());
+
+        clock.advance(Duration::from_secs(11));
+        let starving = handler.check_starvation();
+        assert_eq!(starving.len(), 1);
+        assert_eq!(handler.waited(&starving[0]), Some(Duration::from_secs(11)));
+        assert!(handler.check_starvation().is_empty());
+    }
+
+    #[test]
+    fn online_until_the_first_update_shows_no_peers() {
+        let request_transmitter = Rc::new(RequestTransmitter::detached("10.0.0.8".parse().unwrap(), Shutdown::new()));
+        let mut handler = RequestHandler::new(request_transmitter);
+        assert!(!handler.is_offline());
+
+        // Pressed before the peers were heard from, so it waits for them.
+        handler.announce_new_request(&Button::CallUp(Floor::At(1)));
+        assert_eq!(handler.requests[RequestType::CallUp as usize][1].status(), Pending);
+
+        let mut update = PeerUpdate::new();
+        update.add_peers("10.0.0.8:a".to_string());
+        update.add_new("10.0.0.8:a".to_string());
+        handler.handle_peer_update(update, 0);
+        assert!(handler.is_offline());
+        assert_eq!(handler.requests[RequestType::CallUp as usize][1].status(), Active);
+    }
 
-        local_cost <= min_peer_cost
+    #[test]
+    fn hands_a_rejoining_peer_its_cab_requests() {
+        let (request_transmitter, outgoing) = RequestTransmitter::in_memory("10.0.0.8".parse().unwrap(), Shutdown::new());
+        let mut handler = RequestHandler::new(Rc::new(request_transmitter));
+
+        let cab_request = Request {
+            floor: 2,
+            request_type: RequestType::Internal,
+            counter: 2,
+            created_at: Some(1_760_000_000_000),
+            confirmed_at: Some(1_760_000_000_000),
+            ..Request::default()
+        };
+        handler.merge_incoming_request(&cab_request, "10.0.0.9".to_string());
+        assert!(!handler.requests[RequestType::Internal as usize][2].is_active());
+
+        let mut update = PeerUpdate::new();
+        update.add_peers("10.0.0.9:a".to_string());
+        update.add_new("10.0.0.9:a".to_string());
+        handler.handle_peer_update(update, 0);
+
+        let handed_back: Vec<BroadcastMessage> = outgoing.try_iter()
+            .filter(|message| match *message {
+                BroadcastMessage::CabRequests { .. } => true,
+                _ => false,
+            })
+            .collect();
+        assert_eq!(handed_back.len(), 1);
+        assert!(serde_json::to_string(&handed_back[0]).unwrap().len() < 1024);
+        if let BroadcastMessage::CabRequests { ref owner, ref requests } = handed_back[0] {
+            assert_eq!(owner, "10.0.0.9");
+            assert_eq!(requests[2], cab_request);
+        }
     }
 }
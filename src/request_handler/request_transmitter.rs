@@ -1,84 +1,540 @@
 use rand;
 use rand::Rng;
 
-use std::rc::Rc;
+use std::io;
 use std::thread;
-use std::collections::HashMap;
+use std::thread::JoinHandle;
+use std::sync::Mutex;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::mpsc::{channel, Sender, Receiver};
 
-use elevator_driver::elev_io::{N_FLOORS, Floor, Button, MotorDir, Light};
-
-use network::localip::get_localip;
-use network::peer::{PeerTransmitter, PeerReceiver, PeerUpdate};
+use network::localip::{get_localip, resolve_localip, set_localip};
+use network::socket::NetMode;
+use network::transport::{Transport, TransportKind};
+use network::fault::FaultInjector;
+use network::sidechannel::{ConnectionPool, PoolCommand, SidechannelListener};
+use network::peer::{PeerTransmitter, PeerReceiver, PeerUpdate, PeerConfig, PeerHandle};
 use network::bcast::{BcastTransmitter, BcastReceiver};
 
 use request_handler::request::*;
-use request_handler::request::RequestStatus::*;
+use request_handler::sync::RowVersion;
+
+use shutdown::shutdown::Shutdown;
+use event_log::event_log::{self, LogEvent};
 
 const PEER_PORT: u16 = 9877;
 const BCAST_PORT: u16 = 9876;
+// A row version takes up to about 110 bytes as JSON, and the receivers read
+// datagrams into 1024 bytes.
+const VERSIONS_PER_MESSAGE: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct TransmitterConfig {
+    pub mode: NetMode,
+    pub peer_port: u16,
+    pub bcast_port: u16,
+    pub peer: PeerConfig,
+    // Stands in for the random part of the peer id, so a node keeps its id
+    // across restarts.
+    pub node_id: Option<String>,
+    pub transport: TransportKind,
+    // Faults to inject into what the node receives, for testing.
+    pub faults: Option<FaultInjector>,
+    // Bulk transfers go over TCP on this port when set, and are broadcast
+    // otherwise.
+    pub sidechannel_port: Option<u16>,
+}
+
+impl Default for TransmitterConfig {
+    fn default() -> TransmitterConfig {
+        TransmitterConfig {
+            mode: NetMode::Broadcast,
+            peer_port: PEER_PORT,
+            bcast_port: BCAST_PORT,
+            peer: PeerConfig::default(),
+            node_id: None,
+            transport: TransportKind::Udp,
+            faults: None,
+            sidechannel_port: None,
+        }
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum BroadcastMessage {
     RequestMessage(Request),
     Position(usize),
+    // A request change that is sent again until every peer has acknowledged it.
+    Reliable { id: u64, request: Request },
+    Ack { id: u64, to: IP },
+    // Anti-entropy of the hall requests, see request_handler::sync.
+    Digest(u64),
+    Versions(Vec<RowVersion>),
+    // The cab requests of owner, sent by owner itself and by the peers that
+    // keep a copy for when it restarts.
+    CabRequests { owner: IP, requests: Vec<Request> },
+}
+
+// What is too large for a broadcast datagram, sent over the sidechannel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Transfer {
+    // The whole hall request table, for a peer that joined or rejoined.
+    Requests(Vec<Request>),
+    // Lines of a peer's event log, for the node that collects the logs.
+    EventLog(Vec<String>),
+    // The settings of the sender, for a peer that joined.
+    Settings(SharedSettings),
+}
+
+// The settings the nodes have to agree on to understand each other.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct SharedSettings {
+    pub anti_entropy: bool,
+    pub reliable_delivery: bool,
+    pub broadcast_tick_ms: u64,
+}
+
+impl SharedSettings {
+    // A protocol a peer runs is turned on here as well, so the nodes end up
+    // running the same ones whoever joined first. Returns whether anything
+    // was turned on.
+    pub fn adopt(&mut self, other: &SharedSettings) -> bool {
+        let before = *self;
+        self.anti_entropy |= other.anti_entropy;
+        self.reliable_delivery |= other.reliable_delivery;
+        *self != before
+    }
+}
+
+pub fn peer_id(node_id: &Option<String>) -> io::Result<String> {
+    Ok(peer_id_at(get_localip()?, node_id))
+}
+
+fn peer_id_at(local_ip: IpAddr, node_id: &Option<String>) -> String {
+    let unique = match *node_id {
+        Some(ref node_id) => node_id.clone(),
+        None => rand::thread_rng().gen::<u16>().to_string(),
+    };
+    format!("{}:{}", local_ip, unique)
+}
+
+fn receiver_transport(config: &TransmitterConfig, port: u16) -> io::Result<Box<dyn Transport>> {
+    let transport = config.transport.receiver(port, &config.mode)?;
+    Ok(match config.faults {
+        Some(ref faults) => faults.wrap(transport),
+        None => transport,
+    })
 }
 
-fn spawn_peer_update_threads(peer_tx: Sender<PeerUpdate<String>>) {
-    let unique = rand::thread_rng().gen::<u16>();
+fn spawn_peer_update_threads(peer_tx: Sender<PeerUpdate<String>>, local_ip: IpAddr, config: &TransmitterConfig, shutdown: Shutdown) -> io::Result<(PeerHandle<IP>, JoinHandle<()>)> {
+    let id = peer_id_at(local_ip, &config.node_id);
 
-    thread::spawn(move|| {
-        let id = format!("{}:{}", get_localip().unwrap(), unique);
-        PeerTransmitter::new(PEER_PORT)
-            .expect("Error creating PeerTransmitter")
-            .run(&id);
+    let transmitter = PeerTransmitter::with_transport(config.transport.transmitter(config.peer_port, &config.mode)?, config.peer);
+    let receiver = PeerReceiver::with_transport(receiver_transport(config, config.peer_port)?, config.peer);
+
+    let peer_handle = transmitter.spawn(id);
+    let receiver_thread = thread::spawn(move|| {
+        receiver.run(peer_tx, shutdown);
     });
 
-    thread::spawn(move|| {
-        PeerReceiver::new(PEER_PORT)
-            .expect("Error creating PeerReceiver")
-            .run(peer_tx);
+    Ok((peer_handle, receiver_thread))
+}
+
+fn spawn_bcast_threads(transmit_rx: Receiver<BroadcastMessage>, receive_tx: Sender<(BroadcastMessage, IP)>, config: &TransmitterConfig, shutdown: Shutdown) -> io::Result<Vec<JoinHandle<()>>> {
+    let transmitter = BcastTransmitter::with_transport(config.transport.transmitter(config.bcast_port, &config.mode)?);
+    let receiver = BcastReceiver::with_transport(receiver_transport(config, config.bcast_port)?);
+
+    let transmitter_shutdown = shutdown.clone();
+    let transmitter_thread = thread::spawn(move|| {
+        transmitter.run(transmit_rx, transmitter_shutdown);
     });
+
+    let receiver_thread = thread::spawn(move|| {
+        receiver.run(receive_tx, shutdown);
+    });
+
+    Ok(vec![transmitter_thread, receiver_thread])
 }
 
-fn spawn_bcast_threads(transmit_rx: Receiver<BroadcastMessage>, receive_tx: Sender<(BroadcastMessage, IP)>) {
-    thread::spawn(move|| {
-        BcastTransmitter::new(BCAST_PORT)
-            .expect("Error creating ")
-            .run(transmit_rx);
+struct Sidechannel {
+    commands: Sender<PoolCommand<Transfer>>,
+    received: Receiver<(Transfer, IP)>,
+    failed: Receiver<(Transfer, IP)>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+fn spawn_sidechannel_threads(port: u16, mode: &NetMode, shutdown: Shutdown) -> io::Result<Sidechannel> {
+    let listener = SidechannelListener::bind(port, mode.is_ipv6())?;
+    let (transfer_tx, transfer_rx) = channel::<(Transfer, IP)>();
+    let (failed_tx, failed_rx) = channel::<(Transfer, IP)>();
+    let (command_tx, command_rx) = channel::<PoolCommand<Transfer>>();
+
+    let listener_shutdown = shutdown.clone();
+    let listener_thread = thread::spawn(move|| {
+        listener.run(transfer_tx, listener_shutdown);
     });
 
-    thread::spawn(move|| {
-        BcastReceiver::new(BCAST_PORT)
-            .expect("Error creating BcastReceiver")
-            .run(receive_tx);
+    let pool_thread = thread::spawn(move|| {
+        ConnectionPool::new(port).run(command_rx, failed_tx, shutdown);
     });
+
+    Ok(Sidechannel {
+        commands: command_tx,
+        received: transfer_rx,
+        failed: failed_rx,
+        threads: vec![listener_thread, pool_thread],
+    })
+}
+
+// Keeps the far ends of the channels alive when running without network, so
+// whoever receives on them waits instead of seeing them hang up.
+struct OfflineChannels {
+    _bcast_tx: Sender<(BroadcastMessage, IP)>,
+    _peer_tx: Sender<PeerUpdate<IP>>,
+    _bcast_rx: Receiver<BroadcastMessage>,
 }
 
 pub struct RequestTransmitter {
     pub bcast_sender: Sender<BroadcastMessage>,
-    pub bcast_receiver: Receiver<(BroadcastMessage, IP)>,
-    pub peer_receiver: Receiver<PeerUpdate<IP>>,
+    // Taken by the main loop, which forwards them to its event loop.
+    pub bcast_receiver: Option<Receiver<(BroadcastMessage, IP)>>,
+    pub peer_receiver: Option<Receiver<PeerUpdate<IP>>>,
+    pub transfer_receiver: Option<Receiver<(Transfer, IP)>>,
+    // Transfers the sidechannel could not deliver, to be broadcast instead.
+    pub transfer_failures: Option<Receiver<(Transfer, IP)>>,
+    transfer_sender: Option<Sender<PoolCommand<Transfer>>>,
+    peer_handle: Option<PeerHandle<IP>>,
+    shutdown: Shutdown,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    offline: bool,
+    _offline_channels: Option<OfflineChannels>,
+    local_ip: IpAddr,
 }
 
 impl RequestTransmitter {
-    pub fn new() -> Self {
+    pub fn new() -> io::Result<Self> {
+        RequestTransmitter::with_config(&TransmitterConfig::default(), Shutdown::new())
+    }
+
+    pub fn with_config(config: &TransmitterConfig, shutdown: Shutdown) -> io::Result<Self> {
+        // A node on a loopback network is known by the address it was given there.
+        let local_ip = match config.transport {
+            TransportKind::Udp => resolve_localip(config.mode.is_ipv6())?,
+            TransportKind::Loopback(_, address) => address,
+        };
+
+        // The threads started first are stopped on their own if the rest of the
+        // network cannot be set up, as the node then goes on without network.
+        let setup_shutdown = shutdown.child();
+        let mut threads = Vec::new();
+
+        // Listening before the heartbeats go out, so the peers that see this
+        // node join can already reach it.
+        let sidechannel = match config.sidechannel_port {
+            Some(port) => match spawn_sidechannel_threads(port, &config.mode, setup_shutdown.clone()) {
+                Ok(sidechannel) => Some(sidechannel),
+                Err(err) => {
+                    println!("Sidechannel unavailable, broadcasting bulk transfers instead. Error: {}", err);
+                    None
+                }
+            },
+            None => None,
+        };
+        let (transfer_sender, transfer_receiver, transfer_failures) = match sidechannel {
+            Some(sidechannel) => {
+                threads.extend(sidechannel.threads);
+                (Some(sidechannel.commands), Some(sidechannel.received), Some(sidechannel.failed))
+            },
+            None => (None, None, None),
+        };
+
         let (peer_tx, peer_rx) = channel::<PeerUpdate<IP>>();
-        spawn_peer_update_threads(peer_tx);
+        let peer_handle = match spawn_peer_update_threads(peer_tx, local_ip, config, setup_shutdown.clone()) {
+            Ok((peer_handle, peer_thread)) => {
+                threads.push(peer_thread);
+                peer_handle
+            },
+            Err(err) => {
+                setup_shutdown.trigger();
+                for thread in threads {
+                    let _ = thread.join();
+                }
+                return Err(err);
+            }
+        };
+
+        let (bcast_transmitter_tx, bcast_transmitter_rx) = channel::<BroadcastMessage>();
+        let (bcast_receiver_tx, bcast_receiver_rx) = channel::<(BroadcastMessage, IP)>();
+        match spawn_bcast_threads(bcast_transmitter_rx, bcast_receiver_tx, config, shutdown.clone()) {
+            Ok(bcast_threads) => threads.extend(bcast_threads),
+            Err(err) => {
+                peer_handle.shutdown();
+                setup_shutdown.trigger();
+                for thread in threads {
+                    let _ = thread.join();
+                }
+                return Err(err);
+            }
+        }
+
+        Ok(RequestTransmitter {
+            bcast_sender: bcast_transmitter_tx,
+            bcast_receiver: Some(bcast_receiver_rx),
+            peer_receiver: Some(peer_rx),
+            transfer_receiver: transfer_receiver,
+            transfer_failures: transfer_failures,
+            transfer_sender: transfer_sender,
+            peer_handle: Some(peer_handle),
+            shutdown: shutdown,
+            threads: Mutex::new(threads),
+            offline: false,
+            _offline_channels: None,
+            local_ip: local_ip,
+        })
+    }
+
+    // A transmitter for a node without network. Nothing is sent or received, and
+    // the node identifies itself by the loopback address.
+    pub fn offline(shutdown: Shutdown) -> Self {
+        let loopback = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        set_localip(loopback);
+        RequestTransmitter::without_network(true, loopback, shutdown)
+    }
+
+    // A transmitter that behaves as if the node were on the network, but sends
+    // and receives nothing. Used to feed a recorded node's messages back in.
+    pub fn detached(local_ip: IpAddr, shutdown: Shutdown) -> Self {
+        RequestTransmitter::without_network(false, local_ip, shutdown)
+    }
+
+    // A transmitter for one of several nodes on a network inside this process.
+    // What the node sends comes out of the returned receiver, and whoever runs
+    // the network hands the node what it receives.
+    pub fn in_memory(local_ip: IpAddr, shutdown: Shutdown) -> (Self, Receiver<BroadcastMessage>) {
+        let mut transmitter = RequestTransmitter::without_network(false, local_ip, shutdown);
+        let (sender, receiver) = channel::<BroadcastMessage>();
+        transmitter.bcast_sender = sender;
+        (transmitter, receiver)
+    }
 
+    fn without_network(offline: bool, local_ip: IpAddr, shutdown: Shutdown) -> Self {
+        let (peer_tx, peer_rx) = channel::<PeerUpdate<IP>>();
         let (bcast_transmitter_tx, bcast_transmitter_rx) = channel::<BroadcastMessage>();
         let (bcast_receiver_tx, bcast_receiver_rx) = channel::<(BroadcastMessage, IP)>();
-        spawn_bcast_threads(bcast_transmitter_rx, bcast_receiver_tx);
 
         RequestTransmitter {
             bcast_sender: bcast_transmitter_tx,
-            bcast_receiver: bcast_receiver_rx,
-            peer_receiver: peer_rx,
+            bcast_receiver: Some(bcast_receiver_rx),
+            peer_receiver: Some(peer_rx),
+            transfer_receiver: None,
+            transfer_failures: None,
+            transfer_sender: None,
+            peer_handle: None,
+            shutdown: shutdown,
+            threads: Mutex::new(Vec::new()),
+            offline: offline,
+            _offline_channels: Some(OfflineChannels {
+                _bcast_tx: bcast_receiver_tx,
+                _peer_tx: peer_tx,
+                _bcast_rx: bcast_transmitter_rx,
+            }),
+            local_ip: local_ip,
         }
     }
 
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    // The address the node is known by to its peers.
+    pub fn local_ip(&self) -> IpAddr {
+        self.local_ip
+    }
+
     pub fn announce_request(&self, request: Request) {
-        self.bcast_sender.send(BroadcastMessage::RequestMessage(request))
-            .expect("Could not announce request");
+        let message = BroadcastMessage::RequestMessage(request);
+        event_log::record(LogEvent::MessageSent(message.clone()), None, None);
+        // A failed send only means the network threads are gone, which the
+        // periodic rebroadcast and the offline mode cover for.
+        let _ = self.bcast_sender.send(message);
+    }
+
+    // The periodic repeats are not logged, as they carry no news.
+    pub fn rebroadcast_request(&self, request: Request) {
+        let _ = self.bcast_sender.send(BroadcastMessage::RequestMessage(request));
+    }
+
+    pub fn announce_cab_requests(&self, owner: IP, requests: Vec<Request>) {
+        let _ = self.bcast_sender.send(BroadcastMessage::CabRequests { owner: owner, requests: requests });
+    }
+
+    pub fn announce_reliable(&self, id: u64, request: Request) {
+        let message = BroadcastMessage::Reliable { id: id, request: request };
+        event_log::record(LogEvent::MessageSent(message.clone()), None, None);
+        let _ = self.bcast_sender.send(message);
+    }
+
+    pub fn resend_reliable(&self, id: u64, request: Request) {
+        let _ = self.bcast_sender.send(BroadcastMessage::Reliable { id: id, request: request });
+    }
+
+    pub fn acknowledge(&self, id: u64, to: IP) {
+        let _ = self.bcast_sender.send(BroadcastMessage::Ack { id: id, to: to });
+    }
+
+    pub fn announce_digest(&self, digest: u64) {
+        let _ = self.bcast_sender.send(BroadcastMessage::Digest(digest));
+    }
+
+    // In several messages for a larger table. Each row is compared on its own,
+    // so the receiver needs no reassembly.
+    pub fn announce_versions(&self, versions: Vec<RowVersion>) {
+        for chunk in versions.chunks(VERSIONS_PER_MESSAGE) {
+            let _ = self.bcast_sender.send(BroadcastMessage::Versions(chunk.to_vec()));
+        }
+    }
+
+    // Queues a bulk transfer to the peer at ip. False without a sidechannel,
+    // when the caller has to broadcast instead. A transfer that is queued but
+    // cannot be delivered comes back out of transfer_failures.
+    pub fn transfer(&self, ip: &IP, transfer: Transfer) -> bool {
+        let to = match ip.parse::<IpAddr>() {
+            Ok(to) => to,
+            Err(_) => return false,
+        };
+        match self.transfer_sender {
+            Some(ref transfer_sender) => transfer_sender.send(PoolCommand::Send(to, transfer)).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn forget_peers(&self, ips: &[IP]) {
+        if let Some(ref transfer_sender) = self.transfer_sender {
+            let peers = ips.iter().filter_map(|ip| ip.parse::<IpAddr>().ok()).collect();
+            let _ = transfer_sender.send(PoolCommand::Forget(peers));
+        }
+    }
+
+    pub fn announce_position(&self, floor: usize) {
+        let _ = self.bcast_sender.send(BroadcastMessage::Position(floor));
+    }
+
+    pub fn leave_peer_set(&self) {
+        if let Some(ref peer_handle) = self.peer_handle {
+            peer_handle.disable();
+        }
+    }
+
+    pub fn rejoin_peer_set(&self) {
+        if let Some(ref peer_handle) = self.peer_handle {
+            peer_handle.enable();
+        }
+    }
+
+    // Says goodbye to the peers and waits for every network thread to finish.
+    pub fn shutdown(&self) {
+        if let Some(ref peer_handle) = self.peer_handle {
+            peer_handle.shutdown();
+        }
+        self.shutdown.trigger();
+        for thread in self.threads.lock().unwrap().drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use network::transport::LoopbackNetwork;
+
+    fn node(network: &LoopbackNetwork, address: &str) -> RequestTransmitter {
+        let config = TransmitterConfig {
+            node_id: Some("test".to_string()),
+            transport: TransportKind::Loopback(network.clone(), address.parse().unwrap()),
+            ..TransmitterConfig::default()
+        };
+        RequestTransmitter::with_config(&config, Shutdown::new()).unwrap()
+    }
+
+    #[test]
+    fn nodes_on_a_loopback_network_hear_each_other() {
+        let network = LoopbackNetwork::new();
+        let mut a = node(&network, "10.0.0.1");
+        let mut b = node(&network, "10.0.0.2");
+        assert_eq!(b.local_ip().to_string(), "10.0.0.2");
+
+        a.announce_position(3);
+        let bcast_receiver = b.bcast_receiver.take().unwrap();
+        let (message, from) = bcast_receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(message, BroadcastMessage::Position(3));
+        assert_eq!(from, "10.0.0.1");
+
+        let peer_receiver = a.peer_receiver.take().unwrap();
+        let mut peers = Vec::new();
+        while peers.len() < 2 {
+            peers = peer_receiver.recv_timeout(Duration::from_secs(1)).unwrap().peers;
+        }
+        assert_eq!(peers, vec!["10.0.0.1:test".to_string(), "10.0.0.2:test".to_string()]);
+
+        a.shutdown();
+        b.shutdown();
+    }
+
+    #[test]
+    fn versions_of_a_large_table_fit_the_datagrams() {
+        use request_handler::sync::RowVersion;
+
+        let network = LoopbackNetwork::new();
+        let a = node(&network, "10.0.0.1");
+        let mut b = node(&network, "10.0.0.2");
+        let versions: Vec<RowVersion> = (0..20).map(|floor| RowVersion {
+            request_type: RequestType::CallDown,
+            floor: floor,
+            counter: u64::MAX,
+            acknowledgements: u64::MAX,
+        }).collect();
+        assert!(serde_json::to_string(&BroadcastMessage::Versions(versions.clone())).unwrap().len() > 1024);
+
+        a.announce_versions(versions.clone());
+        let bcast_receiver = b.bcast_receiver.take().unwrap();
+        let mut received = Vec::new();
+        while received.len() < versions.len() {
+            if let BroadcastMessage::Versions(chunk) = bcast_receiver.recv_timeout(Duration::from_secs(1)).unwrap().0 { received.extend(chunk) }
+        }
+        assert_eq!(received, versions);
+
+        a.shutdown();
+        b.shutdown();
+    }
+
+    #[test]
+    fn failing_network_leaves_the_node_running_offline() {
+        use std::net::UdpSocket;
+        use std::sync::mpsc::RecvTimeoutError;
+
+        // Holding the broadcast port without address reuse makes the bcast
+        // receiver fail to bind after the peer threads have started.
+        let taken = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let peer_port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        let config = TransmitterConfig {
+            peer_port: peer_port,
+            bcast_port: taken.local_addr().unwrap().port(),
+            node_id: Some("test".to_string()),
+            ..TransmitterConfig::default()
+        };
+        let shutdown = Shutdown::new();
+        assert!(RequestTransmitter::with_config(&config, shutdown.clone()).is_err());
+        assert!(!shutdown.is_triggered());
+
+        let mut offline = RequestTransmitter::offline(shutdown.clone());
+        assert!(offline.is_offline());
+        let bcast_receiver = offline.bcast_receiver.take().unwrap();
+        // Waits for messages instead of seeing the network hang up.
+        assert_eq!(bcast_receiver.recv_timeout(Duration::from_millis(10)).unwrap_err(), RecvTimeoutError::Timeout);
+        assert!(!shutdown.is_triggered());
+        offline.shutdown();
     }
 }
@@ -0,0 +1,122 @@
+use request_handler::request::{Request, RequestType};
+
+// Anti-entropy for the hall requests. Every node broadcasts a digest of its
+// table each tick. A node whose digest differs answers with the versions of its
+// rows, and whoever has a row ahead of those versions sends that row, so only
+// the rows that differ are transferred.
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// FNV-1a, which hashes the same on every node, unlike the std hashers.
+fn hash_bytes(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+fn hash_u64(hash: u64, value: u64) -> u64 {
+    (0..8).fold(hash, |hash, i| hash_bytes(hash, &[(value >> (i * 8)) as u8]))
+}
+
+// What a row is at, without the times, which converge on their own once the
+// counters and acknowledgements agree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RowVersion {
+    pub request_type: RequestType,
+    pub floor: usize,
+    pub counter: u64,
+    pub acknowledgements: u64,
+}
+
+impl RowVersion {
+    pub fn of(request: &Request) -> Self {
+        let mut acknowledgements = FNV_OFFSET;
+        for ip in &request.acknowledged_by {
+            acknowledgements = hash_bytes(acknowledgements, ip.as_bytes());
+            acknowledgements = hash_bytes(acknowledgements, &[0]);
+        }
+        RowVersion {
+            request_type: request.request_type,
+            floor: request.floor,
+            counter: request.counter,
+            acknowledgements: acknowledgements,
+        }
+    }
+}
+
+pub fn hall_versions(requests: &[Vec<Request>]) -> Vec<RowVersion> {
+    let call_up = &requests[RequestType::CallUp as usize];
+    let call_down = &requests[RequestType::CallDown as usize];
+    call_up.iter().chain(call_down.iter()).map(RowVersion::of).collect()
+}
+
+pub fn digest(versions: &[RowVersion]) -> u64 {
+    versions.iter().fold(FNV_OFFSET, |hash, version| {
+        let hash = hash_u64(hash, version.request_type as u64);
+        let hash = hash_u64(hash, version.floor as u64);
+        let hash = hash_u64(hash, version.counter);
+        hash_u64(hash, version.acknowledgements)
+    })
+}
+
+// The hall rows the remote node is behind on. At the same counter the rows are
+// sent when the acknowledgements differ, as merging unites them.
+pub fn rows_ahead(requests: &[Vec<Request>], remote: &[RowVersion]) -> Vec<Request> {
+    let mut ahead = Vec::new();
+    for version in remote {
+        if version.request_type == RequestType::Internal {
+            continue;
+        }
+        let local = match requests[version.request_type as usize].get(version.floor) {
+            Some(local) => local,
+            None => continue,
+        };
+        if local.counter > version.counter
+            || (local.counter == version.counter && RowVersion::of(local).acknowledgements != version.acknowledgements) {
+            ahead.push(local.clone());
+        }
+    }
+    ahead
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request_handler::request::{Request, RequestType};
+
+    fn table() -> Vec<Vec<Request>> {
+        let mut requests = vec![vec![], vec![], vec![]];
+        for &t in [RequestType::CallDown, RequestType::CallUp, RequestType::Internal].iter() {
+            requests[t as usize] = (0..4).map(|floor| Request { floor: floor, request_type: t, ..Request::default() }).collect();
+        }
+        requests
+    }
+
+    #[test]
+    fn only_differing_rows_are_ahead() {
+        let mut local = table();
+        let remote = table();
+        assert_eq!(digest(&hall_versions(&local)), digest(&hall_versions(&remote)));
+
+        local[RequestType::CallUp as usize][1].press("10.0.0.1".to_string(), 0);
+        // Cab requests are not synchronized.
+        local[RequestType::Internal as usize][2].activate(0);
+        let mut remote = remote;
+        remote[RequestType::CallDown as usize][3].press("10.0.0.2".to_string(), 0);
+        assert!(digest(&hall_versions(&local)) != digest(&hall_versions(&remote)));
+
+        let ahead = rows_ahead(&local, &hall_versions(&remote));
+        assert_eq!(ahead, vec![local[RequestType::CallUp as usize][1].clone()]);
+
+        // The same counter with other acknowledgements is sent too.
+        let mut acknowledged = local.clone();
+        acknowledged[RequestType::CallUp as usize][1].acknowledge("10.0.0.3".to_string());
+        let ahead = rows_ahead(&acknowledged, &hall_versions(&local));
+        assert_eq!(ahead, vec![acknowledged[RequestType::CallUp as usize][1].clone()]);
+
+        for request in ahead {
+            local[RequestType::CallUp as usize][1].merge(&request);
+        }
+        assert_eq!(digest(&hall_versions(&local)), digest(&hall_versions(&acknowledged)));
+    }
+}
@@ -0,0 +1,3 @@
+extern crate libc;
+
+pub mod shutdown;
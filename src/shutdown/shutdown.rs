@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use shutdown::libc;
+
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_: libc::c_int) {
+    SIGNALLED.store(true, Ordering::SeqCst);
+}
+
+// Makes SIGINT and SIGTERM trigger every Shutdown instead of killing the process,
+// so the main loop gets to stop the motor and leave the peer set. Interrupted
+// system calls are restarted rather than failing in whichever thread got the signal.
+pub fn install_signal_handlers() {
+    for &signal in &[libc::SIGINT, libc::SIGTERM] {
+        let installed = unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, ptr::null_mut())
+        };
+        if installed != 0 {
+            println!("Installing the handler for signal {} failed. Error: {}", signal, io::Error::last_os_error());
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Shutdown {
+    flag: Arc<AtomicBool>,
+    parent: Option<Arc<Shutdown>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown {
+            flag: Arc::new(AtomicBool::new(false)),
+            parent: None,
+        }
+    }
+
+    // A shutdown that is triggered along with this one, but can also be
+    // triggered on its own without stopping the rest.
+    pub fn child(&self) -> Self {
+        Shutdown {
+            flag: Arc::new(AtomicBool::new(false)),
+            parent: Some(Arc::new(self.clone())),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        let parent_triggered = match self.parent {
+            Some(ref parent) => parent.is_triggered(),
+            None => false,
+        };
+        self.flag.load(Ordering::SeqCst) || parent_triggered || SIGNALLED.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,565 @@
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+use elevator_driver::elev_io::{ElevIo, Floor, Button, Light, N_FLOORS};
+use elevator_driver::sim_io::{SimIo, SimCar};
+use elevator_fsm::elevator_fsm::Elevator;
+use elevator_timer::elevator_timer::TimerName;
+use request_handler::request::{RequestType, IP};
+use request_handler::request_transmitter::{RequestTransmitter, BroadcastMessage};
+use network::peer::{PeerUpdate, PeerInfo};
+use shutdown::shutdown::Shutdown;
+use clock::clock::{Clock, ManualClock};
+use simulation::network::{SimNetwork, NetworkConfig};
+use request_handler::reliable::ReliableConfig;
+
+const TICK_MS: u64 = 10;
+const FLOOR_TRAVEL_MS: u64 = 2000;
+const BROADCAST_TICK_MS: u64 = 150;
+const STARVATION_CHECK_S: u64 = 1;
+const RETRANSMIT_CHECK_MS: u64 = 20;
+
+// What a script does at a point in time.
+#[derive(Debug, Clone)]
+pub enum Action {
+    // A passenger presses a button on the panel of one node.
+    Press(usize, Button),
+    Partition(Vec<Vec<usize>>),
+    Heal,
+    // The car of a node stops moving, or moves again.
+    Jam(usize, bool),
+    // The process of a node starts over on the same car, which has to be at
+    // a floor. Its peers see it leave and join again.
+    Restart(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    // A button lamp was lit on a node that had no confirmed request for it.
+    LampWithoutRequest { node: usize, request_type: RequestType, floor: usize },
+    // Two cars opened their doors for the same hall call.
+    ServedTwice { request_type: RequestType, floor: usize, nodes: (usize, usize) },
+    // A confirmed request was still waiting when the simulation was checked.
+    NotServed { node: usize, request_type: RequestType, floor: usize },
+}
+
+struct Node {
+    ip: IP,
+    sim: SimIo,
+    car: SimCar,
+    elevator: Elevator,
+    transmitter: Rc<RequestTransmitter>,
+    outgoing: Receiver<BroadcastMessage>,
+    peers: Vec<usize>,
+    in_service: bool,
+}
+
+fn button(request_type: RequestType, floor: usize) -> Button {
+    match request_type {
+        RequestType::CallUp => Button::CallUp(Floor::At(floor)),
+        RequestType::CallDown => Button::CallDown(Floor::At(floor)),
+        RequestType::Internal => Button::Internal(Floor::At(floor)),
+    }
+}
+
+fn has_button(request_type: RequestType, floor: usize) -> bool {
+    match request_type {
+        RequestType::CallUp => floor < N_FLOORS - 1,
+        RequestType::CallDown => floor > 0,
+        RequestType::Internal => true,
+    }
+}
+
+const REQUEST_TYPES: [RequestType; 3] = [RequestType::CallUp, RequestType::CallDown, RequestType::Internal];
+
+// The elevator a node starts with, and the messages it sends.
+fn start_elevator(ip: IpAddr, sim: &SimIo, clock: &Arc<dyn Clock>) -> (Elevator, Rc<RequestTransmitter>, Receiver<BroadcastMessage>) {
+    let (transmitter, outgoing) = RequestTransmitter::in_memory(ip, Shutdown::new());
+    let transmitter = Rc::new(transmitter);
+
+    let io = ElevIo::simulated(sim.clone()).expect("Init of simulated io failed");
+    let mut elevator = Elevator::with_io(io, transmitter.clone());
+    elevator.set_clock(clock.clone());
+    elevator.timers.repeat(TimerName::Broadcast, Duration::from_millis(BROADCAST_TICK_MS));
+    elevator.timers.repeat(TimerName::Starvation, Duration::from_secs(STARVATION_CHECK_S));
+    (elevator, transmitter, outgoing)
+}
+
+// Runs several elevators in this thread on simulated cars, a virtual clock and
+// an in-memory network, and watches them for broken invariants. The same seed
+// and script give the same run.
+pub struct Simulation {
+    clock: ManualClock,
+    start: Instant,
+    network: SimNetwork,
+    nodes: Vec<Node>,
+    script: Vec<(Duration, Action)>,
+    // The node that served each hall call, by type, floor and counter.
+    served: HashMap<(usize, usize, u64), usize>,
+    reported: HashSet<(usize, usize, usize)>,
+    violations: Vec<Violation>,
+    // The protocols a restarted node is started with again.
+    reliable_delivery: bool,
+    anti_entropy: bool,
+}
+
+impl Simulation {
+    pub fn new(nodes: usize, config: NetworkConfig, seed: u32) -> Self {
+        let clock = ManualClock::new();
+        let shared_clock: Arc<dyn Clock> = Arc::new(clock.clone());
+
+        let nodes = (0..nodes).map(|index| {
+            let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, index as u8 + 1));
+            let sim = SimIo::new();
+            let car = SimCar::new(sim.clone(), N_FLOORS, 0, Duration::from_millis(FLOOR_TRAVEL_MS));
+            let (elevator, transmitter, outgoing) = start_elevator(ip, &sim, &shared_clock);
+
+            Node {
+                ip: ip.to_string(),
+                sim: sim,
+                car: car,
+                elevator: elevator,
+                transmitter: transmitter,
+                outgoing: outgoing,
+                peers: Vec::new(),
+                in_service: true,
+            }
+        }).collect::<Vec<Node>>();
+
+        let mut simulation = Simulation {
+            start: clock.now(),
+            clock: clock,
+            network: SimNetwork::new(nodes.len(), config, seed),
+            nodes: nodes,
+            script: Vec::new(),
+            served: HashMap::new(),
+            reported: HashSet::new(),
+            violations: Vec::new(),
+            reliable_delivery: false,
+            anti_entropy: false,
+        };
+        simulation.update_peers();
+        simulation
+    }
+
+    // Does action once the simulation has run for at.
+    pub fn at(&mut self, at: Duration, action: Action) {
+        self.script.push((at, action));
+        self.script.sort_by_key(|a| a.0);
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now().duration_since(self.start)
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.elapsed() + duration;
+        while self.elapsed() < end {
+            self.step();
+        }
+    }
+
+    // Makes every node retransmit its request changes until acknowledged.
+    pub fn enable_reliable_delivery(&mut self) {
+        self.reliable_delivery = true;
+        for node in &mut self.nodes {
+            node.elevator.request_handler.enable_reliable_delivery(ReliableConfig::default());
+            node.elevator.timers.repeat(TimerName::Retransmit, Duration::from_millis(RETRANSMIT_CHECK_MS));
+        }
+    }
+
+    // Makes every node send digests of its table instead of the whole table.
+    pub fn enable_anti_entropy(&mut self) {
+        self.anti_entropy = true;
+        for node in &mut self.nodes {
+            node.elevator.request_handler.enable_anti_entropy();
+        }
+    }
+
+    pub fn elevator(&self, node: usize) -> &Elevator {
+        &self.nodes[node].elevator
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    // Every request that was confirmed should have been served by now. Run
+    // after the script is done and the network has had time to settle.
+    pub fn check_all_served(&mut self) {
+        for (index, node) in self.nodes.iter().enumerate() {
+            for request in node.elevator.request_handler.requests.iter().flat_map(|rs| rs.iter()) {
+                if request.is_active() {
+                    self.violations.push(Violation::NotServed {
+                        node: index,
+                        request_type: request.request_type,
+                        floor: request.floor,
+                    });
+                }
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        self.clock.advance(Duration::from_millis(TICK_MS));
+        let elapsed = self.elapsed();
+
+        while !self.script.is_empty() && self.script[0].0 <= elapsed {
+            let (_, action) = self.script.remove(0);
+            self.perform(action);
+        }
+
+        for index in 0..self.nodes.len() {
+            self.step_node(index);
+        }
+        // A car that went out of service stopped its heartbeats.
+        let mut in_service_changed = false;
+        for node in &mut self.nodes {
+            let in_service = node.elevator.in_service();
+            in_service_changed = in_service_changed || in_service != node.in_service;
+            node.in_service = in_service;
+        }
+        if in_service_changed {
+            self.update_peers();
+        }
+
+        let now = self.clock.now();
+        for index in 0..self.nodes.len() {
+            let sent: Vec<BroadcastMessage> = self.nodes[index].outgoing.try_iter().collect();
+            for message in sent {
+                self.network.send(index, message, now);
+            }
+        }
+        for (from, to, message) in self.network.deliver(now) {
+            let remote_ip = self.nodes[from].ip.clone();
+            let elevator = &mut self.nodes[to].elevator;
+            match message {
+                BroadcastMessage::RequestMessage(request) => elevator.event_request_message(&request, remote_ip),
+                BroadcastMessage::Position(floor) => elevator.event_position_message(remote_ip, floor),
+                BroadcastMessage::Reliable { id, request } => {
+                    elevator.event_request_message(&request, remote_ip.clone());
+                    elevator.request_handler.acknowledge_delivery(id, remote_ip);
+                },
+                BroadcastMessage::Ack { id, to } => elevator.request_handler.handle_ack(id, &to, remote_ip),
+                BroadcastMessage::Digest(digest) => elevator.request_handler.handle_digest(digest),
+                BroadcastMessage::Versions(versions) => elevator.request_handler.handle_versions(&versions),
+                BroadcastMessage::CabRequests { owner, requests } => elevator.event_cab_requests(owner, &requests),
+            }
+        }
+
+        for index in 0..self.nodes.len() {
+            self.check_lamps(index);
+        }
+    }
+
+    fn perform(&mut self, action: Action) {
+        match action {
+            Action::Press(node, button) => self.nodes[node].elevator.event_new_floor_order(button),
+            Action::Partition(groups) => {
+                self.network.partition(&groups);
+                self.update_peers();
+            },
+            Action::Heal => {
+                self.network.heal();
+                self.update_peers();
+            },
+            Action::Jam(node, jammed) => self.nodes[node].car.jam(jammed),
+            Action::Restart(node) => self.restart(node),
+        }
+    }
+
+    fn restart(&mut self, index: usize) {
+        self.nodes[index].in_service = false;
+        self.update_peers();
+
+        let clock: Arc<dyn Clock> = Arc::new(self.clock.clone());
+        let ip = self.nodes[index].ip.parse::<IpAddr>().unwrap();
+        let (mut elevator, transmitter, outgoing) = start_elevator(ip, &self.nodes[index].sim, &clock);
+        if self.reliable_delivery {
+            elevator.request_handler.enable_reliable_delivery(ReliableConfig::default());
+            elevator.timers.repeat(TimerName::Retransmit, Duration::from_millis(RETRANSMIT_CHECK_MS));
+        }
+        if self.anti_entropy {
+            elevator.request_handler.enable_anti_entropy();
+        }
+
+        let node = &mut self.nodes[index];
+        node.elevator = elevator;
+        node.transmitter = transmitter;
+        node.outgoing = outgoing;
+        node.peers = Vec::new();
+        node.in_service = true;
+        self.update_peers();
+    }
+
+    // Moves the car, lets the elevator see where it is and runs its timers. Hall
+    // calls that go from confirmed to cleared while its doors open were served by it.
+    fn step_node(&mut self, index: usize) {
+        let hall_before = self.active_hall_calls(index);
+        let door_was_open = self.door_open(index);
+
+        {
+            let node = &mut self.nodes[index];
+            node.car.step(TICK_MS);
+            match node.elevator.io.get_floor_signal().unwrap() {
+                Floor::At(_) => node.elevator.event_at_floor(),
+                Floor::Between => node.elevator.event_running(),
+            }
+
+            for name in node.elevator.timers.take_due() {
+                match name {
+                    TimerName::Door => node.elevator.event_doors_should_close(),
+                    TimerName::Stuck => node.elevator.event_stuck(),
+                    TimerName::Broadcast => {
+                        if node.elevator.in_service() {
+                            node.transmitter.announce_position(node.elevator.current_floor);
+                        }
+                        node.elevator.request_handler.sync_requests();
+                    },
+                    TimerName::Starvation => node.elevator.event_starvation_check(),
+                    TimerName::Retransmit => node.elevator.request_handler.retransmit_unacknowledged(),
+                    // The nodes share one process and have no sidechannel to ship logs over.
+                    TimerName::ShipLog => {},
+                }
+            }
+        }
+
+        if door_was_open || !self.door_open(index) {
+            return;
+        }
+        let hall_after = self.active_hall_calls(index);
+        for (request_type, floor, counter) in hall_before {
+            if hall_after.contains(&(request_type, floor, counter)) {
+                continue;
+            }
+            let call = (request_type as usize, floor, counter);
+            match self.served.get(&call).cloned() {
+                Some(other) if other != index => {
+                    self.violations.push(Violation::ServedTwice {
+                        request_type: request_type,
+                        floor: floor,
+                        nodes: (other, index),
+                    });
+                },
+                Some(_) => {},
+                None => {
+                    self.served.insert(call, index);
+                },
+            }
+        }
+    }
+
+    fn door_open(&self, index: usize) -> bool {
+        match self.nodes[index].sim.door_light() {
+            Light::On => true,
+            Light::Off => false,
+        }
+    }
+
+    fn active_hall_calls(&self, index: usize) -> Vec<(RequestType, usize, u64)> {
+        self.nodes[index].elevator.request_handler.requests.iter()
+            .flat_map(|rs| rs.iter())
+            .filter(|request| request.request_type != RequestType::Internal && request.is_active())
+            .map(|request| (request.request_type, request.floor, request.counter))
+            .collect()
+    }
+
+    fn check_lamps(&mut self, index: usize) {
+        for &request_type in REQUEST_TYPES.iter() {
+            for floor in 0..N_FLOORS {
+                if !has_button(request_type, floor) {
+                    continue;
+                }
+                let lit = match self.nodes[index].sim.button_light(button(request_type, floor)).unwrap() {
+                    Light::On => true,
+                    Light::Off => false,
+                };
+                let confirmed = self.nodes[index].elevator.request_handler.requests[request_type as usize][floor].is_active();
+                if lit && !confirmed && self.reported.insert((index, request_type as usize, floor)) {
+                    self.violations.push(Violation::LampWithoutRequest {
+                        node: index,
+                        request_type: request_type,
+                        floor: floor,
+                    });
+                }
+            }
+        }
+    }
+
+    // Tells every node which nodes it can reach now, the way the peer receiver
+    // would once the heartbeats stop or start coming through. Nodes out of
+    // service send no heartbeats.
+    fn update_peers(&mut self) {
+        let now = self.clock.now();
+        let ids: Vec<String> = self.nodes.iter().map(|node| format!("{}:sim", node.ip)).collect();
+        for index in 0..self.nodes.len() {
+            let peers: Vec<usize> = (0..self.nodes.len())
+                .filter(|&other| self.network.can_reach(index, other) && self.nodes[other].in_service)
+                .collect();
+            let old_peers = self.nodes[index].peers.clone();
+            if peers == old_peers {
+                continue;
+            }
+
+            let mut update = PeerUpdate::new();
+            for &peer in &peers {
+                update.add_peers(ids[peer].clone());
+                update.add_info(PeerInfo { id: ids[peer].clone(), first_seen: now, last_seen: now, suspected: false });
+                if !old_peers.contains(&peer) {
+                    update.add_new(ids[peer].clone());
+                }
+            }
+            for &peer in &old_peers {
+                if !peers.contains(&peer) {
+                    update.add_lost(ids[peer].clone());
+                }
+            }
+
+            self.nodes[index].peers = peers;
+            self.nodes[index].elevator.event_peer_update(update);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use elevator_driver::elev_io::{Button, Floor};
+    use simulation::network::NetworkConfig;
+
+    fn lossy() -> NetworkConfig {
+        NetworkConfig {
+            latency_min: Duration::from_millis(5),
+            latency_max: Duration::from_millis(60),
+            loss: 0.2,
+            duplication: 0.1,
+        }
+    }
+
+    #[test]
+    fn three_cars_serve_every_call_once() {
+        let mut simulation = Simulation::new(3, lossy(), 7);
+        let s = Duration::from_secs;
+        simulation.at(s(1), Action::Press(0, Button::CallUp(Floor::At(1))));
+        simulation.at(s(1), Action::Press(2, Button::CallDown(Floor::At(3))));
+        simulation.at(s(2), Action::Press(1, Button::Internal(Floor::At(2))));
+        simulation.at(s(4), Action::Press(1, Button::CallDown(Floor::At(2))));
+        simulation.at(s(9), Action::Press(0, Button::CallUp(Floor::At(0))));
+        simulation.at(s(9), Action::Press(2, Button::CallUp(Floor::At(0))));
+        simulation.at(s(12), Action::Partition(vec![vec![0, 1], vec![2]]));
+        simulation.at(s(13), Action::Press(2, Button::Internal(Floor::At(3))));
+        simulation.at(s(14), Action::Press(0, Button::CallDown(Floor::At(1))));
+        simulation.at(s(20), Action::Heal);
+        simulation.run_for(s(45));
+
+        simulation.check_all_served();
+        assert_eq!(simulation.violations(), &[]);
+        assert!(simulation.elevator(1).request_handler.requests[RequestType::Internal as usize][2].wait_time().is_some());
+    }
+
+    #[test]
+    fn reliable_delivery_gets_every_change_acknowledged() {
+        let mut simulation = Simulation::new(3, lossy(), 3);
+        simulation.enable_reliable_delivery();
+        let s = Duration::from_secs;
+        simulation.at(s(1), Action::Press(0, Button::CallUp(Floor::At(2))));
+        simulation.at(s(1), Action::Press(1, Button::CallDown(Floor::At(1))));
+        simulation.at(s(5), Action::Press(2, Button::CallDown(Floor::At(3))));
+
+        // Long before the retransmissions would give up.
+        simulation.run_for(Duration::from_millis(1800));
+        for node in 0..3 {
+            assert_eq!(simulation.elevator(node).request_handler.unacknowledged(), 0);
+        }
+        simulation.run_for(s(25));
+
+        simulation.check_all_served();
+        assert_eq!(simulation.violations(), &[]);
+    }
+
+    #[test]
+    fn stuck_car_gets_no_hall_calls() {
+        let mut simulation = Simulation::new(3, NetworkConfig::default(), 5);
+        let s = Duration::from_secs;
+        // Car 0 jams between floors 1 and 2, closer to the call than the others.
+        simulation.at(s(1), Action::Press(0, Button::Internal(Floor::At(3))));
+        simulation.at(s(4), Action::Jam(0, true));
+        simulation.at(s(12), Action::Press(1, Button::CallDown(Floor::At(1))));
+        simulation.run_for(s(25));
+
+        assert!(!simulation.elevator(0).in_service());
+        for node in 1..3 {
+            let call = &simulation.elevator(node).request_handler.requests[RequestType::CallDown as usize][1];
+            assert!(!call.is_active());
+            assert!(call.wait_time().is_some());
+        }
+        assert_eq!(simulation.violations(), &[]);
+    }
+
+    #[test]
+    fn digests_bring_the_tables_together() {
+        let mut simulation = Simulation::new(3, lossy(), 11);
+        simulation.enable_anti_entropy();
+        let s = Duration::from_secs;
+        // The first call is served before the partition, which would otherwise
+        // have it served on both sides.
+        simulation.at(s(1), Action::Press(0, Button::CallUp(Floor::At(1))));
+        simulation.at(s(5), Action::Partition(vec![vec![0, 1], vec![2]]));
+        simulation.at(s(6), Action::Press(2, Button::CallDown(Floor::At(3))));
+        simulation.at(s(7), Action::Press(1, Button::CallDown(Floor::At(2))));
+        simulation.at(s(13), Action::Heal);
+        simulation.run_for(s(35));
+
+        simulation.check_all_served();
+        assert_eq!(simulation.violations(), &[]);
+        let digest = simulation.elevator(0).request_handler.digest();
+        for node in 1..3 {
+            assert_eq!(simulation.elevator(node).request_handler.digest(), digest);
+        }
+    }
+
+    #[test]
+    fn restarted_car_gets_its_cab_calls_back() {
+        let mut simulation = Simulation::new(3, lossy(), 13);
+        let s = Duration::from_secs;
+        // Car 0 is held at the ground floor so the call is still waiting when
+        // its process restarts.
+        simulation.at(Duration::from_millis(500), Action::Jam(0, true));
+        simulation.at(s(1), Action::Press(0, Button::Internal(Floor::At(3))));
+        simulation.at(s(3), Action::Restart(0));
+        simulation.at(s(4), Action::Jam(0, false));
+
+        simulation.run_for(Duration::from_millis(3500));
+        assert!(simulation.elevator(0).request_handler.requests[RequestType::Internal as usize][3].is_active());
+        simulation.run_for(s(20));
+
+        simulation.check_all_served();
+        assert_eq!(simulation.violations(), &[]);
+        let call = &simulation.elevator(0).request_handler.requests[RequestType::Internal as usize][3];
+        assert!(call.wait_time().is_some());
+    }
+
+    #[test]
+    fn reports_a_call_served_on_both_sides_of_a_partition() {
+        let mut simulation = Simulation::new(2, NetworkConfig::default(), 1);
+        let s = Duration::from_secs;
+        simulation.at(s(1), Action::Partition(vec![vec![0], vec![1]]));
+        simulation.at(s(2), Action::Press(0, Button::CallDown(Floor::At(2))));
+        simulation.at(s(2), Action::Press(1, Button::CallDown(Floor::At(2))));
+        simulation.at(s(15), Action::Heal);
+        simulation.run_for(s(20));
+
+        simulation.check_all_served();
+        assert_eq!(simulation.violations(), &[Violation::ServedTwice {
+            request_type: RequestType::CallDown,
+            floor: 2,
+            nodes: (0, 1),
+        }]);
+    }
+}
@@ -0,0 +1,127 @@
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::time::{Duration, Instant};
+
+use request_handler::request_transmitter::BroadcastMessage;
+
+// How the in-memory network mistreats messages. Every copy of a broadcast is
+// delayed, lost and duplicated on its own.
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkConfig {
+    pub latency_min: Duration,
+    pub latency_max: Duration,
+    pub loss: f64,
+    pub duplication: f64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            latency_min: Duration::from_millis(1),
+            latency_max: Duration::from_millis(1),
+            loss: 0.0,
+            duplication: 0.0,
+        }
+    }
+}
+
+struct InFlight {
+    deliver_at: Instant,
+    // Breaks ties between messages due at the same time, so delivery is deterministic.
+    sequence: u64,
+    from: usize,
+    to: usize,
+    message: BroadcastMessage,
+}
+
+// Carries broadcasts between the nodes of a simulation. The nodes are numbered,
+// and a partition splits them into groups that cannot reach each other.
+pub struct SimNetwork {
+    config: NetworkConfig,
+    rng: XorShiftRng,
+    groups: Vec<usize>,
+    in_flight: Vec<InFlight>,
+    sequence: u64,
+}
+
+impl SimNetwork {
+    pub fn new(nodes: usize, config: NetworkConfig, seed: u32) -> Self {
+        SimNetwork {
+            config: config,
+            rng: XorShiftRng::from_seed([seed, seed ^ 0x9e37_79b9, 0x85eb_ca6b, 0xc2b2_ae35]),
+            groups: vec![0; nodes],
+            in_flight: Vec::new(),
+            sequence: 0,
+        }
+    }
+
+    pub fn can_reach(&self, from: usize, to: usize) -> bool {
+        self.groups[from] == self.groups[to]
+    }
+
+    // Nodes left out of every group are cut off on their own.
+    pub fn partition(&mut self, groups: &[Vec<usize>]) {
+        let nodes = self.groups.len();
+        self.groups = (0..nodes).map(|node| groups.len() + node).collect();
+        for (group, members) in groups.iter().enumerate() {
+            for &node in members {
+                self.groups[node] = group;
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.groups = vec![0; self.groups.len()];
+    }
+
+    fn latency(&mut self) -> Duration {
+        let min = duration_ms(self.config.latency_min);
+        let max = duration_ms(self.config.latency_max);
+        if max <= min {
+            return Duration::from_millis(min);
+        }
+        Duration::from_millis(self.rng.gen_range(min, max + 1))
+    }
+
+    pub fn send(&mut self, from: usize, message: BroadcastMessage, now: Instant) {
+        for to in 0..self.groups.len() {
+            if to == from || !self.can_reach(from, to) {
+                continue;
+            }
+            if self.rng.gen::<f64>() < self.config.loss {
+                continue;
+            }
+            let copies = if self.rng.gen::<f64>() < self.config.duplication { 2 } else { 1 };
+            for _ in 0..copies {
+                let deliver_at = now + self.latency();
+                self.sequence += 1;
+                self.in_flight.push(InFlight {
+                    deliver_at: deliver_at,
+                    sequence: self.sequence,
+                    from: from,
+                    to: to,
+                    message: message.clone(),
+                });
+            }
+        }
+    }
+
+    // Takes the messages due by now, as (from, to, message), in the order they
+    // arrive. A message in flight when a partition starts still arrives.
+    pub fn deliver(&mut self, now: Instant) -> Vec<(usize, usize, BroadcastMessage)> {
+        let mut due = Vec::new();
+        let mut index = 0;
+        while index < self.in_flight.len() {
+            if self.in_flight[index].deliver_at <= now {
+                due.push(self.in_flight.swap_remove(index));
+            } else {
+                index += 1;
+            }
+        }
+        due.sort_by_key(|a| (a.deliver_at, a.sequence));
+        due.into_iter().map(|in_flight| (in_flight.from, in_flight.to, in_flight.message)).collect()
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
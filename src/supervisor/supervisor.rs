@@ -0,0 +1,248 @@
+use std::io;
+use std::env;
+use std::thread;
+use std::thread::JoinHandle;
+use std::net::{UdpSocket, SocketAddr, Ipv4Addr, SocketAddrV4};
+use std::panic;
+use std::process::{self, Command, Child};
+use std::str::from_utf8;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use supervisor::libc;
+use checkpoint::checkpoint::Checkpoint;
+use shutdown::shutdown::Shutdown;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum SupervisorMessage {
+    Alive { pid: i32, checkpoint: Option<Checkpoint> },
+    // Sent on a clean shutdown, so the backup leaves instead of taking over.
+    Stopping,
+}
+
+pub enum BackupOutcome {
+    TakeOver(Option<Checkpoint>),
+    PrimaryStopped,
+    Shutdown,
+}
+
+fn local_addr(port: u16) -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port))
+}
+
+fn receive(socket: &UdpSocket) -> io::Result<SupervisorMessage> {
+    // A checkpoint with every request in it does not fit the usual small buffers.
+    let mut buf = vec![0u8; 65536];
+    let (amt, _) = socket.recv_from(&mut buf)?;
+    let msg = from_utf8(&buf[..amt]).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    serde_json::from_str(msg).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// Set by a primary on the backup it spawns, to its own pid.
+const PRIMARY_PID_ENV: &str = "ELEVATOR_PRIMARY_PID";
+
+// The primary that spawned this process as its backup and is still its parent.
+// Only that process is ever killed, whatever pid the heartbeats claim.
+fn spawning_primary() -> Option<i32> {
+    let parent = unsafe { libc::getppid() };
+    match env::var(PRIMARY_PID_ENV).ok().and_then(|pid| pid.parse::<i32>().ok()) {
+        Some(pid) if pid == parent => Some(pid),
+        _ => None,
+    }
+}
+
+pub struct Backup {
+    socket: UdpSocket,
+}
+
+impl Backup {
+    pub fn bind(port: u16) -> io::Result<Self> {
+        Ok(Backup { socket: UdpSocket::bind(local_addr(port))? })
+    }
+
+    pub fn local_port(&self) -> io::Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    // Waits as the backup until the primary has been silent for timeout. A
+    // primary that stops sending without saying goodbye may still be alive but
+    // stuck, so it is killed before the backup takes over the hardware.
+    pub fn run(self, timeout: Duration, shutdown: &Shutdown) -> io::Result<BackupOutcome> {
+        self.socket.set_read_timeout(Some(timeout))?;
+
+        let mut primary = None;
+        let mut checkpoint = None;
+
+        while !shutdown.is_triggered() {
+            match receive(&self.socket) {
+                Ok(SupervisorMessage::Alive { pid, checkpoint: latest }) => {
+                    primary = Some(pid);
+                    if latest.is_some() {
+                        checkpoint = latest;
+                    }
+                },
+                Ok(SupervisorMessage::Stopping) => return Ok(BackupOutcome::PrimaryStopped),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                             || err.kind() == io::ErrorKind::TimedOut => {
+                    match primary {
+                        Some(pid) if spawning_primary() == Some(pid) => {
+                            println!("Primary {} timed out, taking over", pid);
+                            unsafe { libc::kill(pid, libc::SIGKILL); }
+                        },
+                        Some(pid) => println!("Primary {} timed out, taking over without killing it, as it is not our parent", pid),
+                        None => println!("No primary found, starting as primary"),
+                    }
+                    return Ok(BackupOutcome::TakeOver(checkpoint));
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::InvalidData => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(BackupOutcome::Shutdown)
+    }
+}
+
+pub fn run_backup(port: u16, timeout: Duration, shutdown: &Shutdown) -> io::Result<BackupOutcome> {
+    Backup::bind(port)?.run(timeout, shutdown)
+}
+
+// Starts a new backup running this binary with the same arguments.
+pub fn spawn_backup() -> io::Result<Child> {
+    let exe = env::current_exe()?;
+    let pid = unsafe { libc::getpid() };
+    Command::new(exe).args(env::args().skip(1)).env(PRIMARY_PID_ENV, pid.to_string()).spawn()
+}
+
+// A panic in any thread ends the process, so the backup takes over instead of
+// a primary living on without its polling or network threads.
+pub fn abort_on_panic() {
+    let report = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        report(info);
+        process::abort();
+    }));
+}
+
+// Sends a heartbeat with the latest checkpoint to the backup every interval,
+// until the shutdown, when the backup is told to leave. The heartbeats stop when
+// the main loop has sent no checkpoint for stale_after, as it is gone or hangs,
+// and the backup takes over. Until the first checkpoint the main loop is still
+// starting up, and the heartbeats go out regardless.
+pub fn spawn_heartbeat(port: u16, interval: Duration, stale_after: Duration, shutdown: Shutdown)
+    -> io::Result<(mpsc::Sender<Checkpoint>, JoinHandle<()>)>
+{
+    let socket = UdpSocket::bind(local_addr(0))?;
+    let (checkpoint_tx, checkpoint_rx) = mpsc::channel::<Checkpoint>();
+    let pid = unsafe { libc::getpid() };
+
+    let thread = thread::spawn(move|| {
+        let backup = local_addr(port);
+        let mut checkpoint = None;
+        let mut checkpointed_at: Option<Instant> = None;
+        let mut stalled = false;
+
+        while !shutdown.is_triggered() {
+            match checkpoint_rx.recv_timeout(interval) {
+                Ok(latest) => {
+                    // Only the newest checkpoint is worth sending.
+                    checkpoint = Some(checkpoint_rx.try_iter().last().unwrap_or(latest));
+                    checkpointed_at = Some(Instant::now());
+                    stalled = false;
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+            if checkpointed_at.map_or(false, |at| at.elapsed() > stale_after) {
+                if !stalled {
+                    println!("No checkpoint from the main loop for {:?}, leaving it to the backup", stale_after);
+                    stalled = true;
+                }
+                continue;
+            }
+            let message = SupervisorMessage::Alive { pid: pid, checkpoint: checkpoint.clone() };
+            // No backup listening yet is fine, it will hear the next one.
+            let _ = socket.send_to(serde_json::to_string(&message).unwrap().as_bytes(), backup);
+        }
+
+        if shutdown.is_triggered() {
+            let _ = socket.send_to(serde_json::to_string(&SupervisorMessage::Stopping).unwrap().as_bytes(), backup);
+        }
+    });
+
+    Ok((checkpoint_tx, thread))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use std::collections::HashMap;
+    use shutdown::shutdown::Shutdown;
+    use checkpoint::checkpoint::Checkpoint;
+    use elevator_fsm::elevator_fsm::State;
+
+    #[test]
+    fn backup_takes_over_with_last_checkpoint() {
+        let timeout = Duration::from_millis(200);
+        let checkpoint = Checkpoint {
+            saved_at: 0,
+            requests: vec![vec![]; 3],
+            peer_positions: HashMap::new(),
+            state: State::Running,
+            floor: 2,
+            direction_up: true,
+            door_remaining_ms: None,
+        };
+
+        // A primary whose main loop dies without a goodbye, as after a panic.
+        let backup = Backup::bind(0).unwrap();
+        let port = backup.local_port().unwrap();
+        let backup = thread::spawn(move|| backup.run(timeout, &Shutdown::new()).unwrap());
+        let (checkpoint_tx, heartbeat) = spawn_heartbeat(port, Duration::from_millis(20), timeout, Shutdown::new()).unwrap();
+        checkpoint_tx.send(checkpoint.clone()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(checkpoint_tx);
+        heartbeat.join().unwrap();
+
+        // The test is not the parent of the backup, so nothing is killed.
+        match backup.join().unwrap() {
+            BackupOutcome::TakeOver(Some(taken)) => assert_eq!(taken, checkpoint),
+            _ => panic!("Backup did not take over with the checkpoint"),
+        }
+
+        // A primary that shuts down says goodbye.
+        let backup = Backup::bind(0).unwrap();
+        let port = backup.local_port().unwrap();
+        let backup = thread::spawn(move|| backup.run(timeout, &Shutdown::new()).unwrap());
+        let primary_shutdown = Shutdown::new();
+        let (_checkpoint_tx, heartbeat) = spawn_heartbeat(port, Duration::from_millis(20), timeout, primary_shutdown.clone()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        primary_shutdown.trigger();
+        heartbeat.join().unwrap();
+
+        match backup.join().unwrap() {
+            BackupOutcome::PrimaryStopped => {},
+            _ => panic!("Backup did not leave on the goodbye"),
+        }
+
+        // A primary whose main loop hangs after a checkpoint, with the
+        // heartbeat thread still running.
+        let backup = Backup::bind(0).unwrap();
+        let port = backup.local_port().unwrap();
+        let backup = thread::spawn(move|| backup.run(timeout, &Shutdown::new()).unwrap());
+        let primary_shutdown = Shutdown::new();
+        let (checkpoint_tx, heartbeat) = spawn_heartbeat(port, Duration::from_millis(20), timeout, primary_shutdown.clone()).unwrap();
+        checkpoint_tx.send(checkpoint.clone()).unwrap();
+
+        match backup.join().unwrap() {
+            BackupOutcome::TakeOver(Some(taken)) => assert_eq!(taken, checkpoint),
+            _ => panic!("Backup did not take over from the hung primary"),
+        }
+        primary_shutdown.trigger();
+        heartbeat.join().unwrap();
+    }
+}